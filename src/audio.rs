@@ -0,0 +1,232 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use glam::f32::Vec3;
+use rodio::{ Decoder, OutputStream, OutputStreamHandle, Sink, SpatialSink };
+use rodio::Source;
+
+/// Distance between the ears of the listener (world units)
+const EAR_DISTANCE: f32 = 8.0;
+
+struct Listener {
+    position: Vec3,
+    right: Vec3,
+}
+
+impl Listener {
+    /// Ear positions scaled by the rolloff distance, rodio attenuates
+    /// based on the distance in these units
+    fn ears(&self, rolloff_distance: f32) -> ([f32; 3], [f32; 3]) {
+        let offset = self.right * (EAR_DISTANCE * 0.5);
+        let left = (self.position - offset) / rolloff_distance;
+        let right = (self.position + offset) / rolloff_distance;
+
+        (left.to_array(), right.to_array())
+    }
+}
+
+/// Sound playback, all failures are logged and ignored so the game still
+/// runs on machines without an audio device
+pub struct Audio {
+    handle: Option<OutputStreamHandle>,
+    music: Option<Sink>,
+
+    listener: Listener,
+    spatial_sinks: Vec<SpatialSink>,
+
+    /// Distance (world units) used to scale the attenuation of the
+    /// positional sounds, larger values makes the sounds carry further
+    pub rolloff_distance: f32,
+}
+
+impl Audio {
+    /// Create the audio resource, the returned `OutputStream` needs to be
+    /// kept alive for as long as audio should play
+    pub fn new() -> (Self, Option<OutputStream>) {
+        let (stream, handle) = match OutputStream::try_default() {
+            Ok((stream, handle)) => (Some(stream), Some(handle)),
+            Err(e) => {
+                log::warn!("Audio: Failed to open output device: {}", e);
+                (None, None)
+            }
+        };
+
+        let audio = Self {
+            handle,
+            music: None,
+
+            listener: Listener {
+                position: Vec3::ZERO,
+                right: Vec3::X,
+            },
+            spatial_sinks: Vec::new(),
+
+            rolloff_distance: 256.0,
+        };
+
+        (audio, stream)
+    }
+
+    pub fn play_sound<P>(&self, path: P)
+        where P: AsRef<Path>
+    {
+        let path = path.as_ref();
+
+        let handle = match &self.handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!("Audio: Failed to open '{}': {}", path.display(), e);
+                return;
+            }
+        };
+
+        if let Err(e) = handle.play_once(BufReader::new(file)).map(Sink::detach) {
+            log::warn!("Audio: Failed to play '{}': {}", path.display(), e);
+        }
+    }
+
+    /// Play `path` on repeat, replaces the currently playing music
+    pub fn play_music<P>(&mut self, path: P)
+        where P: AsRef<Path>
+    {
+        let path = path.as_ref();
+
+        let handle = match &self.handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!("Audio: Failed to open '{}': {}", path.display(), e);
+                return;
+            }
+        };
+
+        let source = match Decoder::new_looped(BufReader::new(file)) {
+            Ok(source) => source,
+            Err(e) => {
+                log::warn!("Audio: Failed to decode '{}': {}", path.display(), e);
+                return;
+            }
+        };
+
+        let sink = match Sink::try_new(handle) {
+            Ok(sink) => sink,
+            Err(e) => {
+                log::warn!("Audio: Failed to create sink: {}", e);
+                return;
+            }
+        };
+
+        sink.append(source);
+
+        if let Some(music) = self.music.replace(sink) {
+            music.stop();
+        }
+    }
+
+    /// Update the listener used for positional sounds, should be called
+    /// every frame with the camera
+    pub fn set_listener(&mut self, position: Vec3, direction: Vec3, up: Vec3) {
+        let right = up.cross(direction).normalize_or_zero();
+        self.listener = Listener { position, right };
+
+        let (left_ear, right_ear) = self.listener.ears(self.rolloff_distance);
+
+        self.spatial_sinks.retain(|sink| !sink.empty());
+        for sink in &self.spatial_sinks {
+            sink.set_left_ear_position(left_ear);
+            sink.set_right_ear_position(right_ear);
+        }
+    }
+
+    /// Play `path` from `position` in the world, falls back to a normal
+    /// sound if positional audio can't be used
+    pub fn play_sound_at<P>(&mut self, path: P, position: Vec3)
+        where P: AsRef<Path>
+    {
+        self.play_spatial(path.as_ref(), position, false);
+    }
+
+    /// Same as `play_sound_at` but the sound is looped forever
+    pub fn play_ambient_at<P>(&mut self, path: P, position: Vec3)
+        where P: AsRef<Path>
+    {
+        self.play_spatial(path.as_ref(), position, true);
+    }
+
+    fn play_spatial(&mut self, path: &Path, position: Vec3, looped: bool) {
+        let handle = match &self.handle {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!("Audio: Failed to open '{}': {}", path.display(), e);
+                return;
+            }
+        };
+
+        let source: Box<dyn Source<Item = i16> + Send> = if looped {
+            match Decoder::new_looped(BufReader::new(file)) {
+                Ok(source) => Box::new(source),
+                Err(e) => {
+                    log::warn!("Audio: Failed to decode '{}': {}",
+                               path.display(), e);
+                    return;
+                }
+            }
+        } else {
+            match Decoder::new(BufReader::new(file)) {
+                Ok(source) => Box::new(source),
+                Err(e) => {
+                    log::warn!("Audio: Failed to decode '{}': {}",
+                               path.display(), e);
+                    return;
+                }
+            }
+        };
+
+        let emitter = (position / self.rolloff_distance).to_array();
+        let (left_ear, right_ear) = self.listener.ears(self.rolloff_distance);
+
+        match SpatialSink::try_new(handle, emitter, left_ear, right_ear) {
+            Ok(sink) => {
+                sink.append(source);
+                self.spatial_sinks.push(sink);
+            }
+
+            Err(e) => {
+                log::warn!("Audio: Positional audio unavailable ({}), \
+                            playing '{}' without it", e, path.display());
+
+                match Sink::try_new(handle) {
+                    Ok(sink) => {
+                        sink.append(source);
+                        sink.detach();
+                    }
+
+                    Err(e) => {
+                        log::warn!("Audio: Failed to create sink: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn stop_music(&mut self) {
+        if let Some(music) = self.music.take() {
+            music.stop();
+        }
+    }
+}
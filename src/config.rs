@@ -0,0 +1,147 @@
+use std::path::{ Path, PathBuf };
+
+use serde::Deserialize;
+
+use crate::physics::ColliderShape;
+use crate::render;
+
+/// Startup settings read from a TOML file, any field missing from the file
+/// gets its default value
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub window_width: u32,
+    pub window_height: u32,
+    /// Shown in the title bar together with the map name and the FPS
+    pub window_title: String,
+    /// Image used as the window icon
+    pub window_icon: Option<PathBuf>,
+
+    pub map_path: PathBuf,
+
+    /// Vertical field of view in degrees
+    pub fov: f32,
+    /// Widen the field of view towards `speed_fov_max` when moving fast
+    pub speed_fov: bool,
+    pub speed_fov_max: f32,
+    /// How fast the field of view follows the speed, higher is snappier
+    pub speed_fov_rate: f32,
+    /// Degrees the camera turns per pixel of mouse movement
+    pub sensitivity: f32,
+    /// Moving the mouse up looks down
+    pub invert_y: bool,
+    /// Mouse movement shorter than this (pixels per frame) is ignored,
+    /// hides the jitter of some mice. 0 turns it off
+    pub mouse_deadzone: f32,
+    /// Exponent of the mouse acceleration curve, above 1 makes fast
+    /// movements turn further than slow ones. 1 is linear
+    pub mouse_acceleration: f32,
+    /// Bob the camera while walking
+    pub view_bob: bool,
+
+    pub vsync: bool,
+    /// Frame rate cap when vsync is disabled
+    pub max_fps: Option<u32>,
+    /// Draw the scene depth only first so every pixel is only shaded
+    /// once, helps on maps with a lot of overdraw
+    pub depth_prepass: bool,
+    /// Light the scene with a shadow casting directional light
+    pub shadows: bool,
+    /// Display gamma, raise it if the scene looks too dark
+    pub gamma: f32,
+    /// Multiplier for the scene colors before the gamma
+    pub exposure: f32,
+    /// Prefer the dedicated GPU over the integrated one
+    pub high_performance_gpu: bool,
+    /// Measure how long the GPU spends on each frame, needs support for
+    /// timestamp queries
+    pub gpu_timing: bool,
+    /// Spawn test entities in the door sector of the map for trying out
+    /// the renderer: a point light, a sprite and a particle emitter. The
+    /// door sector itself is turned into a lift playing an ambient sound
+    pub test_entities: bool,
+
+    /// Collider used for the player, a capsule slides over small steps
+    /// where a box gets stuck
+    pub player_collider: ColliderShape,
+    /// Highest step the player walks up on without jumping (world units)
+    pub max_step_height: f32,
+    /// How high the player jumps (world units)
+    pub jump_height: f32,
+    /// How far below the lowest floor of the map the player can fall
+    /// before being moved back (world units)
+    pub kill_plane_depth: f32,
+    /// How fast the player slows down without input, also slows down the
+    /// falling so keep it low
+    pub linear_damping: f32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            window_width: 1280,
+            window_height: 720,
+            window_title: "nocks".to_string(),
+            window_icon: None,
+
+            map_path: PathBuf::from("maps/doom1.mup"),
+
+            fov: 90.0,
+            speed_fov: false,
+            speed_fov_max: 100.0,
+            speed_fov_rate: 4.0,
+            sensitivity: 0.1,
+            invert_y: false,
+            mouse_deadzone: 0.0,
+            mouse_acceleration: 1.0,
+            view_bob: false,
+
+            vsync: true,
+            max_fps: None,
+            depth_prepass: false,
+            shadows: true,
+            gamma: render::DEFAULT_GAMMA,
+            exposure: render::DEFAULT_EXPOSURE,
+            high_performance_gpu: true,
+            gpu_timing: false,
+            test_entities: false,
+
+            player_collider: ColliderShape::Capsule {
+                radius: 1.0,
+                half_height: 3.0,
+            },
+            max_step_height: 24.0,
+            jump_height: 4.0,
+            kill_plane_depth: 1024.0,
+            linear_damping: 1.0,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config from `path`, falls back to the defaults if the file
+    /// is missing or can't be parsed
+    pub fn load<P>(path: P) -> Self
+        where P: AsRef<Path>
+    {
+        let path = path.as_ref();
+
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                log::info!("Config: Using defaults, failed to read '{}': {}",
+                           path.display(), e);
+                return Self::default();
+            }
+        };
+
+        match toml::from_str(&contents) {
+            Ok(config) => config,
+            Err(e) => {
+                log::warn!("Config: Using defaults, failed to parse '{}': {}",
+                           path.display(), e);
+                Self::default()
+            }
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::ops::Range;
+
+/// Commands that can be typed into the console
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// `fov <degrees>`
+    Fov(f32),
+    /// `grav <meters per second squared>`, pulls downwards
+    Gravity(f32),
+    /// `tp <sector>`
+    Teleport(usize),
+    /// `sectors <all | index | start..end>`, only draw the selected sectors
+    Sectors(Option<Range<usize>>),
+    /// `gamma <value>`
+    Gamma(f32),
+    /// `exposure <value>`
+    Exposure(f32),
+}
+
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    let mut parts = line.split_whitespace();
+
+    let name = match parts.next() {
+        Some(name) => name,
+        None => return Err("Empty command".to_string()),
+    };
+
+    let arg = parts.next()
+        .ok_or_else(|| format!("'{}' needs an argument", name))?;
+
+    if parts.next().is_some() {
+        return Err(format!("'{}' only takes one argument", name));
+    }
+
+    let number = |arg: &str| {
+        arg.parse::<f32>()
+            .map_err(|_| format!("'{}' is not a number", arg))
+    };
+
+    match name {
+        "fov" => Ok(Command::Fov(number(arg)?)),
+        "grav" => Ok(Command::Gravity(number(arg)?)),
+        "tp" => {
+            arg.parse::<usize>()
+                .map(Command::Teleport)
+                .map_err(|_| format!("'{}' is not a sector index", arg))
+        }
+        "sectors" => parse_sector_range(arg).map(Command::Sectors),
+        "gamma" => Ok(Command::Gamma(number(arg)?)),
+        "exposure" => Ok(Command::Exposure(number(arg)?)),
+
+        _ => Err(format!("Unknown command '{}'", name)),
+    }
+}
+
+/// Runtime command console toggled with the tilde key, the text is typed
+/// in through the `TextInput` of the game state
+#[derive(Debug, Default)]
+pub struct Console {
+    pub open: bool,
+
+    submitted: Vec<String>,
+}
+
+impl Console {
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+    }
+
+    /// Queue `line` to be executed
+    pub fn submit(&mut self, line: String) {
+        if !line.trim().is_empty() {
+            self.submitted.push(line);
+        }
+    }
+
+    /// Lines submitted since the last call
+    pub fn take_submitted(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.submitted)
+    }
+}
+
+/// Parse `all`, a single sector index or a `start..end` range (end is
+/// exclusive)
+fn parse_sector_range(arg: &str) -> Result<Option<Range<usize>>, String> {
+    if arg == "all" {
+        return Ok(None);
+    }
+
+    let index = |s: &str| {
+        s.parse::<usize>()
+            .map_err(|_| format!("'{}' is not a sector index", s))
+    };
+
+    match arg.split_once("..") {
+        Some((start, end)) => {
+            let (start, end) = (index(start)?, index(end)?);
+            if start > end {
+                return Err(format!("'{}' starts after it ends", arg));
+            }
+
+            Ok(Some(start..end))
+        }
+        None => {
+            let start = index(arg)?;
+            let end = start.checked_add(1)
+                .ok_or_else(|| format!("'{}' is not a sector index", arg))?;
+
+            Ok(Some(start..end))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sectors(line: &str) -> Result<Option<Range<usize>>, String> {
+        match parse_command(line)? {
+            Command::Sectors(range) => Ok(range),
+            command => panic!("Expected a sectors command, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn parse_number_commands() {
+        assert!(matches!(parse_command("fov 90"),
+                         Ok(Command::Fov(f)) if f == 90.0));
+        assert!(matches!(parse_command("grav -9.8"),
+                         Ok(Command::Gravity(g)) if g == -9.8));
+        assert!(matches!(parse_command("  gamma   2.2 "),
+                         Ok(Command::Gamma(g)) if g == 2.2));
+        assert!(matches!(parse_command("tp 3"), Ok(Command::Teleport(3))));
+    }
+
+    #[test]
+    fn parse_invalid_commands() {
+        assert!(parse_command("").is_err());
+        assert!(parse_command("fov").is_err());
+        assert!(parse_command("fov 90 100").is_err());
+        assert!(parse_command("fov wide").is_err());
+        assert!(parse_command("tp -1").is_err());
+        assert!(parse_command("noclip 1").is_err());
+    }
+
+    #[test]
+    fn parse_sector_ranges() {
+        assert_eq!(sectors("sectors all"), Ok(None));
+        assert_eq!(sectors("sectors 4"), Ok(Some(4..5)));
+        assert_eq!(sectors("sectors 2..6"), Ok(Some(2..6)));
+        assert_eq!(sectors("sectors 3..3"), Ok(Some(3..3)));
+    }
+
+    #[test]
+    fn parse_sector_ranges_rejects_invalid() {
+        assert!(sectors("sectors 6..2").is_err());
+        assert!(sectors("sectors 2..").is_err());
+        assert!(sectors("sectors ..").is_err());
+        assert!(sectors("sectors a..b").is_err());
+        assert!(sectors(&format!("sectors {}", usize::MAX)).is_err());
+    }
+}
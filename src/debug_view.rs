@@ -0,0 +1,228 @@
+use std::sync::mpsc::Receiver;
+
+use glam::f32::{ Mat4, Vec3 };
+
+use crate::render::{ GpuDevice, WindowSurface, RenderPipeline, Texture };
+use crate::render::{ UniformBuffer, GpuUniform, DebugLines };
+use crate::render::{ DynamicUniformBuffer, ModelUniform, MeshRegistry };
+use crate::Map;
+
+const DEBUG_VIEW_WIDTH: u32 = 640;
+const DEBUG_VIEW_HEIGHT: u32 = 480;
+/// How many world units the debug view covers from the left to the right
+/// edge
+const DEBUG_VIEW_EXTENT: f32 = 1536.0;
+/// Height above the player the debug camera is placed at
+const DEBUG_VIEW_CAMERA_HEIGHT: f32 = 1000.0;
+/// Size of the box drawn around the player (world units)
+const PLAYER_MARKER_SIZE: [f32; 3] = [16.0, 56.0, 16.0];
+const PLAYER_MARKER_COLOR: [f32; 4] = [1.0, 0.2, 0.2, 1.0];
+
+/// Second window showing the world from above the player, shares the
+/// `GpuDevice` and the scene pipelines with the main window
+pub struct DebugView {
+    window: glfw::Window,
+    events: Receiver<(f64, glfw::WindowEvent)>,
+
+    surface: WindowSurface,
+    depth_texture: Texture,
+
+    uniform_buffer: UniformBuffer,
+    gpu_uniform: GpuUniform<UniformBuffer>,
+
+    lines: DebugLines,
+}
+
+impl DebugView {
+    /// Open the window, `format` is the format of the main surface the
+    /// scene pipelines were created for
+    pub fn new(glfw: &mut glfw::Glfw,
+               gpu_device: &GpuDevice,
+               title: &str,
+               format: wgpu::TextureFormat)
+        -> Result<Self, String>
+    {
+        let (window, events) =
+            glfw.create_window(DEBUG_VIEW_WIDTH, DEBUG_VIEW_HEIGHT, title,
+                               glfw::WindowMode::Windowed)
+                .ok_or("Failed to create the debug view window")?;
+
+        let mut surface = gpu_device.create_surface(&window)
+            .map_err(|e| format!("Failed to create the debug view surface: {}",
+                                 e))?;
+
+        // NOTE(patrik): The pipelines are shared with the main window so
+        // the formats needs to match
+        if surface.config().format != format {
+            return Err(format!("Debug view surface format {:?} doesn't \
+                                match the main surface format {:?}",
+                               surface.config().format, format));
+        }
+
+        // NOTE(patrik): Waiting for vsync on both windows would halve the
+        // frame rate of the main window. Mailbox doesn't wait and doesn't
+        // tear, where it isn't supported the surface falls back to Fifo
+        surface.set_present_mode(gpu_device, wgpu::PresentMode::Mailbox);
+
+        let depth_texture =
+            Texture::create_depth_texture(gpu_device,
+                                          surface.config().width,
+                                          surface.config().height);
+
+        let uniform_buffer = UniformBuffer::new(Mat4::IDENTITY,
+                                                Mat4::IDENTITY,
+                                                Mat4::IDENTITY);
+        let gpu_uniform = GpuUniform::new(gpu_device, &uniform_buffer,
+                                          wgpu::ShaderStages::VERTEX |
+                                          wgpu::ShaderStages::FRAGMENT,
+                                          "debug_view_uniform_buffer");
+
+        Ok(Self {
+            window,
+            events,
+
+            surface,
+            depth_texture,
+
+            uniform_buffer,
+            gpu_uniform,
+
+            lines: DebugLines::new(gpu_device),
+        })
+    }
+
+    /// Drain the events of the window, needs to be called after
+    /// `glfw.poll_events()`. Returns false when the window wants to close
+    pub fn handle_events(&mut self) -> bool {
+        for _ in glfw::flush_messages(&self.events) {}
+
+        !self.window.should_close()
+    }
+
+    /// Render the map from above `player_pos` with a box around the
+    /// player. `identity_offset` is a model uniform with an identity
+    /// matrix used for the box
+    pub fn render(&mut self,
+                  gpu_device: &GpuDevice,
+                  pipeline: &RenderPipeline,
+                  lines_pipeline: &RenderPipeline,
+                  model_uniforms: &DynamicUniformBuffer<ModelUniform>,
+                  identity_offset: usize,
+                  shadow_bind_group: &wgpu::BindGroup,
+                  map: &Map,
+                  meshes: &MeshRegistry,
+                  player_pos: Vec3)
+    {
+        let _span = tracing::debug_span!("debug_view").entered();
+
+        let (width, height) = self.window.get_framebuffer_size();
+        if width <= 0 || height <= 0 {
+            return;
+        }
+
+        let width = width as u32;
+        let height = height as u32;
+        if width != self.surface.config().width ||
+           height != self.surface.config().height
+        {
+            self.surface.resize(gpu_device, width, height);
+            self.depth_texture.recreate_depth(gpu_device, width, height);
+        }
+
+        let output = match self.surface.get_render_target() {
+            Ok(output) => output,
+
+            Err(wgpu::SurfaceError::Lost) |
+            Err(wgpu::SurfaceError::Outdated) => {
+                self.surface.reconfigure(gpu_device);
+                return;
+            }
+
+            Err(e) => {
+                log::warn!("Debug view: Skipping frame: {:?}", e);
+                return;
+            }
+        };
+        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let half_width = DEBUG_VIEW_EXTENT / 2.0;
+        let half_height = half_width * height as f32 / width as f32;
+        let projection_matrix =
+            Mat4::orthographic_lh(-half_width, half_width,
+                                  -half_height, half_height,
+                                  0.1, DEBUG_VIEW_CAMERA_HEIGHT * 2.0);
+
+        let eye = player_pos + Vec3::new(0.0, DEBUG_VIEW_CAMERA_HEIGHT, 0.0);
+        let view_matrix = Mat4::look_at_lh(eye, player_pos, Vec3::Z);
+
+        self.uniform_buffer.update_projection(projection_matrix);
+        self.uniform_buffer.update_view(view_matrix);
+        self.gpu_uniform.update(&gpu_device.queue, &self.uniform_buffer);
+
+        let half_size = Vec3::from(PLAYER_MARKER_SIZE) / 2.0;
+        let center = player_pos + Vec3::new(0.0, half_size.y, 0.0);
+        self.lines.clear();
+        self.lines.aabb(center - half_size, center + half_size,
+                        PLAYER_MARKER_COLOR);
+        self.lines.upload(gpu_device);
+
+        let mut encoder = gpu_device.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Debug View Encoder"),
+        });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Debug View Pass"),
+                color_attachments: &[
+                    wgpu::RenderPassColorAttachment {
+                        view: &view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                            store: true,
+                        }
+                    }
+                ],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(0),
+                        store: true,
+                    }),
+                }),
+            });
+
+            render_pass.set_pipeline(pipeline.handle());
+            render_pass.set_bind_group(0, self.gpu_uniform.bind_group(), &[]);
+            render_pass.set_bind_group(2, shadow_bind_group, &[]);
+
+            // NOTE(patrik): No portal culling, the point is to see the
+            // parts of the map the player can't. The ceilings are skipped
+            // since they would cover everything from above
+            for (index, sector) in map.sectors.iter().enumerate() {
+                render_pass.set_bind_group(1, model_uniforms.bind_group(),
+                                           &[model_uniforms.offset(index)]);
+
+                for handle in [sector.floor_mesh, sector.wall_mesh] {
+                    let m = match meshes.get(handle) {
+                        Some(m) => m,
+                        None => continue,
+                    };
+                    m.draw(&mut render_pass);
+                }
+            }
+
+            render_pass.set_pipeline(lines_pipeline.handle());
+            render_pass.set_bind_group(1, model_uniforms.bind_group(),
+                                       &[model_uniforms.offset(identity_offset)]);
+            self.lines.mesh().draw(&mut render_pass);
+        }
+
+        gpu_device.queue.submit(std::iter::once(encoder.finish()));
+        output.present();
+    }
+}
@@ -0,0 +1,21 @@
+// Fullscreen triangle, no vertex buffer needed. The vertices are generated
+// from the vertex index and cover the whole screen
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] uv: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn vs_main(
+    [[builtin(vertex_index)]] in_vertex_index: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    let x = f32(i32((in_vertex_index << 1u) & 2u)) * 2.0 - 1.0;
+    let y = f32(i32(in_vertex_index & 2u)) * 2.0 - 1.0;
+
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    out.uv = vec2<f32>(x * 0.5 + 0.5, 1.0 - (y * 0.5 + 0.5));
+
+    return out;
+}
@@ -0,0 +1,18 @@
+// Gamma correction post process, only useful when the target surface
+// format isn't sRGB, otherwise the colors get encoded twice
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] uv: vec2<f32>;
+};
+
+[[group(0), binding(0)]]
+var source_texture: texture_2d<f32>;
+[[group(0), binding(1)]]
+var source_sampler: sampler;
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let color = textureSample(source_texture, source_sampler, in.uv);
+    return vec4<f32>(pow(color.rgb, vec3<f32>(1.0 / 2.2)), color.a);
+}
@@ -0,0 +1,181 @@
+use glam::f32::Vec3;
+
+/// Normal returned for triangles without a well defined normal
+pub const DEFAULT_NORMAL: Vec3 = Vec3::Y;
+
+/// Triangles with a cross product length below this are treated as
+/// degenerate
+const AREA_EPSILON: f32 = 1e-6;
+
+/// Checks if the triangle has (close to) zero area
+pub fn is_degenerate(a: Vec3, b: Vec3, c: Vec3) -> bool {
+    (b - a).cross(c - a).length() < AREA_EPSILON
+}
+
+/// The triangles in `indices` that aren't degenerate, triangles using the
+/// same vertex twice or referencing vertices out of range are skipped
+pub fn non_degenerate_triangles(vertices: &[Vec3], indices: &[u32])
+    -> Vec<[u32; 3]>
+{
+    indices.chunks_exact(3)
+        .filter(|t| t[0] != t[1] && t[1] != t[2] && t[0] != t[2])
+        .filter(|t| {
+            let a = vertices.get(t[0] as usize);
+            let b = vertices.get(t[1] as usize);
+            let c = vertices.get(t[2] as usize);
+
+            match (a, b, c) {
+                (Some(a), Some(b), Some(c)) => !is_degenerate(*a, *b, *c),
+                _ => false,
+            }
+        })
+        .map(|t| [t[0], t[1], t[2]])
+        .collect()
+}
+
+/// Normal of the triangle `a`, `b`, `c` using the right hand rule, zero
+/// area triangles gets `DEFAULT_NORMAL`
+pub fn triangle_normal(a: Vec3, b: Vec3, c: Vec3) -> Vec3 {
+    if is_degenerate(a, b, c) {
+        DEFAULT_NORMAL
+    } else {
+        (b - a).cross(c - a).normalize()
+    }
+}
+
+/// One normal per triangle in `indices`, triangles referencing vertices
+/// out of range gets `DEFAULT_NORMAL`
+pub fn compute_flat_normals(vertices: &[Vec3], indices: &[u32]) -> Vec<Vec3> {
+    indices.chunks_exact(3)
+        .map(|t| {
+            let a = vertices.get(t[0] as usize);
+            let b = vertices.get(t[1] as usize);
+            let c = vertices.get(t[2] as usize);
+
+            match (a, b, c) {
+                (Some(a), Some(b), Some(c)) => triangle_normal(*a, *b, *c),
+                _ => DEFAULT_NORMAL,
+            }
+        })
+        .collect()
+}
+
+/// One normal per vertex, the average of the flat normals of the
+/// triangles sharing the vertex
+pub fn compute_vertex_normals(vertices: &[Vec3], indices: &[u32]) -> Vec<Vec3> {
+    let flat_normals = compute_flat_normals(vertices, indices);
+
+    let mut normals = vec![Vec3::ZERO; vertices.len()];
+    for (t, normal) in indices.chunks_exact(3).zip(flat_normals) {
+        for &index in t {
+            if let Some(n) = normals.get_mut(index as usize) {
+                *n += normal;
+            }
+        }
+    }
+
+    normals.into_iter()
+        .map(|n| n.try_normalize().unwrap_or(DEFAULT_NORMAL))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn triangle_normal_of_floor_triangle() {
+        let normal = triangle_normal(Vec3::ZERO, Vec3::Z, Vec3::X);
+        assert_close(normal, Vec3::Y);
+    }
+
+    #[test]
+    fn triangle_normal_follows_winding() {
+        let a = Vec3::new(1.0, 2.0, 3.0);
+        let b = Vec3::new(4.0, 2.0, 3.0);
+        let c = Vec3::new(1.0, 5.0, 3.0);
+
+        assert_close(triangle_normal(a, b, c), Vec3::Z);
+        assert_close(triangle_normal(a, c, b), -Vec3::Z);
+        // NOTE(patrik): Rotating the vertices keeps the winding
+        assert_close(triangle_normal(b, c, a), Vec3::Z);
+    }
+
+    #[test]
+    fn triangle_normal_of_degenerate_triangle() {
+        let normal = triangle_normal(Vec3::ZERO, Vec3::X, Vec3::X * 2.0);
+        assert_eq!(normal, DEFAULT_NORMAL);
+    }
+
+    #[test]
+    fn non_degenerate_triangles_skips_zero_area() {
+        let vertices = [Vec3::ZERO, Vec3::Z, Vec3::X, Vec3::X * 2.0];
+        let indices = [0, 1, 2, 0, 2, 3];
+
+        assert_eq!(non_degenerate_triangles(&vertices, &indices),
+                   vec![[0, 1, 2]]);
+    }
+
+    #[test]
+    fn non_degenerate_triangles_skips_duplicate_indices() {
+        let vertices = [Vec3::ZERO, Vec3::Z, Vec3::X];
+        let indices = [0, 1, 2, 0, 0, 1, 1, 2, 2, 2, 1, 2];
+
+        assert_eq!(non_degenerate_triangles(&vertices, &indices),
+                   vec![[0, 1, 2]]);
+    }
+
+    #[test]
+    fn non_degenerate_triangles_skips_out_of_range() {
+        let vertices = [Vec3::ZERO, Vec3::Z, Vec3::X];
+        let indices = [0, 1, 2, 0, 1, 3];
+
+        assert_eq!(non_degenerate_triangles(&vertices, &indices),
+                   vec![[0, 1, 2]]);
+    }
+
+    #[test]
+    fn flat_normals_one_per_triangle() {
+        let vertices = [Vec3::ZERO, Vec3::Z, Vec3::X, Vec3::Y];
+        let indices = [0, 1, 2, 0, 2, 3, 0, 1, 9];
+
+        let normals = compute_flat_normals(&vertices, &indices);
+
+        assert_eq!(normals.len(), 3);
+        assert_close(normals[0], Vec3::Y);
+        assert_close(normals[1], Vec3::Z);
+        // NOTE(patrik): Index out of range
+        assert_eq!(normals[2], DEFAULT_NORMAL);
+    }
+
+    #[test]
+    fn vertex_normals_average_shared_vertices() {
+        // NOTE(patrik): Two triangles sharing the edge between vertex 0
+        // and 2, one facing up and one facing +Z
+        let vertices = [Vec3::ZERO, Vec3::Z, Vec3::X, Vec3::Y];
+        let indices = [0, 1, 2, 0, 2, 3];
+
+        let normals = compute_vertex_normals(&vertices, &indices);
+
+        let shared = (Vec3::Y + Vec3::Z).normalize();
+        assert_eq!(normals.len(), 4);
+        assert_close(normals[0], shared);
+        assert_close(normals[1], Vec3::Y);
+        assert_close(normals[2], shared);
+        assert_close(normals[3], Vec3::Z);
+    }
+
+    #[test]
+    fn vertex_normals_for_unused_vertices() {
+        let vertices = [Vec3::ZERO, Vec3::Z, Vec3::X, Vec3::ONE];
+        let indices = [0, 1, 2];
+
+        let normals = compute_vertex_normals(&vertices, &indices);
+
+        assert_eq!(normals[3], DEFAULT_NORMAL);
+    }
+}
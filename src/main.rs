@@ -3,24 +3,137 @@ use rapier3d::prelude::*;
 
 use rapier3d::na::*;
 
-use std::path::Path;
+use std::borrow::Cow;
+use std::path::{ Path, PathBuf };
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
 use std::fs::File;
 use std::io::Read;
-use std::time::Instant;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
 
 use glfw::{Action, Context, Key};
-use glam::f32::{ Mat4, Vec2, Vec3 };
-use wgpu::util::DeviceExt;
+use glam::f32::{ Mat4, Quat, Vec2, Vec3 };
 
 use bevy_ecs::world::EntityRef;
 
-use render::{ GpuDevice, Mesh, Vertex, UniformBuffer, Texture };
+use render::{ GpuDevice, GpuOptions, RenderPipeline, Mesh, Vertex, UniformBuffer, Texture };
+use render::{ RenderTarget, PostProcess };
+use render::{ DynamicUniformBuffer, ModelUniform };
+use render::{ MeshHandle, MeshRegistry, Material };
+use minimap::Minimap;
+use debug_view::DebugView;
+use audio::Audio;
+use physics::{ PhysicsContext, ColliderShape, SurfaceMaterial };
+use config::Config;
+use console::{ Console, Command };
+use particles::{ ParticleEmitter, Particles };
+use sprite::{ Sprite, SpriteRenderer };
+use replay::{ FrameInput, Replay, ReplayRecorder };
 
 extern crate glfw;
 
 const UNIT_TO_METERS: f32 = 4.0;
 
+/// Sound played when the player jumps
+const JUMP_SOUND: &str = "sounds/jump.wav";
+
+/// Ambient loop played from the center of the door sector, only with
+/// `test_entities` enabled
+const AMBIENT_SOUND: &str = "sounds/ambient.ogg";
+
+/// Sector used for the scripted door, only with `test_entities` enabled
+const DOOR_SECTOR: usize = 38;
+
+/// Largest delta time (in seconds) fed to the physics and the systems
+const MAX_DELTA_TIME: f32 = 0.1;
+
+/// Fixed timestep the physics is simulated with (in seconds)
+const PHYSICS_TIMESTEP: f32 = 1.0 / 60.0;
+
+/// File the startup settings are read from
+const CONFIG_PATH: &str = "nocks.toml";
+
+/// Scene shader read at runtime so it can be reloaded with F5 while the
+/// game is running
+const SHADER_PATH: &str = "src/shader.wgsl";
+const EMBEDDED_SHADER: &str = include_str!("shader.wgsl");
+
+/// Max number of `Renderable` entities drawn each frame
+const MAX_RENDERABLES: usize = 256;
+
+/// Half size of the boxes dropped with B (meters)
+const SPAWN_BOX_HALF_EXTENTS: [f32; 3] = [1.0, 1.0, 1.0];
+const SPAWN_BOX_COLOR: [f32; 4] = [0.8, 0.5, 0.2, 1.0];
+/// How far in front of the eye the boxes are dropped (world units)
+const SPAWN_BOX_DISTANCE: f32 = 32.0;
+
+/// Max number of `Sprite` entities drawn each frame
+const MAX_SPRITES: usize = 128;
+
+/// Texture of the test sprite, a placeholder is drawn if it's missing
+const TEST_SPRITE_TEXTURE: &str = "textures/sprite.png";
+
+/// Near and far plane of the perspective projection (world units)
+const NEAR_PLANE: f32 = 0.1;
+const FAR_PLANE: f32 = 2000.0;
+
+/// Route the scene through the gamma correction post process pass
+const GAMMA_CORRECTION: bool = false;
+
 mod render;
+mod minimap;
+mod debug_view;
+mod audio;
+mod physics;
+mod config;
+mod geometry;
+mod console;
+mod particles;
+mod sprite;
+mod replay;
+
+/// Text typed in by the user, characters are only collected while
+/// `capturing` is set so nothing is gathered when no one is listening
+#[derive(Debug, Default)]
+struct TextInput {
+    capturing: bool,
+    buffer: String,
+}
+
+impl TextInput {
+    fn begin(&mut self) {
+        self.capturing = true;
+        self.buffer.clear();
+    }
+
+    fn end(&mut self) {
+        self.capturing = false;
+        self.buffer.clear();
+    }
+
+    fn as_str(&self) -> &str {
+        &self.buffer
+    }
+
+    /// Take the text typed in so far, capturing continues
+    fn take(&mut self) -> String {
+        std::mem::take(&mut self.buffer)
+    }
+
+    fn push(&mut self, c: char) {
+        if self.capturing && !c.is_control() {
+            self.buffer.push(c);
+        }
+    }
+
+    fn backspace(&mut self) {
+        if self.capturing {
+            self.buffer.pop();
+        }
+    }
+}
 
 #[derive(Debug)]
 struct GameState {
@@ -37,12 +150,55 @@ struct GameState {
     last_mouse_x: f32,
     last_mouse_y: f32,
 
+    /// Mouse movement in pixels since the last update, applied to the
+    /// yaw and pitch in `update_camera`
+    mouse_delta: Vec2,
+
     yaw: f32,
     pitch: f32,
+
+    /// Degrees turned per pixel of mouse movement
+    sensitivity: f32,
+    invert_y: bool,
+    /// See `apply_mouse_curve`
+    mouse_deadzone: f32,
+    mouse_acceleration: f32,
+
+    show_minimap: bool,
+    screenshot: bool,
+    /// Read the scene shader from disk again, triggered with F5
+    reload_shader: bool,
+    /// Log where the player is, triggered with F3
+    log_position: bool,
+    /// Draw the outlines of the physics colliders, toggled with F4
+    show_colliders: bool,
+    /// Shader output for debugging the lighting, cycled with F6
+    debug_mode: render::DebugMode,
+    /// Move the player back to the spawn point, triggered with R
+    reset_player: bool,
+    /// Drop a physics box in front of the player, triggered with B
+    spawn_box: bool,
+
+    /// Only draw the sectors close to the current sector, off by default
+    /// and toggled with O
+    portal_culling: bool,
+    /// Faces culled by the scene pipeline, cycled with C
+    cull_mode: Option<wgpu::Face>,
+    /// Fly through the geometry without collisions, toggled with N
+    noclip: bool,
+
+    /// Sector index typed in with the number keys, teleported to with T
+    sector_input: Option<usize>,
+    teleport_sector: Option<usize>,
+
+    toggle_pause: bool,
+
+    text_input: TextInput,
+    console: Console,
 }
 
 impl GameState {
-    fn new() -> Self {
+    fn new(sensitivity: f32, invert_y: bool) -> Self {
         Self {
             close: false,
 
@@ -56,366 +212,2105 @@ impl GameState {
             last_mouse_x: 0.0,
             last_mouse_y: 0.0,
 
+            mouse_delta: Vec2::ZERO,
+
             yaw: 90.0,
             pitch: 0.0,
-        }
-    }
-}
-
-struct Sector {
-    floor_mesh: Mesh,
-    ceiling_mesh: Mesh,
-    wall_mesh: Mesh,
 
-    floor_collider: Option<Collider>,
-    wall_collider: Option<Collider>,
-}
+            sensitivity,
+            invert_y,
+            mouse_deadzone: 0.0,
+            mouse_acceleration: 1.0,
 
-struct Map {
-    sectors: Vec<Sector>,
-}
+            show_minimap: true,
+            screenshot: false,
+            reload_shader: false,
+            log_position: false,
+            show_colliders: false,
+            debug_mode: render::DebugMode::Lit,
+            reset_player: false,
+            spawn_box: false,
 
-fn load_map<P>(filename: P, gpu_device: &GpuDevice) -> Option<Map>
-    where P: AsRef<Path>
-{
-    let mut file = File::open(filename).ok()?;
-    let mut data = Vec::new();
-    file.read_to_end(&mut data).ok()?;
+            portal_culling: false,
+            cull_mode: Some(wgpu::Face::Back),
+            noclip: false,
 
-    let mime_map = mime::Map::deserialize(&data).unwrap();
+            sector_input: None,
+            teleport_sector: None,
 
-    let mut sectors = Vec::new();
+            toggle_pause: false,
 
-    let mut index = 0;
-    for sector in &mime_map.sectors {
-        let generate_mesh = |m: &mime::Mesh| {
-            let mut vertex_buffer = Vec::new();
+            text_input: TextInput::default(),
+            console: Console::default(),
+        }
+    }
 
-            for v in &m.vertex_buffer {
-                vertex_buffer.push(Vertex {
-                    position: [v.x, v.y, v.z],
-                    color: [v.color[0], v.color[1], v.color[2]],
-                });
-            }
+    /// The inputs recorded in a replay
+    fn frame_input(&self, dt: f32) -> FrameInput {
+        FrameInput {
+            dt,
 
-            let index_buffer = &m.index_buffer;
+            up: self.up,
+            down: self.down,
+            left: self.left,
+            right: self.right,
+            jump: self.jump,
 
-            Mesh::from_data(gpu_device, &vertex_buffer, index_buffer)
-        };
+            mouse_delta: self.mouse_delta,
+        }
+    }
 
-        let generate_collider = |m: &mime::Mesh, t: bool| {
-            let mut points = Vec::new();
-            let mut indices = Vec::new();
+    /// Replace the live input with a frame from a replay
+    fn apply_frame_input(&mut self, input: &FrameInput) {
+        self.up = input.up;
+        self.down = input.down;
+        self.left = input.left;
+        self.right = input.right;
+        self.jump = input.jump;
 
-            for v in &m.vertex_buffer {
-                points.push(Point3::new(v.x / UNIT_TO_METERS,
-                                        v.y / UNIT_TO_METERS,
-                                        v.z / UNIT_TO_METERS));
-            }
+        self.mouse_delta = input.mouse_delta;
+    }
+}
 
-            for i in 0..m.index_buffer.len() / 3 {
-                let p1 = m.index_buffer[i * 3 + 0];
-                let p2 = m.index_buffer[i * 3 + 1];
-                let p3 = m.index_buffer[i * 3 + 2];
+#[derive(Copy, Clone, Debug)]
+struct Aabb {
+    min: Vec3,
+    max: Vec3,
+}
 
-                indices.push([p1, p2, p3]);
-            }
+impl Aabb {
+    fn from_points<I>(points: I) -> Self
+        where I: IntoIterator<Item = Vec3>
+    {
+        let mut points = points.into_iter();
 
-            if t {
-                ColliderBuilder::trimesh(points, indices)
-                    .active_hooks(ActiveHooks::MODIFY_SOLVER_CONTACTS)
-                    .build()
-            } else {
-                ColliderBuilder::trimesh(points, indices).build()
-            }
+        let first = match points.next() {
+            Some(p) => p,
+            None => return Self { min: Vec3::ZERO, max: Vec3::ZERO },
         };
 
-        let floor_collider = generate_collider(&sector.floor_mesh, false);
-        let wall_collider = generate_collider(&sector.wall_mesh, true);
+        let mut min = first;
+        let mut max = first;
+        for p in points {
+            min = min.min(p);
+            max = max.max(p);
+        }
 
-        let floor_mesh = generate_mesh(&sector.floor_mesh);
-        let ceiling_mesh = generate_mesh(&sector.ceiling_mesh);
-        let wall_mesh = generate_mesh(&sector.wall_mesh);
+        Self { min, max }
+    }
 
-        sectors.push(Sector {
-            floor_mesh,
-            ceiling_mesh,
-            wall_mesh,
+    fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
 
-            floor_collider: Some(floor_collider),
-            wall_collider: Some(wall_collider),
-        });
+    fn union(&self, other: &Aabb) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
 
-        index += 1;
+    /// Checks if the boxes overlap or touch on the XZ plane, `epsilon`
+    /// gives some slack for floating point errors in the map data
+    fn touches_xz(&self, other: &Aabb, epsilon: f32) -> bool {
+        self.min.x <= other.max.x + epsilon &&
+        self.max.x + epsilon >= other.min.x &&
+        self.min.z <= other.max.z + epsilon &&
+        self.max.z + epsilon >= other.min.z
     }
 
-    let map = Map {
-        sectors,
-    };
+    /// Distance from `point` to the box on the XZ plane, 0 if inside
+    fn distance_xz(&self, point: Vec3) -> f32 {
+        let dx = (self.min.x - point.x).max(point.x - self.max.x).max(0.0);
+        let dz = (self.min.z - point.z).max(point.z - self.max.z).max(0.0);
 
-    Some(map)
-}
+        Vec2::new(dx, dz).length()
+    }
 
+    fn contains_xz(&self, point: Vec3) -> bool {
+        point.x >= self.min.x && point.x <= self.max.x &&
+        point.z >= self.min.z && point.z <= self.max.z
+    }
+}
 
-struct DeltaTime(f32);
+/// Lighting and fog used while the camera is inside a sector
+#[derive(Copy, Clone, Debug)]
+struct SectorEnvironment {
+    /// Linear fog color
+    fog_color: Vec3,
+    /// Fog density per world unit, 0 disables the fog
+    fog_density: f32,
+    /// Multiplier for the sector colors
+    light_level: f32,
+}
 
-#[derive(Component, Debug)]
-#[repr(transparent)]
-struct Position(Vec3);
+impl Default for SectorEnvironment {
+    fn default() -> Self {
+        Self {
+            fog_color: Vec3::ZERO,
+            fog_density: 0.0,
+            light_level: 1.0,
+        }
+    }
+}
 
-#[derive(Component, Debug)]
-struct Camera {
-    direction: Vec3,
-    up: Vec3,
+/// Optional data the map format can carry for a mesh on top of the
+/// positions and colors, None when the map doesn't have it
+#[derive(Clone, Debug, Default)]
+struct MeshSurface {
+    /// Name of the Doom texture used by the mesh
+    texture: Option<String>,
+    /// Texture coordinates, one for each vertex
+    uvs: Option<Vec<Vec2>>,
+    material_id: Option<u32>,
 }
 
-#[derive(Component)]
-struct Player {
-    collider_handle: ColliderHandle,
-    body_handle: RigidBodyHandle,
-    speed: f32,
+impl MeshSurface {
+    // TODO(patrik): Read the fields when the exporter starts writing them,
+    // mime::Mesh only has the vertex and index buffers so far
+    fn read(_m: &mime::Mesh) -> Self {
+        Self::default()
+    }
 }
 
-fn update_camera(mut query: Query<(&mut Position, &mut Camera, &Player)>,
-                 game_state: Res<GameState>,
-                 mut bodies: ResMut<RigidBodySet>,
-                 dt: Res<DeltaTime>)
-{
-    for (mut position, mut camera, player) in query.iter_mut() {
-        let mut body = bodies.get_mut(player.body_handle).unwrap();
+struct Sector {
+    /// Transform applied to all the meshes of the sector
+    model_matrix: Mat4,
 
-        let pitch = game_state.pitch;
-        let yaw = game_state.yaw;
+    environment: SectorEnvironment,
+    material: Material,
 
-        let direction = Vec3::new(
-            yaw.to_radians().cos() * pitch.to_radians().cos(),
-            pitch.to_radians().sin(),
-            yaw.to_radians().sin() * pitch.to_radians().cos());
+    /// Bounds of the floor mesh in world units
+    floor_aabb: Aabb,
 
-        camera.direction = direction.normalize();
+    /// Indices of the sectors that border this sector
+    neighbors: Vec<usize>,
 
-        const SPEED: f32 = 10.0;
+    /// Floor triangles projected on to the XZ plane, used for testing if
+    /// a point is inside the sector
+    floor_triangles: Vec<[Vec2; 3]>,
 
-        if game_state.up {
-            let dir = camera.direction * SPEED;
+    floor_mesh: MeshHandle,
+    ceiling_mesh: MeshHandle,
+    wall_mesh: MeshHandle,
 
-            let linvel = body.linvel();
-            let force = vector![dir.x, linvel.y, dir.z];
+    floor_surface: MeshSurface,
+    ceiling_surface: MeshSurface,
+    wall_surface: MeshSurface,
 
-            body.set_linvel(force, true);
-        } else if game_state.down {
-            let dir = -camera.direction * SPEED;
+    floor_collider: Option<Collider>,
+    wall_collider: Option<Collider>,
+    ceiling_collider: Option<Collider>,
+}
 
-            let linvel = body.linvel();
-            let force = vector![dir.x, linvel.y, dir.z];
+/// Where the player starts in the map, `position` is in world units and
+/// `yaw` in degrees
+#[derive(Copy, Clone, Debug)]
+struct SpawnPoint {
+    position: Vec3,
+    yaw: f32,
+}
 
-            body.set_linvel(force, true);
-        }
+struct Map {
+    sectors: Vec<Sector>,
+    spawn: SpawnPoint,
 
-        if game_state.jump {
-            let dir = Vec3::new(0.0, 1.0, 0.0);
-            let force = dir * 2.0;
+    load_timings: MapLoadTimings,
+}
 
-            let linvel = body.linvel();
-            let force = vector![linvel.x, 2.0, linvel.z];
+#[derive(Debug)]
+enum MapLoadError {
+    Io(std::io::Error),
+    /// The map file isn't a valid mime map
+    Deserialize(String),
+    /// The map doesn't have any sectors
+    EmptyMap,
+    /// None of the sectors has a floor
+    NoFloor,
+    /// The map was written for a different version of the format
+    UnsupportedVersion(u32),
+}
 
-            body.set_linvel(force, true);
+impl fmt::Display for MapLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Deserialize(e) => write!(f, "Invalid map data: {}", e),
+            Self::EmptyMap => write!(f, "The map has no sectors"),
+            Self::NoFloor => write!(f, "The map has no floors"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported map format version {} (supported: {})",
+                       version, MIME_FORMAT_VERSION)
+            }
         }
-        /*
-        let speed = player.speed;
+    }
+}
 
-        if game_state.up {
-            position.0 += (speed * camera.direction) * dt.0;
-        }
+impl Error for MapLoadError {}
 
-        if game_state.down {
-            position.0 -= (speed * camera.direction) * dt.0;
-        }
+impl From<std::io::Error> for MapLoadError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
 
-        if game_state.right {
-            position.0 -= (camera.direction.cross(camera.up).normalize() * speed) * dt.0;
-        }
+/// Time spent in the different parts of `load_map`
+#[derive(Copy, Clone, Debug, Default)]
+struct MapLoadTimings {
+    deserialize: Duration,
+    meshes: Duration,
+    colliders: Duration,
+}
 
-        if game_state.left {
-            position.0 += (camera.direction.cross(camera.up).normalize() * speed) * dt.0;
+/// Height above the floor the player is spawned at (world units)
+const SPAWN_HEIGHT: f32 = 20.0;
+
+/// The map format doesn't carry any spawn information so by convention the
+/// player spawns in the center of the first sector, if that sector has no
+/// floor the center of the whole map is used instead
+fn find_spawn_point(sectors: &[Sector]) -> SpawnPoint {
+    let position = if let Some(sector) = sectors.first()
+        .filter(|sector| sector.has_floor())
+    {
+        let aabb = &sector.floor_aabb;
+        let center = aabb.center();
+        Vec3::new(center.x, aabb.max.y + SPAWN_HEIGHT, center.z)
+    } else {
+        let map_aabb = sectors.iter()
+            .filter(|sector| sector.has_floor())
+            .map(|sector| sector.floor_aabb)
+            .reduce(|a, b| a.union(&b));
+
+        match map_aabb {
+            Some(aabb) => {
+                let center = aabb.center();
+                Vec3::new(center.x, aabb.max.y + SPAWN_HEIGHT, center.z)
+            }
+
+            None => Vec3::new(0.0, SPAWN_HEIGHT, 0.0),
         }
-        */
+    };
+
+    SpawnPoint {
+        position,
+        yaw: 90.0,
     }
 }
 
-fn update_player_physics(mut query: Query<(&mut Position, &Player)>,
-                         bodies: Res<RigidBodySet>)
-{
-    for (mut position, player) in query.iter_mut() {
-        let body = bodies.get(player.body_handle).unwrap();
-        let x = body.translation().x;
-        let y = body.translation().y;
-        let z = body.translation().z;
-        let new_pos = Vec3::new(x, y, z);
+/// Slack used when checking if two sectors border each other (world units)
+const NEIGHBOR_EPSILON: f32 = 0.5;
 
-        position.0 = new_pos * UNIT_TO_METERS;
+/// How many sectors away from the current sector that are drawn when
+/// portal culling is enabled
+const PORTAL_DEPTH: usize = 4;
+
+/// The map format doesn't store which sectors are connected, so sectors
+/// with touching floor bounds are treated as neighbors
+fn compute_sector_neighbors(sectors: &mut [Sector]) {
+    for i in 0..sectors.len() {
+        if !sectors[i].has_floor() {
+            continue;
+        }
+
+        for j in (i + 1)..sectors.len() {
+            if !sectors[j].has_floor() {
+                continue;
+            }
+
+            if sectors[i].floor_aabb.touches_xz(&sectors[j].floor_aabb,
+                                                NEIGHBOR_EPSILON)
+            {
+                sectors[i].neighbors.push(j);
+                sectors[j].neighbors.push(i);
+            }
+        }
     }
 }
 
-fn generate_view_matrix(camera: EntityRef) -> Mat4 {
-    let pos = camera.get::<Position>()
-        .expect("Camera dosen't have Position Component");
-    let controller = camera.get::<Camera>()
-        .expect("Camera dosen't have Camera Controller Component");
+fn point_in_triangle(p: Vec2, [a, b, c]: [Vec2; 3]) -> bool {
+    let side = |a: Vec2, b: Vec2| (b - a).perp_dot(p - a);
+
+    let d1 = side(a, b);
+    let d2 = side(b, c);
+    let d3 = side(c, a);
 
-    let pos = pos.0 + Vec3::new(0.0, 20.0, 0.0);
+    let has_neg = d1 < 0.0 || d2 < 0.0 || d3 < 0.0;
+    let has_pos = d1 > 0.0 || d2 > 0.0 || d3 > 0.0;
 
-    Mat4::look_at_lh(pos, pos+ controller.direction, controller.up)
+    !(has_neg && has_pos)
 }
 
-struct Test;
+impl Sector {
+    fn has_floor(&self) -> bool {
+        !self.floor_triangles.is_empty()
+    }
 
-impl EventHandler for Test {
-    fn handle_intersection_event(&self, event: IntersectionEvent) {
-        println!("handle_intersection_event");
+    /// Checks if `point` is above the floor of the sector
+    fn contains(&self, point: Vec3) -> bool {
+        if !self.floor_aabb.contains_xz(point) ||
+           self.floor_aabb.min.y > point.y
+        {
+            return false;
+        }
+
+        let p = Vec2::new(point.x, point.z);
+        self.floor_triangles.iter().any(|t| point_in_triangle(p, *t))
     }
+}
 
-    fn handle_contact_event(&self,
-                            event: ContactEvent,
-                            contact_pair: &ContactPair)
-    {
-        println!("handle_contact_event");
+/// Find the sector with the highest floor below `point`
+fn sector_containing(map: &Map, point: Vec3) -> Option<usize> {
+    map.sectors.iter()
+        .enumerate()
+        .filter(|(_, sector)| sector.contains(point))
+        .max_by(|(_, a), (_, b)| {
+            a.floor_aabb.max.y.total_cmp(&b.floor_aabb.max.y)
+        })
+        .map(|(index, _)| index)
+}
+
+/// Same as `sector_containing` but checks `last` and its neighbors first,
+/// the point usually stays in the same sector between frames
+fn sector_containing_near(map: &Map, point: Vec3, last: Option<usize>)
+    -> Option<usize>
+{
+    if let Some(last) = last.filter(|i| *i < map.sectors.len()) {
+        let sector = &map.sectors[last];
+        // NOTE(patrik): Sectors can overlap on the XZ plane (stairs, lifts)
+        // so the cached sector is only used if no neighbor is a better fit
+        let best = std::iter::once(last)
+            .chain(sector.neighbors.iter().copied())
+            .filter(|i| map.sectors[*i].contains(point))
+            .max_by(|a, b| {
+                let a = map.sectors[*a].floor_aabb.max.y;
+                let b = map.sectors[*b].floor_aabb.max.y;
+                a.total_cmp(&b)
+            });
+
+        if best.is_some() {
+            return best;
+        }
     }
+
+    sector_containing(map, point)
 }
 
-impl PhysicsHooks<RigidBodySet, ColliderSet> for Test {
-    fn modify_solver_contacts(&self, context: &mut ContactModificationContext<RigidBodySet, ColliderSet>) {
-        //println!("modify_solver_contacts {:?}", context.rigid_body2);
-        if let Some(rigid_body) = context.rigid_body2 {
-            let collider = context.colliders.get(context.collider1)
-                .unwrap();
-            let rigid_body = context.bodies.get(rigid_body)
-                .unwrap();
+/// Distance from a neighboring sector where its environment starts to blend
+/// in (world units)
+const ENVIRONMENT_BLEND_DISTANCE: f32 = 64.0;
 
+/// Environment at `point` inside the `current` sector, the neighbors are
+/// blended in close to the sector borders to avoid harsh transitions
+fn sector_environment(map: &Map, current: usize, point: Vec3)
+    -> SectorEnvironment
+{
+    let sector = &map.sectors[current];
 
-            let normal = Vec3::new(context.normal.x,
-                                   context.normal.y,
-                                   context.normal.z);
+    let mut total_weight = 1.0;
+    let mut fog_color = sector.environment.fog_color;
+    let mut fog_density = sector.environment.fog_density;
+    let mut light_level = sector.environment.light_level;
 
-            let dir = -normal.normalize();
+    for &index in &sector.neighbors {
+        let neighbor = &map.sectors[index];
 
-            let test = normal.dot(Vec3::new(0.0, 1.0, 0.0));
-            if test > 0.0 || test < 0.0 {
-                let aabb = collider.shape().compute_local_aabb();
-                let height = aabb.extents().y;
-                *context.normal = vector![0.0, 1.0, 0.0];
+        let distance = neighbor.floor_aabb.distance_xz(point);
+        if distance >= ENVIRONMENT_BLEND_DISTANCE {
+            continue;
+        }
 
-                /*
-                for mut solve in context.solver_contacts.iter_mut() {
-                    let mut vel = dir * 10.0;
-                    vel.y = vel.y + height * 100.0;
+        // NOTE(patrik): Right at the border both sectors get the same
+        // weight
+        let weight = 1.0 - distance / ENVIRONMENT_BLEND_DISTANCE;
+        total_weight += weight;
+        fog_color += neighbor.environment.fog_color * weight;
+        fog_density += neighbor.environment.fog_density * weight;
+        light_level += neighbor.environment.light_level * weight;
+    }
 
-                    solve.tangent_velocity = vector![0.0, 100.0, 0.0];
+    SectorEnvironment {
+        fog_color: fog_color / total_weight,
+        fog_density: fog_density / total_weight,
+        light_level: light_level / total_weight,
+    }
+}
+
+/// Mark the sectors reachable within `PORTAL_DEPTH` steps from `start`
+fn visible_sectors(map: &Map, start: usize) -> Vec<bool> {
+    let mut visible = vec![false; map.sectors.len()];
+    visible[start] = true;
+
+    let mut frontier = vec![start];
+    for _ in 0..PORTAL_DEPTH {
+        let mut next = Vec::new();
+        for index in frontier {
+            for &neighbor in &map.sectors[index].neighbors {
+                if !visible[neighbor] {
+                    visible[neighbor] = true;
+                    next.push(neighbor);
                 }
-                */
             }
         }
+
+        frontier = next;
     }
 
+    visible
 }
 
-fn main() {
-    env_logger::init();
+/// Load the map `iterations` times and print the average time spent in the
+/// different parts of the loading
+fn bench_load(path: &Path, gpu_device: &GpuDevice, iterations: usize)
+    -> Result<(), MapLoadError>
+{
+    let mut total = MapLoadTimings::default();
+    let mut total_time = Duration::ZERO;
+
+    for _ in 0..iterations {
+        // NOTE(patrik): Fresh registry every time so the meshes from the
+        // previous iteration gets freed
+        let mut meshes = MeshRegistry::new();
+
+        let start = Instant::now();
+        let map = load_map(path, gpu_device, &mut meshes,
+                           MapLoadOptions::default())?;
+        total_time += start.elapsed();
+
+        total.deserialize += map.load_timings.deserialize;
+        total.meshes += map.load_timings.meshes;
+        total.colliders += map.load_timings.colliders;
+    }
 
-    let mut glfw = glfw::init(glfw::FAIL_ON_ERRORS).unwrap();
-    glfw.window_hint(glfw::WindowHint::ClientApi(glfw::ClientApiHint::NoApi));
+    let iterations = iterations.max(1) as u32;
+    println!("Loaded '{}' {} times, average:", path.display(), iterations);
+    println!("  Total:       {:?}", total_time / iterations);
+    println!("  Deserialize: {:?}", total.deserialize / iterations);
+    println!("  Meshes:      {:?}", total.meshes / iterations);
+    println!("  Colliders:   {:?}", total.colliders / iterations);
 
-    let (mut window, events) =
-        glfw.create_window(1280, 720,
-                           "Hello this is window",
-                           glfw::WindowMode::Windowed)
-            .expect("Failed to create GLFW window.");
+    Ok(())
+}
 
-    window.set_key_polling(true);
-    window.set_cursor_pos_polling(true);
-    window.set_cursor_mode(glfw::CursorMode::Disabled);
+#[derive(Copy, Clone, Debug)]
+struct MapLoadOptions {
+    /// Generate colliders for the ceilings, maps without meaningful
+    /// ceilings can skip them
+    ceiling_colliders: bool,
 
-    let (window_width, window_height) = window.get_framebuffer_size();
+    floor_material: SurfaceMaterial,
+    wall_material: SurfaceMaterial,
+    ceiling_material: SurfaceMaterial,
+}
 
-    let aspect_ratio = window_width as f32 / window_height as f32;
-    let projection_matrix = Mat4::perspective_lh(90.0f32.to_radians(), aspect_ratio, 0.1, 2000.0);
+impl Default for MapLoadOptions {
+    fn default() -> Self {
+        Self {
+            ceiling_colliders: true,
+
+            // NOTE(patrik): Floors grip so the player doesn't slide
+            // around, walls and ceilings are slippery so the player slides
+            // along them instead of getting stuck
+            floor_material: SurfaceMaterial {
+                friction: 1.0,
+                friction_combine_rule: CoefficientCombineRule::Average,
+                restitution: 0.0,
+            },
+            wall_material: SurfaceMaterial::SLIPPERY,
+            ceiling_material: SurfaceMaterial::SLIPPERY,
+        }
+    }
+}
+
+/// Version of the mime format the loader understands
+const MIME_FORMAT_VERSION: u32 = 1;
+
+/// Versioned maps start with the magic followed by the format version as
+/// a little endian u32, maps from before the header are version 1
+const MIME_MAGIC: &[u8; 4] = b"MIME";
+
+/// Check the format header of `data` and return the map data after it
+fn strip_format_header(data: &[u8]) -> Result<&[u8], MapLoadError> {
+    let rest = match data.strip_prefix(MIME_MAGIC) {
+        Some(rest) => rest,
+        None => {
+            log::debug!("Map: No format header, assuming version {}",
+                        MIME_FORMAT_VERSION);
+            return Ok(data);
+        }
+    };
+
+    if rest.len() < 4 {
+        return Err(MapLoadError::Deserialize(
+            "Truncated format header".to_string()));
+    }
+
+    let (version, rest) = rest.split_at(4);
+    let version = u32::from_le_bytes(version.try_into().unwrap());
+    if version != MIME_FORMAT_VERSION {
+        return Err(MapLoadError::UnsupportedVersion(version));
+    }
+
+    Ok(rest)
+}
+
+/// Log which of the optional `MeshSurface` fields the map has
+fn log_optional_fields(sectors: &[Sector]) {
+    let mut textures = 0;
+    let mut uvs = 0;
+    let mut material_ids = 0;
+
+    for sector in sectors {
+        let surfaces = [
+            &sector.floor_surface,
+            &sector.ceiling_surface,
+            &sector.wall_surface,
+        ];
+
+        for surface in surfaces {
+            textures += surface.texture.is_some() as usize;
+            uvs += surface.uvs.is_some() as usize;
+            material_ids += surface.material_id.is_some() as usize;
+        }
+    }
+
+    if textures == 0 && uvs == 0 && material_ids == 0 {
+        log::info!("Map: No texture names, UVs or material ids, using the \
+                    vertex colors");
+        return;
+    }
+
+    log::info!("Map: Meshes with texture names: {}, UVs: {}, \
+                material ids: {}", textures, uvs, material_ids);
+}
+
+/// Checks that there is something for the player to stand on, without it
+/// the player would fall forever. `floors` are the floor meshes of the
+/// deserialized sectors
+fn validate_sectors<'a, I>(floors: I) -> Result<(), MapLoadError>
+    where I: IntoIterator<Item = &'a mime::Mesh>
+{
+    let mut sector_count = 0;
+    let mut has_floor = false;
+    for floor in floors {
+        sector_count += 1;
+        has_floor |= !floor.index_buffer.is_empty();
+    }
+
+    if sector_count == 0 {
+        Err(MapLoadError::EmptyMap)
+    } else if !has_floor {
+        Err(MapLoadError::NoFloor)
+    } else {
+        Ok(())
+    }
+}
+
+fn load_map<P>(filename: P,
+               gpu_device: &GpuDevice,
+               meshes: &mut MeshRegistry,
+               options: MapLoadOptions)
+    -> Result<Map, MapLoadError>
+    where P: AsRef<Path>
+{
+    let filename = filename.as_ref();
+    let _span = tracing::info_span!("load_map", path = %filename.display())
+        .entered();
+
+    let mut file = File::open(filename)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    let mut load_timings = MapLoadTimings::default();
+
+    // NOTE(patrik): Checked before deserializing since a map from another
+    // version can deserialize into garbage instead of failing
+    let map_data = strip_format_header(&data)?;
+
+    let start = Instant::now();
+    let mime_map = mime::Map::deserialize(map_data)
+        .map_err(|e| MapLoadError::Deserialize(format!("{:?}", e)))?;
+    load_timings.deserialize = start.elapsed();
+
+    validate_sectors(mime_map.sectors.iter().map(|s| &s.floor_mesh))?;
+
+    let mut sectors = Vec::new();
+
+    // NOTE(patrik): Number of zero area triangles left out of the colliders
+    let mut skipped_triangles = 0;
+
+    let mut index = 0;
+    for sector in &mime_map.sectors {
+        // TODO(patrik): Map the UVs from MeshSurface into a
+        // render::TextureAtlas when the exporter writes texture names and
+        // UVs for the vertices
+        let generate_mesh = |m: &mime::Mesh| {
+            let positions = m.vertex_buffer.iter()
+                .map(|v| Vec3::new(v.x, v.y, v.z))
+                .collect::<Vec<_>>();
+            let normals =
+                geometry::compute_vertex_normals(&positions, &m.index_buffer);
+
+            let mut vertex_buffer = Vec::new();
+
+            for (v, normal) in m.vertex_buffer.iter().zip(normals) {
+                vertex_buffer.push(Vertex {
+                    position: [v.x, v.y, v.z],
+                    // NOTE(patrik): The map format has no alpha
+                    color: [v.color[0], v.color[1], v.color[2], 1.0],
+                    normal: normal.to_array(),
+                });
+            }
+
+            let index_buffer = &m.index_buffer;
+
+            Mesh::from_data(gpu_device, &vertex_buffer, index_buffer)
+        };
+
+        let mut generate_collider = |m: &mime::Mesh,
+                                     material: SurfaceMaterial,
+                                     t: bool| {
+            let mut points = Vec::new();
+
+            for v in &m.vertex_buffer {
+                points.push(Point3::new(v.x / UNIT_TO_METERS,
+                                        v.y / UNIT_TO_METERS,
+                                        v.z / UNIT_TO_METERS));
+            }
+
+            let positions = m.vertex_buffer.iter()
+                .map(|v| Vec3::new(v.x, v.y, v.z))
+                .collect::<Vec<_>>();
+
+            // NOTE(patrik): Zero area triangles gives the trimesh bad
+            // contacts that the player can get caught on
+            let indices = geometry::non_degenerate_triangles(&positions,
+                                                             &m.index_buffer);
+            skipped_triangles += m.index_buffer.len() / 3 - indices.len();
+
+            let builder = material.apply(ColliderBuilder::trimesh(points,
+                                                                  indices));
+
+            if t {
+                builder
+                    .active_hooks(ActiveHooks::MODIFY_SOLVER_CONTACTS)
+                    .build()
+            } else {
+                builder.build()
+            }
+        };
+
+        let start = Instant::now();
+
+        let floor_collider = generate_collider(&sector.floor_mesh,
+                                               options.floor_material, false);
+        let wall_collider = generate_collider(&sector.wall_mesh,
+                                              options.wall_material, true);
+
+        let ceiling_collider = if options.ceiling_colliders &&
+                                  !sector.ceiling_mesh.index_buffer.is_empty()
+        {
+            Some(generate_collider(&sector.ceiling_mesh,
+                                   options.ceiling_material, false))
+        } else {
+            None
+        };
+
+        load_timings.colliders += start.elapsed();
+
+        let floor_aabb = Aabb::from_points(
+            sector.floor_mesh.vertex_buffer.iter()
+                .map(|v| Vec3::new(v.x, v.y, v.z)));
+
+        let floor_triangles = sector.floor_mesh.index_buffer.chunks_exact(3)
+            .map(|t| {
+                let p = |i: u32| {
+                    let v = &sector.floor_mesh.vertex_buffer[i as usize];
+                    Vec2::new(v.x, v.z)
+                };
+
+                [p(t[0]), p(t[1]), p(t[2])]
+            })
+            .collect();
+
+        let start = Instant::now();
+
+        let floor_mesh = meshes.register(generate_mesh(&sector.floor_mesh));
+        let ceiling_mesh = meshes.register(generate_mesh(&sector.ceiling_mesh));
+        let wall_mesh = meshes.register(generate_mesh(&sector.wall_mesh));
+
+        load_timings.meshes += start.elapsed();
+
+        sectors.push(Sector {
+            model_matrix: Mat4::IDENTITY,
+
+            // TODO(patrik): The map format doesn't carry any lighting or
+            // fog information yet
+            environment: SectorEnvironment::default(),
+            material: Material::default(),
+
+            floor_aabb,
+
+            neighbors: Vec::new(),
+            floor_triangles,
+
+            floor_mesh,
+            ceiling_mesh,
+            wall_mesh,
+
+            floor_surface: MeshSurface::read(&sector.floor_mesh),
+            ceiling_surface: MeshSurface::read(&sector.ceiling_mesh),
+            wall_surface: MeshSurface::read(&sector.wall_mesh),
+
+            floor_collider: Some(floor_collider),
+            wall_collider: Some(wall_collider),
+            ceiling_collider,
+        });
+
+        index += 1;
+    }
+
+    if skipped_triangles > 0 {
+        log::info!("Map: Skipped {} degenerate collider triangles",
+                   skipped_triangles);
+    }
+
+    compute_sector_neighbors(&mut sectors);
+
+    let vertex_count: usize = mime_map.sectors.iter()
+        .map(|s| s.floor_mesh.vertex_buffer.len() +
+                 s.ceiling_mesh.vertex_buffer.len() +
+                 s.wall_mesh.vertex_buffer.len())
+        .sum();
+    log::info!("Map: Loaded '{}' with {} sectors and {} vertices",
+               filename.display(), sectors.len(), vertex_count);
+    log_optional_fields(&sectors);
+
+    let spawn = find_spawn_point(&sectors);
+
+    let map = Map {
+        sectors,
+        spawn,
+
+        load_timings,
+    };
+
+    Ok(map)
+}
+
+
+struct DeltaTime(f32);
+
+/// Vertical field of view of the camera in degrees
+struct FieldOfView(f32);
+
+/// Widens the field of view when the player moves fast
+struct SpeedFov {
+    enabled: bool,
+    /// Field of view at full speed (degrees)
+    max: f32,
+    /// How fast the field of view follows the speed
+    rate: f32,
+
+    /// Field of view used for the projection this frame (degrees)
+    current: f32,
+}
+
+/// Speed where the field of view reaches `SpeedFov::max` (meters per
+/// second)
+const SPEED_FOV_FULL_SPEED: f32 = 15.0;
+
+fn update_speed_fov(query: Query<&MovementState, With<Player>>,
+                    field_of_view: Res<FieldOfView>,
+                    mut speed_fov: ResMut<SpeedFov>,
+                    dt: Res<DeltaTime>)
+{
+    let base = field_of_view.0;
+    if !speed_fov.enabled {
+        speed_fov.current = base;
+        return;
+    }
+
+    let speed = query.iter()
+        .map(|movement| movement.speed)
+        .fold(0.0, f32::max);
+
+    // NOTE(patrik): The base can be changed from the console to something
+    // wider than the max
+    let max = speed_fov.max.max(base);
+    let t = (speed / SPEED_FOV_FULL_SPEED).min(1.0);
+    let target = base + (max - base) * t;
+
+    let blend = (speed_fov.rate * dt.0).min(1.0);
+    speed_fov.current += (target - speed_fov.current) * blend;
+}
+
+/// Brightness adjustment of the scene, set from the console
+struct DisplaySettings {
+    gamma: f32,
+    exposure: f32,
+}
+
+/// What the scene pass drew during the current frame
+#[derive(Copy, Clone, Debug, Default)]
+struct RenderStats {
+    sectors_drawn: u32,
+    sectors_culled: u32,
+    draw_calls: u32,
+    triangles: u32,
+    /// The draw calls and triangles include the depth pre-pass
+    depth_prepass: bool,
+    /// GPU time of a recent frame, only measured with `gpu_timing`
+    gpu_time_ms: Option<f32>,
+}
+
+/// Debug filter for which sectors get drawn, set with the `sectors`
+/// console command. None draws all of them, indices outside of the map
+/// are ignored
+#[derive(Clone, Debug, Default)]
+struct VisibleSectors(Option<Range<usize>>);
+
+impl VisibleSectors {
+    fn contains(&self, index: usize) -> bool {
+        match &self.0 {
+            Some(range) => range.contains(&index),
+            None => true,
+        }
+    }
+}
+
+/// Direction the directional light shines in
+const SUN_DIRECTION: [f32; 3] = [-0.4, -1.0, -0.3];
+/// Strength of the directional light on top of the sector light level
+const SUN_INTENSITY: f32 = 0.4;
+/// Size of the area around the player covered by the shadow map (world
+/// units)
+const SHADOW_EXTENT: f32 = 1024.0;
+
+/// How often the render stats are logged (in seconds)
+const RENDER_STATS_INTERVAL: f32 = 1.0;
+
+/// Freezes the physics simulation while true, rendering and looking around
+/// still works
+struct Paused(bool);
+
+/// Color the scene is cleared to before rendering
+struct ClearColor(wgpu::Color);
+
+#[derive(Component, Debug)]
+#[repr(transparent)]
+struct Position(Vec3);
+
+#[derive(Component, Debug)]
+struct Camera {
+    direction: Vec3,
+    up: Vec3,
+
+    /// Offset from the `Position` up to the eye of the camera
+    eye_height: f32,
+    /// Tilt around the view direction in radians, 0 keeps the view level.
+    /// Used for effects like leaning or a landing impact
+    roll: f32,
+}
+
+#[derive(Component, Copy, Clone)]
+struct Player {
+    collider_handle: ColliderHandle,
+    body_handle: RigidBodyHandle,
+    speed: f32,
+    /// Upwards speed given by a jump (meters per second)
+    jump_speed: f32,
+}
+
+/// Upwards speed needed to reach `height` (world units) with the default
+/// gravity
+fn jump_speed_for_height(height: f32) -> f32 {
+    // NOTE(patrik): Changing the gravity from the console changes the
+    // height reached
+    (2.0 * 9.81 * (height / UNIT_TO_METERS).max(0.0)).sqrt()
+}
+
+/// Mouse movement (pixels per frame) where the acceleration curve turns
+/// the same amount as the linear one
+const MOUSE_ACCELERATION_PIVOT: f32 = 10.0;
+
+/// Deadzone and acceleration for the raw mouse movement. Movement shorter
+/// than `deadzone` is dropped, the rest is scaled by the length raised to
+/// `exponent`, an exponent of 1 leaves the movement unchanged
+fn apply_mouse_curve(delta: Vec2, deadzone: f32, exponent: f32) -> Vec2 {
+    let length = delta.length();
+    if length == 0.0 || length < deadzone {
+        return Vec2::ZERO;
+    }
+
+    if exponent == 1.0 {
+        return delta;
+    }
+
+    let curved = (length / MOUSE_ACCELERATION_PIVOT).powf(exponent) *
+        MOUSE_ACCELERATION_PIVOT;
+    delta * (curved / length)
+}
+
+fn update_camera(mut query: Query<(&mut Position, &mut Camera, &Player, &mut MovementState)>,
+                 mut game_state: ResMut<GameState>,
+                 mut bodies: ResMut<RigidBodySet>,
+                 audio: Res<Audio>,
+                 dt: Res<DeltaTime>)
+{
+    let mouse_delta = std::mem::take(&mut game_state.mouse_delta);
+    let mouse_delta = apply_mouse_curve(mouse_delta,
+                                        game_state.mouse_deadzone,
+                                        game_state.mouse_acceleration);
+    let invert = if game_state.invert_y { -1.0 } else { 1.0 };
+    game_state.yaw -= mouse_delta.x * game_state.sensitivity;
+    game_state.pitch += mouse_delta.y * game_state.sensitivity * invert;
+
+    for (mut position, mut camera, player, mut movement) in query.iter_mut() {
+        let mut body = bodies.get_mut(player.body_handle).unwrap();
+
+        let pitch = game_state.pitch;
+        let yaw = game_state.yaw;
+
+        let direction = Vec3::new(
+            yaw.to_radians().cos() * pitch.to_radians().cos(),
+            pitch.to_radians().sin(),
+            yaw.to_radians().sin() * pitch.to_radians().cos());
+
+        camera.direction = direction.normalize();
+
+        if game_state.noclip {
+            fly_noclip(body, &game_state, camera.direction, camera.up,
+                       dt.0);
+            movement.wish_direction = Vec2::ZERO;
+            continue;
+        }
+
+        const SPEED: f32 = 10.0;
+        /// How fast the player reaches `SPEED` (meters per second squared)
+        const MOVE_ACCELERATION: f32 = 60.0;
+
+        let horizontal_direction = Vec2::new(camera.direction.x,
+                                             camera.direction.z)
+            .normalize_or_zero();
+        movement.wish_direction = if game_state.up {
+            horizontal_direction
+        } else if game_state.down {
+            -horizontal_direction
+        } else {
+            Vec2::ZERO
+        };
+
+        // NOTE(patrik): Push the velocity towards the wished velocity
+        // instead of setting it, without input the linear damping and the
+        // friction slows the player down
+        if movement.wish_direction != Vec2::ZERO {
+            let linvel = body.linvel();
+            let current = Vec2::new(linvel.x, linvel.z);
+            let change = (movement.wish_direction * SPEED - current)
+                .clamp_length_max(MOVE_ACCELERATION * dt.0);
+
+            let impulse = change * body.mass();
+            body.apply_impulse(vector![impulse.x, 0.0, impulse.y], true);
+        }
+
+        if game_state.jump && movement.grounded {
+            // NOTE(patrik): The impulse only changes the vertical velocity
+            // so the horizontal momentum is kept, any leftover vertical
+            // velocity is cancelled so the jump height is the same
+            let delta = player.jump_speed - body.linvel().y;
+            body.apply_impulse(vector![0.0, delta * body.mass(), 0.0], true);
+
+            audio.play_sound(JUMP_SOUND);
+        }
+        /*
+        let speed = player.speed;
+
+        if game_state.up {
+            position.0 += (speed * camera.direction) * dt.0;
+        }
+
+        if game_state.down {
+            position.0 -= (speed * camera.direction) * dt.0;
+        }
+
+        if game_state.right {
+            position.0 -= (camera.direction.cross(camera.up).normalize() * speed) * dt.0;
+        }
+
+        if game_state.left {
+            position.0 += (camera.direction.cross(camera.up).normalize() * speed) * dt.0;
+        }
+        */
+    }
+}
+
+/// Fly speed while no-clip is enabled (meters per second)
+const NOCLIP_SPEED: f32 = 20.0;
+
+/// Move the player body straight along the camera, the body doesn't
+/// collide with anything while no-clip is enabled so the movement is
+/// applied directly to the translation
+fn fly_noclip(body: &mut RigidBody,
+              game_state: &GameState,
+              direction: Vec3,
+              up: Vec3,
+              dt: f32)
+{
+    let right = direction.cross(up).normalize_or_zero();
+
+    let mut wish = Vec3::ZERO;
+    if game_state.up { wish += direction; }
+    if game_state.down { wish -= direction; }
+    if game_state.left { wish += right; }
+    if game_state.right { wish -= right; }
+
+    let offset = wish.normalize_or_zero() * NOCLIP_SPEED * dt;
+    let translation = body.translation() + vector![offset.x, offset.y, offset.z];
+
+    body.set_translation(translation, true);
+    body.set_linvel(vector![0.0, 0.0, 0.0], true);
+}
+
+/// Turn the player collider into a sensor and ignore gravity while no-clip
+/// is enabled, when disabled the body is dropped at the current position
+/// with zero velocity
+fn set_noclip(bodies: &mut RigidBodySet,
+              colliders: &mut ColliderSet,
+              player: &Player,
+              enabled: bool)
+{
+    if let Some(collider) = colliders.get_mut(player.collider_handle) {
+        collider.set_sensor(enabled);
+    }
+
+    if let Some(body) = bodies.get_mut(player.body_handle) {
+        let gravity_scale = if enabled { 0.0 } else { 1.0 };
+        body.set_gravity_scale(gravity_scale, true);
+        body.set_linvel(vector![0.0, 0.0, 0.0], true);
+    }
+}
+
+/// Moves a sector up and down along Y between `low` and `high` (world
+/// units, relative to the authored position), opens while the player is
+/// within `trigger_distance` of the sector
+#[derive(Component)]
+struct SectorAnimation {
+    sector: usize,
+    body_handle: RigidBodyHandle,
+
+    low: f32,
+    high: f32,
+    speed: f32,
+    trigger_distance: f32,
+
+    offset: f32,
+}
+
+fn update_sector_animations(mut query: Query<&mut SectorAnimation>,
+                            players: Query<&Position, With<Player>>,
+                            mut map: ResMut<Map>,
+                            mut bodies: ResMut<RigidBodySet>,
+                            dt: Res<DeltaTime>)
+{
+    for mut animation in query.iter_mut() {
+        let sector = match map.sectors.get_mut(animation.sector) {
+            Some(sector) => sector,
+            None => continue,
+        };
+
+        let center = sector.floor_aabb.center();
+        let triggered = players.iter().any(|position| {
+            position.0.distance(center) < animation.trigger_distance
+        });
+
+        let target = if triggered { animation.high } else { animation.low };
+        let step = animation.speed * dt.0;
+        let offset = animation.offset;
+        animation.offset = if offset < target {
+            (offset + step).min(target)
+        } else {
+            (offset - step).max(target)
+        };
+
+        let translation = Vec3::new(0.0, animation.offset, 0.0);
+        sector.model_matrix = Mat4::from_translation(translation);
+
+        // NOTE(patrik): The sector colliders are attached to a kinematic
+        // body so the player gets carried along when riding the sector
+        if let Some(body) = bodies.get_mut(animation.body_handle) {
+            let t = translation / UNIT_TO_METERS;
+            body.set_next_kinematic_translation(vector![t.x, t.y, t.z]);
+        }
+    }
+}
+
+/// Debug command for moving the player to the center of a sector, the
+/// sector index is typed with the number keys and triggered with T
+fn teleport_player(query: Query<&Player>,
+                   mut game_state: ResMut<GameState>,
+                   map: Res<Map>,
+                   mut bodies: ResMut<RigidBodySet>)
+{
+    let index = match game_state.teleport_sector.take() {
+        Some(index) => index,
+        None => return,
+    };
+
+    let sector = match map.sectors.get(index) {
+        Some(sector) => sector,
+        None => {
+            log::warn!("Teleport: No sector with index {} ({} sectors)",
+                       index, map.sectors.len());
+            return;
+        }
+    };
+
+    let aabb = &sector.floor_aabb;
+    let center = aabb.center();
+    let target = Vec3::new(center.x, aabb.max.y + SPAWN_HEIGHT, center.z);
+    let target = target / UNIT_TO_METERS;
+
+    for player in query.iter() {
+        if let Some(body) = bodies.get_mut(player.body_handle) {
+            place_body(body, target);
+        }
+    }
+
+    log::info!("Teleported player to sector {}", index);
+}
+
+/// Height (world units) the player is considered to have fallen out of
+/// the world below
+struct KillPlane(f32);
+
+/// Where the player last stood on something (world units)
+#[derive(Component, Copy, Clone, Debug)]
+struct LastGrounded(Vec3);
+
+/// Height above the last grounded position the player is placed at when
+/// recovering from a fall, so it doesn't land right in the same hole
+/// (world units)
+const RECOVER_HEIGHT: f32 = 8.0;
+
+/// Uses the ray based `MovementState::grounded`, so the top of a jump over
+/// a hole isn't stored as a place to recover to
+fn update_last_grounded(mut query: Query<(&Position, &MovementState, &mut LastGrounded)>,
+                        game_state: Res<GameState>,
+                        kill_plane: Res<KillPlane>)
+{
+    if game_state.noclip {
+        return;
+    }
+
+    for (position, movement, mut last_grounded) in query.iter_mut() {
+        if movement.grounded && position.0.y >= kill_plane.0 {
+            last_grounded.0 = position.0;
+        }
+    }
+}
+
+fn recover_fallen_player(query: Query<(&Position, &Player, Option<&LastGrounded>)>,
+                         game_state: Res<GameState>,
+                         kill_plane: Res<KillPlane>,
+                         map: Res<Map>,
+                         mut bodies: ResMut<RigidBodySet>)
+{
+    if game_state.noclip {
+        return;
+    }
+
+    for (position, player, last_grounded) in query.iter() {
+        if position.0.y >= kill_plane.0 {
+            continue;
+        }
+
+        let target = match last_grounded {
+            Some(last_grounded) => {
+                last_grounded.0 + Vec3::new(0.0, RECOVER_HEIGHT, 0.0)
+            }
+
+            None => map.spawn.position,
+        };
+
+        // NOTE(patrik): The horizontal position is where the player fell
+        // through, useful for finding the broken collider
+        log::warn!("Player fell out of the world at ({:.2}, {:.2}, {:.2}), \
+                    moving back to ({:.2}, {:.2}, {:.2})",
+                   position.0.x, position.0.y, position.0.z,
+                   target.x, target.y, target.z);
+
+        if let Some(body) = bodies.get_mut(player.body_handle) {
+            place_body(body, target / UNIT_TO_METERS);
+        }
+    }
+}
+
+/// Move `body` to `position` (meters) and stop it
+fn place_body(body: &mut RigidBody, position: Vec3) {
+    body.set_translation(vector![position.x, position.y, position.z], true);
+    body.set_linvel(vector![0.0, 0.0, 0.0], true);
+    body.set_angvel(vector![0.0, 0.0, 0.0], true);
+
+    // NOTE(patrik): The player is moved with impulses and rapier clears
+    // the accumulated forces after every step, so there is nothing left
+    // that could push the body after this
+}
+
+fn reset_player(query: Query<&Player>,
+                mut game_state: ResMut<GameState>,
+                map: Res<Map>,
+                mut bodies: ResMut<RigidBodySet>)
+{
+    if !std::mem::take(&mut game_state.reset_player) {
+        return;
+    }
+
+    let spawn = map.spawn;
+    for player in query.iter() {
+        if let Some(body) = bodies.get_mut(player.body_handle) {
+            place_body(body, spawn.position / UNIT_TO_METERS);
+        }
+    }
+
+    game_state.yaw = spawn.yaw;
+    game_state.pitch = 0.0;
+
+    log::info!("Reset player to the spawn point");
+}
+
+/// Movement of the player derived from the rigid body, velocities are in
+/// meters per second
+#[derive(Component, Copy, Clone, Debug, Default)]
+struct MovementState {
+    speed: f32,
+    /// Updated after every physics update with `player_grounded`
+    grounded: bool,
+    horizontal_velocity: Vec2,
+
+    /// Normalized direction on the XZ plane the player wants to move in,
+    /// zero when standing still
+    wish_direction: Vec2,
+}
+
+/// Distance below the feet of the player where something counts as ground
+/// (meters)
+const GROUND_PROBE_DISTANCE: f32 = 0.1;
+
+/// Upwards speed (meters per second) above which the player isn't grounded,
+/// right after a jump the feet are still close enough to the floor
+const MAX_GROUNDED_RISE_SPEED: f32 = 1.0;
+
+/// Cast a short ray down from the feet of the player. The vertical velocity
+/// alone can't tell standing on the floor from the top of a jump
+fn player_grounded(physics: &PhysicsContext,
+                   bodies: &RigidBodySet,
+                   colliders: &ColliderSet,
+                   player: &Player)
+    -> bool
+{
+    let rising = match bodies.get(player.body_handle) {
+        Some(body) => body.linvel().y > MAX_GROUNDED_RISE_SPEED,
+        None => return false,
+    };
+
+    let aabb = match colliders.get(player.collider_handle) {
+        Some(collider) => collider.compute_aabb(),
+        None => return false,
+    };
+
+    // NOTE(patrik): Start above the feet so the ray doesn't begin inside
+    // of the floor when the player sinks into it a bit
+    let center = aabb.center();
+    let origin = point![center.x, aabb.mins.y + GROUND_PROBE_DISTANCE,
+                        center.z];
+    let hit = physics.cast_ray(colliders, origin, vector![0.0, -1.0, 0.0],
+                               GROUND_PROBE_DISTANCE * 2.0,
+                               Some(player.collider_handle));
+
+    hit.is_some() && !rising
+}
+
+fn update_movement_state(mut query: Query<(&Player, &mut MovementState)>,
+                         bodies: Res<RigidBodySet>)
+{
+    for (player, mut movement) in query.iter_mut() {
+        let body = match bodies.get(player.body_handle) {
+            Some(body) => body,
+            None => continue,
+        };
+
+        let linvel = body.linvel();
+        let horizontal_velocity = Vec2::new(linvel.x, linvel.z);
+
+        movement.speed = horizontal_velocity.length();
+        movement.horizontal_velocity = horizontal_velocity;
+    }
+}
+
+/// Radians the view bob advances per meter walked, one step is half a
+/// cycle
+const BOB_RATE: f32 = 1.6;
+/// Max vertical and sideways offset of the eye (world units)
+const BOB_HEIGHT: f32 = 0.6;
+const BOB_SWAY: f32 = 0.4;
+/// Speed where the bob reaches its full amplitude (meters per second)
+const BOB_FULL_SPEED: f32 = 10.0;
+/// How fast the bob fades in and out when starting and stopping
+const BOB_FADE_RATE: f32 = 8.0;
+
+/// Camera bob while walking, only added to the player when enabled in the
+/// config
+#[derive(Component, Copy, Clone, Debug, Default)]
+struct ViewBob {
+    phase: f32,
+    /// 0 to 1, eased towards the current speed so the bob doesn't snap
+    amount: f32,
+    /// Sideways (x) and vertical (y) offset of the eye this frame
+    offset: Vec2,
+}
+
+fn update_view_bob(mut query: Query<(&mut ViewBob, &MovementState)>,
+                   game_state: Res<GameState>,
+                   dt: Res<DeltaTime>)
+{
+    let dt = dt.0;
+
+    for (mut bob, movement) in query.iter_mut() {
+        let walking = movement.grounded && !game_state.noclip;
+        let target = if walking {
+            (movement.speed / BOB_FULL_SPEED).min(1.0)
+        } else {
+            0.0
+        };
+
+        bob.amount += (target - bob.amount) * (BOB_FADE_RATE * dt).min(1.0);
+        bob.phase = (bob.phase + movement.speed * BOB_RATE * dt) %
+            std::f32::consts::TAU;
+
+        // NOTE(patrik): The eye dips on every step and sways once per
+        // two steps
+        bob.offset = Vec2::new(bob.phase.sin() * BOB_SWAY,
+                               -(bob.phase * 2.0).sin().abs() * BOB_HEIGHT) *
+            bob.amount;
+    }
+}
+
+/// Extra distance in front of the player checked for steps (meters)
+const STEP_PROBE_DISTANCE: f32 = 0.5;
+
+/// Lift the player up on to a step in front of it, a step is an obstacle
+/// at foot height with free space above it lower than `max_step_height`
+/// (meters)
+fn step_up(physics: &PhysicsContext,
+           bodies: &mut RigidBodySet,
+           colliders: &ColliderSet,
+           player: &Player,
+           movement: &MovementState,
+           max_step_height: f32)
+{
+    if !movement.grounded || movement.wish_direction == Vec2::ZERO {
+        return;
+    }
+
+    let aabb = match colliders.get(player.collider_handle) {
+        Some(collider) => collider.compute_aabb(),
+        None => return,
+    };
+
+    let center = aabb.center();
+    let half_width = aabb.half_extents().x.max(aabb.half_extents().z);
+    let foot = aabb.mins.y;
+
+    let direction = vector![movement.wish_direction.x,
+                            0.0,
+                            movement.wish_direction.y];
+    let probe_distance = half_width + STEP_PROBE_DISTANCE;
+
+    let cast = |origin: Point<Real>, dir: Vector<Real>, max_toi: Real| {
+        physics.cast_ray(colliders, origin, dir, max_toi,
+                         Some(player.collider_handle))
+    };
+
+    // NOTE(patrik): Something has to block the feet...
+    let low = point![center.x, foot + 0.05, center.z];
+    let hit_toi = match cast(low, direction, probe_distance) {
+        Some((_, toi)) => toi,
+        None => return,
+    };
+
+    // NOTE(patrik): ...while the space above the step is free
+    let high = point![center.x, foot + max_step_height + 0.05, center.z];
+    if cast(high, direction, probe_distance).is_some() {
+        return;
+    }
+
+    // NOTE(patrik): Find the top of the step by casting down just past
+    // the point where the feet got blocked
+    let forward = hit_toi + 0.05;
+    let above_step = high + direction * forward;
+    let step_top = match cast(above_step, vector![0.0, -1.0, 0.0],
+                              max_step_height + 0.05)
+    {
+        Some((_, toi)) => above_step.y - toi,
+        None => return,
+    };
+
+    let lift = step_top - foot;
+    if lift <= 0.0 || lift > max_step_height {
+        return;
+    }
+
+    if let Some(body) = bodies.get_mut(player.body_handle) {
+        let translation = body.translation() + vector![0.0, lift + 0.01, 0.0] +
+            direction * 0.05;
+        body.set_translation(translation, true);
+    }
+}
+
+fn map_sector_center(world: &World, index: usize) -> Option<Vec3> {
+    let map = world.get_resource::<Map>()?;
+    map.sectors.get(index).map(|sector| sector.floor_aabb.center())
+}
+
+/// The sector the player is currently in, None when outside of the map
+struct CurrentSector(Option<usize>);
+
+fn update_current_sector(query: Query<&Position, With<Player>>,
+                         map: Res<Map>,
+                         mut current_sector: ResMut<CurrentSector>)
+{
+    for position in query.iter() {
+        current_sector.0 =
+            sector_containing_near(&map, position.0, current_sector.0);
+    }
+}
+
+fn log_player_position(query: Query<(&Position, Option<&LastGrounded>), With<Player>>,
+                       mut game_state: ResMut<GameState>,
+                       current_sector: Res<CurrentSector>)
+{
+    if !std::mem::take(&mut game_state.log_position) {
+        return;
+    }
+
+    for (position, last_grounded) in query.iter() {
+        let meters = position.0 / UNIT_TO_METERS;
+        let sector = match current_sector.0 {
+            Some(index) => index.to_string(),
+            None => "none".to_string(),
+        };
+
+        log::info!("Player: Position ({:.2}, {:.2}, {:.2}) units, \
+                    ({:.2}, {:.2}, {:.2}) meters, sector {}, \
+                    yaw {:.1} pitch {:.1}",
+                   position.0.x, position.0.y, position.0.z,
+                   meters.x, meters.y, meters.z,
+                   sector, game_state.yaw, game_state.pitch);
+
+        if let Some(last_grounded) = last_grounded {
+            log::info!("Player: Last grounded at ({:.2}, {:.2}, {:.2}) units",
+                       last_grounded.0.x, last_grounded.0.y, last_grounded.0.z);
+        }
+    }
+}
+
+fn update_audio_listener(query: Query<(&Position, &Camera)>,
+                         mut audio: ResMut<Audio>)
+{
+    for (position, camera) in query.iter() {
+        let eye = position.0 + Vec3::new(0.0, camera.eye_height, 0.0);
+        audio.set_listener(eye, camera.direction, camera.up);
+    }
+}
+
+/// Position from the previous physics step, used together with `Position`
+/// to interpolate the rendered position between the physics steps
+#[derive(Component, Debug)]
+#[repr(transparent)]
+struct PreviousPosition(Vec3);
+
+/// Result of the last physics update, `alpha` is how far into the next
+/// physics step the current frame is
+struct PhysicsInterpolation {
+    steps: u32,
+    alpha: f32,
+}
+
+fn update_player_physics(mut query: Query<(&mut Position, &mut PreviousPosition, &Player)>,
+                         bodies: Res<RigidBodySet>,
+                         interpolation: Res<PhysicsInterpolation>)
+{
+    // NOTE(patrik): Only the state after the last step of the frame is
+    // seen here, when multiple steps run in a single frame the previous
+    // position is older than one step which is fine for rendering
+    if interpolation.steps == 0 {
+        return;
+    }
+
+    for (mut position, mut previous, player) in query.iter_mut() {
+        let body = bodies.get(player.body_handle).unwrap();
+        let x = body.translation().x;
+        let y = body.translation().y;
+        let z = body.translation().z;
+        let new_pos = Vec3::new(x, y, z);
+
+        previous.0 = position.0;
+        position.0 = new_pos * UNIT_TO_METERS;
+    }
+}
+
+/// Rigid body that drives the `Position` of the entity
+#[derive(Component, Copy, Clone, Debug)]
+struct PhysicsBody(RigidBodyHandle);
+
+/// Mesh drawn at the `Position` of the entity
+#[derive(Component)]
+struct Renderable {
+    mesh: MeshHandle,
+    material: Material,
+}
+
+/// Group the model matrices of the instances with equal keys, returns the
+/// range of each group in the model matrices ordered so the instances of a
+/// group are next to each other
+fn batch_instances<K, I>(instances: I) -> (Vec<(K, Range<u32>)>, Vec<Mat4>)
+    where K: PartialEq,
+          I: IntoIterator<Item=(K, Mat4)>
+{
+    let mut groups: Vec<(K, Vec<Mat4>)> = Vec::new();
+    for (key, model_matrix) in instances {
+        match groups.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, model_matrices)) => model_matrices.push(model_matrix),
+            None => groups.push((key, vec![model_matrix])),
+        }
+    }
+
+    let mut batches = Vec::with_capacity(groups.len());
+    let mut model_matrices = Vec::new();
+    for (key, group) in groups {
+        let start = model_matrices.len() as u32;
+        model_matrices.extend(group);
+
+        batches.push((key, start..model_matrices.len() as u32));
+    }
+
+    (batches, model_matrices)
+}
+
+/// Point light placed at the `Position` of the entity
+#[derive(Component, Copy, Clone, Debug)]
+struct LightSource {
+    /// Linear color, values above 1 makes the light brighter
+    color: Vec3,
+    /// Distance where the light has faded out completely (world units)
+    radius: f32,
+}
+
+fn update_particles(mut query: Query<(&Position, &mut ParticleEmitter)>,
+                    mut particles: ResMut<Particles>,
+                    dt: Res<DeltaTime>)
+{
+    let dt = dt.0;
+
+    particles.simulate(dt);
+
+    for (position, mut emitter) in query.iter_mut() {
+        particles.emit(&mut emitter, position.0, dt);
+    }
+}
+
+/// The point lights closest to `camera_pos`, at most `MAX_POINT_LIGHTS`
+fn nearest_point_lights(world: &mut World, camera_pos: Vec3)
+    -> Vec<render::PointLight>
+{
+    let mut lights = world.query::<(&Position, &LightSource)>()
+        .iter(world)
+        .map(|(position, light)| (position.0, *light))
+        .collect::<Vec<_>>();
+
+    lights.sort_by(|(a, _), (b, _)| {
+        a.distance_squared(camera_pos)
+            .partial_cmp(&b.distance_squared(camera_pos))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    lights.iter()
+        .take(render::MAX_POINT_LIGHTS)
+        .map(|(position, light)| {
+            render::PointLight::new(*position, light.color, light.radius)
+        })
+        .collect()
+}
+
+fn update_physics_bodies(mut query: Query<(&mut Position, &mut PreviousPosition, &PhysicsBody)>,
+                         bodies: Res<RigidBodySet>,
+                         interpolation: Res<PhysicsInterpolation>)
+{
+    if interpolation.steps == 0 {
+        return;
+    }
+
+    for (mut position, mut previous, body) in query.iter_mut() {
+        let body = match bodies.get(body.0) {
+            Some(body) => body,
+            None => continue,
+        };
+
+        let translation = body.translation();
+        let new_pos = Vec3::new(translation.x, translation.y, translation.z);
+
+        previous.0 = position.0;
+        position.0 = new_pos * UNIT_TO_METERS;
+    }
+}
+
+/// Spawn an entity with a dynamic rigid body at `position` (world units)
+/// that drives the `Position` of the entity
+fn spawn_physics_object(world: &mut World,
+                        colliders: &mut ColliderSet,
+                        position: Vec3,
+                        collider_shape: ColliderShape)
+    -> Entity
+{
+    let body_position = position / UNIT_TO_METERS;
+
+    let body_handle = {
+        let mut bodies = world.get_resource_mut::<RigidBodySet>().unwrap();
+
+        // NOTE(patrik): `Renderable` is only drawn at the position of the
+        // entity, so the body can't be allowed to rotate
+        let body = RigidBodyBuilder::new_dynamic()
+            .translation(vector![body_position.x,
+                                 body_position.y,
+                                 body_position.z])
+            .lock_rotations()
+            .build();
+        let body_handle = bodies.insert(body);
+
+        let collider = collider_shape.builder().build();
+        colliders.insert_with_parent(collider, body_handle, &mut bodies);
+
+        body_handle
+    };
+
+    world.spawn()
+        .insert(Position(position))
+        .insert(PreviousPosition(position))
+        .insert(PhysicsBody(body_handle))
+        .id()
+}
+
+/// Drop a box `SPAWN_BOX_DISTANCE` in front of the eye of `player`, drawn
+/// with `mesh`
+fn spawn_box(world: &mut World,
+             colliders: &mut ColliderSet,
+             player: Entity,
+             mesh: MeshHandle)
+{
+    let renderables = world.query::<&Renderable>().iter(world).count();
+    if renderables >= MAX_RENDERABLES {
+        log::warn!("Can't spawn more than {} boxes", MAX_RENDERABLES);
+        return;
+    }
+
+    let position = {
+        let player = world.entity(player);
+        let position = player.get::<Position>().unwrap().0;
+        let camera = player.get::<Camera>().unwrap();
+
+        position + Vec3::new(0.0, camera.eye_height, 0.0) +
+            camera.direction * SPAWN_BOX_DISTANCE
+    };
+
+    let shape = ColliderShape::Cuboid {
+        half_extents: SPAWN_BOX_HALF_EXTENTS,
+    };
+    let entity = spawn_physics_object(world, colliders, position, shape);
+    world.entity_mut(entity)
+        .insert(Renderable {
+            mesh,
+            material: Material::default(),
+        });
+}
+
+/// Position of the entity interpolated between the last two physics steps
+fn interpolated_position(entity: &EntityRef, alpha: f32) -> Vec3 {
+    let pos = entity.get::<Position>()
+        .expect("Entity dosen't have Position Component");
+
+    match entity.get::<PreviousPosition>() {
+        Some(previous) => previous.0.lerp(pos.0, alpha),
+        None => pos.0,
+    }
+}
+
+fn generate_view_matrix(camera: EntityRef, alpha: f32) -> Mat4 {
+    let pos = interpolated_position(&camera, alpha);
+    let controller = camera.get::<Camera>()
+        .expect("Camera dosen't have Camera Controller Component");
+
+    let up = Quat::from_axis_angle(controller.direction, controller.roll) *
+        controller.up;
+
+    let pos = match camera.get::<ViewBob>() {
+        Some(bob) => {
+            let right = up.cross(controller.direction).normalize_or_zero();
+            pos + right * bob.offset.x + up * bob.offset.y
+        }
+
+        None => pos,
+    };
+
+    view_matrix(pos, controller.direction, up, controller.eye_height)
+}
+
+fn view_matrix(pos: Vec3, direction: Vec3, up: Vec3, eye_height: f32)
+    -> Mat4
+{
+    let eye = pos + Vec3::new(0.0, eye_height, 0.0);
+
+    Mat4::look_at_lh(eye, eye + direction, up)
+}
+
+struct Test;
+
+impl EventHandler for Test {
+    fn handle_intersection_event(&self, event: IntersectionEvent) {
+        println!("handle_intersection_event");
+    }
+
+    fn handle_contact_event(&self,
+                            event: ContactEvent,
+                            contact_pair: &ContactPair)
+    {
+        println!("handle_contact_event");
+    }
+}
+
+impl PhysicsHooks<RigidBodySet, ColliderSet> for Test {
+    fn modify_solver_contacts(&self, context: &mut ContactModificationContext<RigidBodySet, ColliderSet>) {
+        //println!("modify_solver_contacts {:?}", context.rigid_body2);
+        if let Some(rigid_body) = context.rigid_body2 {
+            let collider = context.colliders.get(context.collider1)
+                .unwrap();
+            let rigid_body = context.bodies.get(rigid_body)
+                .unwrap();
+
+
+            let normal = Vec3::new(context.normal.x,
+                                   context.normal.y,
+                                   context.normal.z);
+
+            let dir = -normal.normalize();
+
+            let test = normal.dot(Vec3::new(0.0, 1.0, 0.0));
+            if test > 0.0 || test < 0.0 {
+                let aabb = collider.shape().compute_local_aabb();
+                let height = aabb.extents().y;
+                *context.normal = vector![0.0, 1.0, 0.0];
+
+                /*
+                for mut solve in context.solver_contacts.iter_mut() {
+                    let mut vel = dir * 10.0;
+                    vel.y = vel.y + height * 100.0;
+
+                    solve.tangent_velocity = vector![0.0, 100.0, 0.0];
+                }
+                */
+            }
+        }
+    }
+
+}
+
+fn main() {
+    env_logger::init();
+
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut config = Config::load(CONFIG_PATH);
+
+    let mut bench_load_iterations = None;
+    let mut record_path = None;
+    let mut replay_path = None;
+    let mut open_debug_view = false;
+
+    // NOTE(patrik): The map given on the command line overrides the one
+    // from the config
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--bench-load" {
+            let iterations = args.next()
+                .and_then(|n| n.to_str()?.parse::<usize>().ok());
+
+            match iterations {
+                Some(iterations) => bench_load_iterations = Some(iterations),
+                None => {
+                    return Err("--bench-load needs the number of iterations".into());
+                }
+            }
+        } else if arg == "--record" || arg == "--replay" {
+            let path = match args.next() {
+                Some(path) => PathBuf::from(path),
+                None => {
+                    return Err(format!("{} needs a file",
+                                       arg.to_string_lossy()).into());
+                }
+            };
+
+            if arg == "--record" {
+                record_path = Some(path);
+            } else {
+                replay_path = Some(path);
+            }
+        } else if arg == "--debug-view" {
+            open_debug_view = true;
+        } else {
+            config.map_path = arg.into();
+        }
+    }
+
+    if !config.map_path.is_file() {
+        eprintln!("Usage: nocks [--bench-load <ITERATIONS>] \
+                   [--record <FILE>] [--replay <FILE>] [--debug-view] <MAP>");
+        eprintln!("The map can also be set with 'map_path' in '{}'",
+                  CONFIG_PATH);
+        return Err(format!("Map '{}' not found",
+                           config.map_path.display()).into());
+    }
+
+    let mut gpu_options = GpuOptions {
+        vsync: config.vsync,
+        timestamp_queries: config.gpu_timing,
+        ..Default::default()
+    };
+
+    if !config.high_performance_gpu {
+        gpu_options.power_preference = wgpu::PowerPreference::LowPower;
+    }
+
+    // NOTE(patrik): Nothing is presented when benchmarking so no window
+    // is needed
+    if let Some(iterations) = bench_load_iterations {
+        let gpu_device = pollster::block_on(GpuDevice::new(gpu_options))
+            .ok_or("Failed to initialize the GPU")?;
+
+        bench_load(&config.map_path, &gpu_device, iterations)
+            .map_err(|e| format!("Failed to load map '{}': {}",
+                                 config.map_path.display(), e))?;
+        return Ok(());
+    }
+
+    let mut glfw = glfw::init(glfw::FAIL_ON_ERRORS)
+        .map_err(|e| format!("Failed to initialize GLFW: {:?}", e))?;
+    glfw.window_hint(glfw::WindowHint::ClientApi(glfw::ClientApiHint::NoApi));
+
+    let map_name = config.map_path.file_stem()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let window_title = format!("{} - {}", config.window_title, map_name);
+
+    let (mut window, events) =
+        glfw.create_window(config.window_width, config.window_height,
+                           &window_title,
+                           glfw::WindowMode::Windowed)
+            .ok_or("Failed to create the GLFW window")?;
+
+    if let Some(icon_path) = &config.window_icon {
+        match load_window_icon(icon_path) {
+            Ok(icon) => window.set_icon_from_pixels(vec![icon]),
+            Err(e) => log::warn!("Failed to load the window icon '{}': {}",
+                                 icon_path.display(), e),
+        }
+    }
+
+    window.set_key_polling(true);
+    window.set_char_polling(true);
+    window.set_cursor_pos_polling(true);
+    window.set_cursor_mode(glfw::CursorMode::Disabled);
+
+    let (window_width, window_height) = window.get_framebuffer_size();
+
+    let aspect_ratio = window_width as f32 / window_height as f32;
+    let projection_matrix = Mat4::perspective_lh(config.fov.to_radians(), aspect_ratio, NEAR_PLANE, FAR_PLANE);
     let view_matrix = Mat4::IDENTITY;
     let model_matrix = Mat4::from_scale(Vec3::new(1.0, 1.0, 1.0));
 
     let mut uniform_buffer = UniformBuffer::new(projection_matrix, view_matrix, model_matrix);
 
-    let (gpu_device, surface) = pollster::block_on(GpuDevice::new_for_window(&window)).unwrap();
+    let (gpu_device, mut surface) =
+        pollster::block_on(GpuDevice::new_for_window(&window, gpu_options))
+            .ok_or("Failed to initialize the GPU")?;
 
-    let mut map = load_map("/home/nanoteck137/doom1.mup", &gpu_device)
-        .expect("Failed to load map");
+    let mut meshes = MeshRegistry::new();
 
-    let shader = gpu_device.device.create_shader_module(&wgpu::include_wgsl!("shader.wgsl"));
+    let mut map = load_map(&config.map_path, &gpu_device, &mut meshes,
+                           MapLoadOptions::default())
+        .map_err(|e| format!("Failed to load map '{}': {}",
+                             config.map_path.display(), e))?;
 
-    let uniform_buffer_handle = gpu_device.device.create_buffer_init(
-        &wgpu::util::BufferInitDescriptor {
-            label: Some("Uniform Buffer"),
-            contents: bytemuck::cast_slice(&[uniform_buffer]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        }
-    );
+    let box_half_extents = Vec3::from(SPAWN_BOX_HALF_EXTENTS) * UNIT_TO_METERS;
+    let box_mesh = meshes.register(Mesh::cuboid(&gpu_device, box_half_extents,
+                                                SPAWN_BOX_COLOR));
 
-    let uniform_buffer_bind_group_layout = gpu_device.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-        entries: &[
-            wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::VERTEX,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            }
-        ],
-        label: Some("uniform_buffer_bind_group_layout"),
-    });
+    let mut shader = match load_scene_shader(&gpu_device) {
+        Ok(shader) => shader,
+        Err(e) => {
+            log::error!("Failed to compile '{}', using the embedded \
+                         shader:\n{}", SHADER_PATH, e);
+            gpu_device.create_wgsl_shader("shader.wgsl", EMBEDDED_SHADER)?
+        }
+    };
 
-    let uniform_buffer_bind_group = gpu_device.device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout: &uniform_buffer_bind_group_layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: uniform_buffer_handle.as_entire_binding(),
-            }
-        ],
-        label: Some("uniform_buffer_bind_group"),
-    });
+    let scene_uniform =
+        render::GpuUniform::new(&gpu_device, &uniform_buffer,
+                                wgpu::ShaderStages::VERTEX |
+                                wgpu::ShaderStages::FRAGMENT,
+                                "scene_uniform_buffer");
+
+    // NOTE(patrik): One model matrix per sector followed by one for each
+    // batch of renderable entities, one for the particles and then one for
+    // each sprite, selected with a dynamic offset when drawing
+    let mut model_uniforms =
+        DynamicUniformBuffer::<ModelUniform>::new(&gpu_device,
+                                                  map.sectors.len() + MAX_RENDERABLES + 1 + MAX_SPRITES,
+                                                  wgpu::ShaderStages::VERTEX |
+                                                  wgpu::ShaderStages::FRAGMENT);
+
+    let mut shadow_map = render::ShadowMap::new(&gpu_device, shader.handle(),
+                                            scene_uniform.bind_group_layout(),
+                                            model_uniforms.bind_group_layout());
+
+    let mut sprite_renderer =
+        SpriteRenderer::new(&gpu_device, &surface,
+                            scene_uniform.bind_group_layout(),
+                            model_uniforms.bind_group_layout())?;
 
     let pipeline_layout = render::PipelineLayout::builder()
-        .bind_group_layout(&uniform_buffer_bind_group_layout)
+        .bind_group_layout(scene_uniform.bind_group_layout())
+        .bind_group_layout(model_uniforms.bind_group_layout())
+        .bind_group_layout(shadow_map.bind_group_layout())
         .build(&gpu_device);
 
-    let pipeline = render::RenderPipeline::builder()
-        .fragment_shader(&shader)
-        .vertex_shader(&shader)
-        .depth_stencil(true)
-        .cull_mode(wgpu::Face::Back)
-        .build(&gpu_device, &surface, &pipeline_layout);
+    let mut pipeline_cache = render::PipelineCache::new();
 
+    let mut cull_mode = Some(wgpu::Face::Back);
+    let mut noclip = false;
+    let mut pipelines = ScenePipelines::new(&mut pipeline_cache,
+                                            &gpu_device, &surface,
+                                            &shader, &pipeline_layout,
+                                            cull_mode, config.depth_prepass)?;
 
-    let depth_texture = Texture::create_depth_texture(&gpu_device, surface.config().width, surface.config().height);
+
+    let mut depth_texture = Texture::create_depth_texture(&gpu_device, surface.config().width, surface.config().height);
+
+    let mut minimap = Minimap::new(&gpu_device, &surface);
+
+    let mut gpu_timer = if config.gpu_timing {
+        render::GpuTimer::new(&gpu_device)
+    } else {
+        None
+    };
+
+    // NOTE(patrik): Created after the main window so the adapter is picked
+    // for the main window
+    let mut debug_view = if open_debug_view {
+        let title = format!("{} - Debug View", window_title);
+        match DebugView::new(&mut glfw, &gpu_device, &title,
+                             surface.config().format)
+        {
+            Ok(debug_view) => Some(debug_view),
+            Err(e) => {
+                log::error!("{}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut debug_lines = render::DebugLines::new(&gpu_device);
+    let mut instance_buffer =
+        render::InstanceBuffer::new(&gpu_device, MAX_RENDERABLES as u32);
+
+    let mut post_process = if GAMMA_CORRECTION {
+        let gamma_shader = gpu_device.device.create_shader_module(&wgpu::include_wgsl!("gamma.wgsl"));
+        let post_process = PostProcess::new(&gpu_device, &surface, &gamma_shader);
+
+        let scene_target = RenderTarget::new(&gpu_device,
+                                             surface.config().width,
+                                             surface.config().height,
+                                             surface.config().format);
+        let bind_group = post_process.create_bind_group(&gpu_device,
+                                                        &scene_target.color);
+
+        Some((post_process, scene_target, bind_group))
+    } else {
+        None
+    };
 
     let mut rigid_body_set = RigidBodySet::new();
     let mut collider_set = ColliderSet::new();
 
+    // NOTE(patrik): The door is a test entity as well, the maps don't mark
+    // which sectors move yet
+    let door_sector = if config.test_entities {
+        Some(DOOR_SECTOR)
+    } else {
+        None
+    };
+    let mut door_body = None;
+
     //let sector = &mut map.sectors[38]; {
-    for sector in map.sectors.iter_mut() {
-        collider_set.insert(sector.floor_collider.take().unwrap());
-        collider_set.insert(sector.wall_collider.take().unwrap());
+    for (index, sector) in map.sectors.iter_mut().enumerate() {
+        let floor_collider = sector.floor_collider.take().unwrap();
+        let wall_collider = sector.wall_collider.take().unwrap();
+        let ceiling_collider = sector.ceiling_collider.take();
+
+        if Some(index) == door_sector {
+            let body = RigidBodyBuilder::new_kinematic_position_based()
+                .build();
+            let body = rigid_body_set.insert(body);
+
+            collider_set.insert_with_parent(floor_collider, body,
+                                            &mut rigid_body_set);
+            collider_set.insert_with_parent(wall_collider, body,
+                                            &mut rigid_body_set);
+
+            if let Some(ceiling_collider) = ceiling_collider {
+                collider_set.insert_with_parent(ceiling_collider, body,
+                                                &mut rigid_body_set);
+            }
+
+            door_body = Some(body);
+        } else {
+            collider_set.insert(floor_collider);
+            collider_set.insert(wall_collider);
+
+            if let Some(ceiling_collider) = ceiling_collider {
+                collider_set.insert(ceiling_collider);
+            }
+        }
     }
 
-    let mut player_rigidbody = RigidBodyBuilder::new_dynamic()
-        .translation(vector![1077.0 / UNIT_TO_METERS, 20.0 / UNIT_TO_METERS, -3600.0 / UNIT_TO_METERS])
+    let spawn = map.spawn;
+    log::info!("Spawning player at {:?}", spawn);
+
+    let spawn_position = spawn.position / UNIT_TO_METERS;
+    // NOTE(patrik): The body can't rotate so bumping into walls doesn't
+    // tip the player over and spin the camera
+    let player_rigidbody = RigidBodyBuilder::new_dynamic()
+        .translation(vector![spawn_position.x, spawn_position.y, spawn_position.z])
+        .lock_rotations()
+        .linear_damping(config.linear_damping)
         .build();
-    player_rigidbody.lock_rotations(true, true);
     let player_rigidbody = rigid_body_set.insert(player_rigidbody);
 
-    let player_collider = ColliderBuilder::cuboid(1.0, 4.0, 1.0)
+    let player_collider = config.player_collider.builder()
         .build();
     let player_collider =
         collider_set.insert_with_parent(player_collider,
@@ -424,188 +2319,1284 @@ fn main() {
 
     let mut world = World::default();
 
-    world.insert_resource(GameState::new());
+    let mut game_state = GameState::new(config.sensitivity, config.invert_y);
+    game_state.yaw = spawn.yaw;
+    game_state.mouse_deadzone = config.mouse_deadzone;
+    game_state.mouse_acceleration = config.mouse_acceleration;
+
+    world.insert_resource(game_state);
     world.insert_resource(DeltaTime(0.0));
+    world.insert_resource(Paused(false));
+    world.insert_resource(PhysicsInterpolation { steps: 0, alpha: 0.0 });
+    world.insert_resource(CurrentSector(None));
+    world.insert_resource(FieldOfView(config.fov));
+    world.insert_resource(SpeedFov {
+        enabled: config.speed_fov,
+        max: config.speed_fov_max,
+        rate: config.speed_fov_rate,
+
+        current: config.fov,
+    });
+    world.insert_resource(DisplaySettings {
+        gamma: config.gamma,
+        exposure: config.exposure,
+    });
+    world.insert_resource(RenderStats::default());
+    world.insert_resource(VisibleSectors::default());
+    world.insert_resource(ClearColor(render::srgb_color(0.1, 0.2, 0.3, 1.0)));
+    let lowest_floor = map.sectors.iter()
+        .map(|sector| sector.floor_aabb.min.y)
+        .fold(f32::INFINITY, f32::min);
+    world.insert_resource(KillPlane(lowest_floor - config.kill_plane_depth));
     world.insert_resource(map);
+    world.insert_resource(meshes);
+    world.insert_resource(Particles::new(&gpu_device));
     world.insert_resource(rigid_body_set);
 
+    // NOTE(patrik): The stream needs to be kept alive for the audio to play
+    let (mut audio, _audio_stream) = Audio::new();
+
+    // TODO(patrik): Place the ambient sounds from the map
+    if config.test_entities {
+        if let Some(sector) = map_sector_center(&world, DOOR_SECTOR) {
+            audio.play_ambient_at(AMBIENT_SOUND, sector);
+        }
+    }
+
+    world.insert_resource(audio);
+
     let player_id = world.spawn()
-        .insert(Position(Vec3::new(1077.0, 460.0, -3600.0)))
+        .insert(Position(spawn.position))
+        .insert(PreviousPosition(spawn.position))
         .insert(Camera {
             direction: Vec3::new(0.0, 0.0, 1.0),
             up: Vec3::new(0.0, 1.0, 0.0),
+            eye_height: 20.0,
+            roll: 0.0,
         })
         .insert(Player {
             speed: 100.0,
+            jump_speed: jump_speed_for_height(config.jump_height),
             collider_handle: player_collider,
             body_handle: player_rigidbody,
         })
+        .insert(MovementState::default())
+        .insert(LastGrounded(spawn.position))
         .id();
 
-    let mut schedule = Schedule::default();
+    if config.view_bob {
+        world.entity_mut(player_id).insert(ViewBob::default());
+    }
+
+    // TODO(patrik): Read the lights from the map when the exporter
+    // supports them
+    let test_center = if config.test_entities {
+        map_sector_center(&world, DOOR_SECTOR)
+    } else {
+        None
+    };
+
+    if let Some(center) = test_center {
+        world.spawn()
+            .insert(Position(center + Vec3::new(0.0, 48.0, 0.0)))
+            .insert(LightSource {
+                color: Vec3::new(1.0, 0.7, 0.4),
+                radius: 256.0,
+            });
+    }
+
+    // TODO(patrik): Place the sprites and emitters from the map
+    if let Some(center) = test_center {
+        let texture = sprite_renderer.load_texture(&gpu_device,
+                                                   Path::new(TEST_SPRITE_TEXTURE));
+
+        world.spawn()
+            .insert(Position(center + Vec3::new(48.0, 0.0, 0.0)))
+            .insert(Sprite {
+                texture,
+                size: Vec2::new(24.0, 32.0),
+                material: Material::CUTOUT,
+            });
+    }
+
+    if let Some(center) = test_center {
+        world.spawn()
+            .insert(Position(center + Vec3::new(0.0, 40.0, 0.0)))
+            .insert(ParticleEmitter::new(40.0, 1.5,
+                                         Vec3::new(0.0, 24.0, 0.0),
+                                         [1.0, 0.6, 0.2, 1.0])
+                    .spread(16.0)
+                    .acceleration(Vec3::new(0.0, -32.0, 0.0))
+                    .size(1.5));
+    }
+
+    if let Some(body_handle) = door_body {
+        world.spawn()
+            .insert(SectorAnimation {
+                sector: DOOR_SECTOR,
+                body_handle,
+
+                low: 0.0,
+                high: 72.0,
+                speed: 64.0,
+                trigger_distance: 128.0,
+
+                offset: 0.0,
+            });
+    }
+
+    let mut renderables =
+        world.query::<(&Position, Option<&PreviousPosition>, &Renderable)>();
+    let mut sprites = world.query::<(&Position, &Sprite)>();
+
+    let mut schedule = Schedule::default();
+
+    let stage = SystemStage::single_threaded()
+        .with_system(update_player_physics)
+        .with_system(update_physics_bodies)
+        .with_system(update_movement_state)
+        .with_system(update_camera)
+        .with_system(update_view_bob)
+        .with_system(update_speed_fov)
+        .with_system(update_sector_animations)
+        .with_system(teleport_player)
+        .with_system(update_last_grounded)
+        .with_system(reset_player)
+        .with_system(recover_fallen_player)
+        .with_system(update_current_sector)
+        .with_system(log_player_position)
+        .with_system(update_audio_listener)
+        .with_system(update_particles);
+    schedule.add_stage("update", stage);
+
+    let mut physics = PhysicsContext::new(PHYSICS_TIMESTEP);
+    {
+        // NOTE(patrik): Make the map colliders queryable before the first
+        // physics step
+        let rigid_body_set = world.get_resource::<RigidBodySet>().unwrap();
+        physics.update_query_pipeline(rigid_body_set, &collider_set);
+    }
+    let physics_hooks = Test {};
+    let event_handler = Test {};
+
+    let time = Instant::now();
+    let mut past = 0.0;
+
+    let mut console_was_open = false;
+    let mut last_stats_log = 0.0;
+    let mut frames_since_stats_log = 0;
+
+    // NOTE(patrik): The replay feeds back the delta time of every frame, so
+    // the physics takes the same fixed steps as when it was recorded
+    let mut replay = match &replay_path {
+        Some(path) => {
+            let replay = Replay::load(path)
+                .map_err(|e| format!("Failed to load replay '{}': {}",
+                                     path.display(), e))?;
+            log::info!("Replay: Playing {} frames from '{}'",
+                       replay.len(), path.display());
+            Some(replay)
+        }
+
+        None => None,
+    };
+
+    let mut recorder = match &record_path {
+        Some(path) => {
+            let recorder = ReplayRecorder::create(path)
+                .map_err(|e| format!("Failed to create replay '{}': {}",
+                                     path.display(), e))?;
+            log::info!("Replay: Recording to '{}'", path.display());
+            Some(recorder)
+        }
+
+        None => None,
+    };
+
+    // NOTE(patrik): Vsync already paces the frames
+    let mut frame_limiter = match config.max_fps {
+        Some(max_fps) if config.vsync => {
+            log::info!("Ignoring max_fps {} since vsync is enabled", max_fps);
+            None
+        }
+
+        Some(max_fps) => FrameLimiter::new(max_fps),
+        None => None,
+    };
+
+    let mut close_game = false;
+    while !close_game {
+        if let Some(frame_limiter) = &mut frame_limiter {
+            frame_limiter.wait();
+        }
+
+        let _frame_span = tracing::debug_span!("frame").entered();
+
+        let now = time.elapsed().as_secs_f32();
+        // NOTE(patrik): Clamp the delta time so a long hitch (loading,
+        // breakpoints) doesn't make the physics run a lot of steps to catch
+        // up
+        let mut dt = (now - past).min(MAX_DELTA_TIME);
+        past = now;
+
+        {
+            let mut game_state = world.get_resource_mut::<GameState>().unwrap();
+            glfw.poll_events();
+            for (_, event) in glfw::flush_messages(&events) {
+                handle_window_event(&mut game_state, event);
+            }
+
+            if window.should_close() {
+                game_state.close = true;
+            }
+        }
+
+        let debug_view_open = debug_view.as_mut()
+            .map(|debug_view| debug_view.handle_events());
+        if debug_view_open == Some(false) {
+            log::info!("Debug view closed");
+            debug_view = None;
+        }
+
+        match replay.as_mut().map(|replay| replay.next_frame()) {
+            Some(Some(input)) => {
+                dt = input.dt;
+
+                let mut game_state = world.get_resource_mut::<GameState>()
+                    .unwrap();
+                game_state.apply_frame_input(&input);
+            }
+
+            Some(None) => {
+                log::info!("Replay: Finished, back to live input");
+                replay = None;
+            }
+
+            None => {}
+        }
+
+        if let Some(active_recorder) = &mut recorder {
+            let game_state = world.get_resource::<GameState>().unwrap();
+            let input = game_state.frame_input(dt);
+
+            if let Err(e) = active_recorder.record(&input) {
+                log::error!("Replay: Failed to record, stopping: {}", e);
+                recorder = None;
+            }
+        }
+
+        {
+            let mut game_state = world.get_resource_mut::<GameState>().unwrap();
+            let console_open = game_state.console.open;
+            if console_open {
+                window.set_title(&format!("> {}_", game_state.text_input.as_str()));
+            } else if console_was_open {
+                window.set_title(&window_title);
+            }
+            console_was_open = console_open;
+
+            let lines = game_state.console.take_submitted();
+            drop(game_state);
+
+            for line in lines {
+                match console::parse_command(&line) {
+                    Ok(command) => {
+                        execute_command(&mut world, &mut physics, command);
+                    }
+
+                    Err(e) => log::warn!("Console: {}", e),
+                }
+            }
+        }
+
+        let paused = {
+            let toggle_pause = {
+                let mut game_state = world.get_resource_mut::<GameState>()
+                    .unwrap();
+                std::mem::take(&mut game_state.toggle_pause)
+            };
+
+            let mut paused = world.get_resource_mut::<Paused>().unwrap();
+            if toggle_pause {
+                paused.0 = !paused.0;
+                log::info!("Paused: {}", paused.0);
+            }
+
+            paused.0
+        };
+
+        {
+            // NOTE(patrik): Nothing should integrate while paused
+            let mut dtr = world.get_resource_mut::<DeltaTime>().unwrap();
+            dtr.0 = if paused { 0.0 } else { dt };
+        }
+
+        {
+            let game_state = world.get_resource_mut::<GameState>().unwrap();
+            if game_state.close {
+                close_game = true;
+            }
+        }
+
+        {
+            let new_noclip = world.get_resource::<GameState>().unwrap().noclip;
+            if new_noclip != noclip {
+                noclip = new_noclip;
+                log::info!("No-clip: {}", noclip);
+
+                let player_component =
+                    *world.entity(player_id).get::<Player>().unwrap();
+                let mut rigid_body_set =
+                    world.get_resource_mut::<RigidBodySet>().unwrap();
+                set_noclip(&mut rigid_body_set, &mut collider_set,
+                           &player_component, noclip);
+            }
+        }
+
+        let steps = if !paused {
+            let mut rigid_body_set = world.get_resource_mut::<RigidBodySet>()
+                .unwrap();
+
+            physics.update(dt,
+                           &mut rigid_body_set,
+                           &mut collider_set,
+                           &physics_hooks,
+                           &event_handler)
+        } else {
+            0
+        };
+
+        if steps > 0 {
+            let player_component =
+                *world.entity(player_id).get::<Player>().unwrap();
+            let rigid_body_set = world.get_resource::<RigidBodySet>()
+                .unwrap();
+            let grounded = !noclip &&
+                player_grounded(&physics, rigid_body_set, &collider_set,
+                                &player_component);
+
+            world.entity_mut(player_id).get_mut::<MovementState>().unwrap()
+                .grounded = grounded;
+        }
+
+        if steps > 0 && !noclip {
+            // NOTE(patrik): Copied out so the rigid body set can be
+            // borrowed mutably
+            let player = world.entity(player_id);
+            let player_component = *player.get::<Player>().unwrap();
+            let movement = *player.get::<MovementState>().unwrap();
+
+            let mut rigid_body_set = world.get_resource_mut::<RigidBodySet>()
+                .unwrap();
+            step_up(&physics, &mut rigid_body_set, &collider_set,
+                    &player_component, &movement,
+                    config.max_step_height / UNIT_TO_METERS);
+        }
+
+        let spawn = std::mem::take(
+            &mut world.get_resource_mut::<GameState>().unwrap().spawn_box);
+        if spawn {
+            spawn_box(&mut world, &mut collider_set, player_id, box_mesh);
+        }
+
+        let reload_shader = std::mem::take(
+            &mut world.get_resource_mut::<GameState>().unwrap().reload_shader);
+        let new_shader = if reload_shader {
+            log::info!("Reloading '{}'", SHADER_PATH);
+
+            match load_scene_shader(&gpu_device) {
+                Ok(new_shader) => Some(new_shader),
+
+                // NOTE(patrik): Keep running with the old shader so the
+                // error can be fixed and reloaded again
+                Err(e) => {
+                    log::error!("Failed to compile '{}':\n{}", SHADER_PATH, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        if let Some(new_shader) = new_shader {
+            let new_pipelines = ScenePipelines::new(&mut pipeline_cache,
+                                                    &gpu_device, &surface,
+                                                    &new_shader,
+                                                    &pipeline_layout,
+                                                    cull_mode,
+                                                    config.depth_prepass);
+
+            match new_pipelines {
+                Ok(new_pipelines) => {
+                    pipeline_cache.evict_shader(&shader);
+                    shader = new_shader;
+                    pipelines = new_pipelines;
+
+                    shadow_map.reload_shader(&gpu_device, shader.handle(),
+                                             scene_uniform.bind_group_layout(),
+                                             model_uniforms.bind_group_layout());
+                }
+
+                Err(e) => log::error!("Failed to create the pipelines for \
+                                       '{}':\n{}", SHADER_PATH, e),
+            }
+        }
+
+        {
+            let new_cull_mode = world.get_resource::<GameState>().unwrap()
+                .cull_mode;
+            if new_cull_mode != cull_mode {
+                cull_mode = new_cull_mode;
+                log::info!("Cull mode: {:?}", cull_mode);
+
+                match ScenePipelines::new(&mut pipeline_cache,
+                                          &gpu_device, &surface,
+                                          &shader, &pipeline_layout,
+                                          cull_mode, config.depth_prepass)
+                {
+                    Ok(new_pipelines) => pipelines = new_pipelines,
+                    Err(e) => log::error!("Failed to create the pipelines: {}",
+                                          e),
+                }
+            }
+        }
+
+        {
+            let mut interpolation =
+                world.get_resource_mut::<PhysicsInterpolation>().unwrap();
+            interpolation.steps = steps;
+            interpolation.alpha = physics.alpha();
+        }
+
+        schedule.run(&mut world);
+
+        let alpha = world.get_resource::<PhysicsInterpolation>().unwrap()
+            .alpha;
+
+        let player = world.entity(player_id);
+        let view_matrix = generate_view_matrix(player, alpha);
+
+        let player = world.entity(player_id);
+        let player_pos = interpolated_position(&player, alpha);
+
+        let (camera_right, camera_up, camera_forward) = {
+            let camera = player.get::<Camera>().unwrap();
+            let right = camera.up.cross(camera.direction).normalize();
+            (right, camera.direction.cross(right), camera.direction)
+        };
+
+        let _render_span = tracing::debug_span!("render").entered();
+
+        let (framebuffer_width, framebuffer_height) =
+            window.get_framebuffer_size();
+        if framebuffer_width <= 0 || framebuffer_height <= 0 {
+            // NOTE(patrik): The window is minimized so there is nothing to
+            // render to
+            continue;
+        }
+
+        let framebuffer_width = framebuffer_width as u32;
+        let framebuffer_height = framebuffer_height as u32;
+        if framebuffer_width != surface.config().width ||
+           framebuffer_height != surface.config().height
+        {
+            surface.resize(&gpu_device, framebuffer_width, framebuffer_height);
+            depth_texture.recreate_depth(&gpu_device,
+                                         framebuffer_width,
+                                         framebuffer_height);
+
+            if let Some((post_process, scene_target, bind_group)) = &mut post_process {
+                *scene_target = RenderTarget::new(&gpu_device,
+                                                  framebuffer_width,
+                                                  framebuffer_height,
+                                                  surface.config().format);
+                *bind_group = post_process.create_bind_group(&gpu_device,
+                                                             &scene_target.color);
+            }
+        }
+
+        let output = match surface.get_render_target() {
+            Ok(output) => output,
+
+            Err(wgpu::SurfaceError::Lost) |
+            Err(wgpu::SurfaceError::Outdated) => {
+                log::warn!("Surface lost or outdated, reconfiguring");
+                surface.reconfigure(&gpu_device);
+                continue;
+            }
+
+            Err(wgpu::SurfaceError::Timeout) => {
+                log::warn!("Surface timed out, skipping frame");
+                continue;
+            }
+
+            Err(wgpu::SurfaceError::OutOfMemory) => {
+                log::error!("Surface out of memory, shutting down");
+                break;
+            }
+        };
+        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        uniform_buffer.update_view(view_matrix);
+
+        {
+            let fov = world.get_resource::<SpeedFov>().unwrap().current;
+            let aspect_ratio =
+                framebuffer_width as f32 / framebuffer_height as f32;
+            uniform_buffer.update_projection(
+                Mat4::perspective_lh(fov.to_radians(), aspect_ratio,
+                                     NEAR_PLANE, FAR_PLANE));
+        }
+
+        {
+            let map = world.get_resource::<Map>().unwrap();
+            let current_sector = world.get_resource::<CurrentSector>().unwrap()
+                .0;
+
+            let environment = match current_sector {
+                Some(current) => sector_environment(map, current, player_pos),
+                None => SectorEnvironment::default(),
+            };
+
+            uniform_buffer.update_environment(environment.fog_color,
+                                              environment.fog_density,
+                                              environment.light_level);
+        }
+
+        {
+            let display = world.get_resource::<DisplaySettings>().unwrap();
+            uniform_buffer.update_display(display.gamma, display.exposure);
+
+            let game_state = world.get_resource::<GameState>().unwrap();
+            uniform_buffer.update_debug_mode(game_state.debug_mode);
+        }
+
+        let point_lights = nearest_point_lights(&mut world, player_pos);
+        uniform_buffer.update_point_lights(&point_lights);
 
-    let stage = SystemStage::single_threaded()
-        .with_system(update_player_physics)
-        .with_system(update_camera);
-    schedule.add_stage("update", stage);
+        {
+            let sun_direction = Vec3::from(SUN_DIRECTION);
+            let light_space_matrix =
+                render::shadow::light_space_matrix(sun_direction, player_pos,
+                                                   SHADOW_EXTENT);
+            uniform_buffer.update_sun(sun_direction, SUN_INTENSITY,
+                                      light_space_matrix);
+            uniform_buffer.update_shadows(config.shadows);
+        }
 
-    let gravity = vector![0.0, -9.81, 0.0];
-    let integration_parameters = IntegrationParameters::default();
-    let mut physics_pipeline = PhysicsPipeline::new();
-    let mut island_manager = IslandManager::new();
-    let mut broad_phase = BroadPhase::new();
-    let mut narrow_phase = NarrowPhase::new();
-    let mut joint_set = JointSet::new();
-    let mut ccd_solver = CCDSolver::new();
-    let physics_hooks = Test {};
-    let event_handler = Test {};
+        scene_uniform.update(&gpu_device.queue, &uniform_buffer);
 
-    let time = Instant::now();
-    let mut past = 0.0;
+        let gpu_time_ms = gpu_timer.as_mut()
+            .and_then(|gpu_timer| gpu_timer.poll(&gpu_device));
 
-    let mut close_game = false;
-    while !close_game {
-        let now = time.elapsed().as_secs_f32();
-        let dt = now - past;
-        past = now;
+        let mut encoder = gpu_device.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Render Encoder"),
+        });
 
-        {
-            let mut dtr = world.get_resource_mut::<DeltaTime>().unwrap();
-            dtr.0 = dt;
+        if let Some(gpu_timer) = &gpu_timer {
+            gpu_timer.begin(&mut encoder);
         }
 
-        {
-            let mut game_state = world.get_resource_mut::<GameState>().unwrap();
-            glfw.poll_events();
-            for (_, event) in glfw::flush_messages(&events) {
-                handle_window_event(&mut game_state, event);
+        let renderable_offset = {
+            let map = world.get_resource::<Map>().unwrap();
+            for (index, sector) in map.sectors.iter().enumerate() {
+                model_uniforms.set(index,
+                                   &ModelUniform::with_material(sector.model_matrix,
+                                                                &sector.material));
             }
+
+            map.sectors.len()
+        };
+
+        // NOTE(patrik): The renderables sharing a mesh and a material are
+        // drawn with one instanced draw, the model matrices go in the
+        // instance buffer and the model uniform only carries the material
+        let (batches, model_matrices) = batch_instances(
+            renderables.iter(&world)
+                .take(MAX_RENDERABLES)
+                .map(|(position, previous, renderable)| {
+                    let position = match previous {
+                        Some(previous) => previous.0.lerp(position.0, alpha),
+                        None => position.0,
+                    };
+
+                    ((renderable.mesh, renderable.material),
+                     Mat4::from_translation(position))
+                }));
+        instance_buffer.update(&gpu_device, &model_matrices);
+
+        for (index, ((_, material), _)) in batches.iter().enumerate() {
+            model_uniforms.set(renderable_offset + index,
+                               &ModelUniform::with_material(Mat4::IDENTITY,
+                                                            material));
         }
 
+        let particle_offset = renderable_offset + MAX_RENDERABLES;
+        model_uniforms.set(particle_offset,
+                           &ModelUniform::with_material(Mat4::IDENTITY,
+                                                        &Material::UNLIT));
+
+        let sprite_offset = particle_offset + 1;
+        for (index, (position, sprite)) in sprites.iter(&world)
+            .take(MAX_SPRITES)
+            .enumerate()
         {
-            let game_state = world.get_resource_mut::<GameState>().unwrap();
-            if game_state.close {
-                close_game = true;
-            }
+            let model_matrix = sprite::billboard_matrix(position.0, sprite.size,
+                                                        camera_right,
+                                                        camera_up,
+                                                        camera_forward);
+            model_uniforms.set(sprite_offset + index,
+                               &ModelUniform::with_material(model_matrix,
+                                                            &sprite.material));
         }
 
-        let mut rigid_body_set = world.get_resource_mut::<RigidBodySet>()
-            .unwrap();
-
-        physics_pipeline.step(
-            &gravity,
-            &integration_parameters,
-            &mut island_manager,
-            &mut broad_phase,
-            &mut narrow_phase,
-            &mut rigid_body_set,
-            &mut collider_set,
-            &mut joint_set,
-            &mut ccd_solver,
-            &physics_hooks,
-            &event_handler,
-        );
+        model_uniforms.upload(&gpu_device);
 
-        schedule.run(&mut world);
+        let show_colliders = world.get_resource::<GameState>().unwrap()
+            .show_colliders;
+        debug_lines.clear();
+        if show_colliders {
+            physics::collider_lines(&collider_set, UNIT_TO_METERS,
+                                    &mut debug_lines);
+            debug_lines.upload(&gpu_device);
+        }
 
-        let player = world.entity(player_id);
-        let view_matrix = generate_view_matrix(player);
+        world.get_resource_mut::<Particles>().unwrap()
+            .upload(&gpu_device, camera_right, camera_up);
 
-        let player = world.entity(player_id);
-        let player_pos = player.get::<Position>().unwrap().0;
+        // NOTE(patrik): The pass still runs with shadows disabled so the
+        // shadow map is cleared, the shader skips the lookup then
+        {
+            let _shadow_span = tracing::debug_span!("shadow_pass").entered();
+
+            let mut render_pass = shadow_map.begin_pass(&mut encoder);
+            render_pass.set_bind_group(0, scene_uniform.bind_group(), &[]);
+
+            if config.shadows {
+                let map = world.get_resource::<Map>().unwrap();
+                let meshes = world.get_resource::<MeshRegistry>().unwrap();
+                let sector_filter =
+                    world.get_resource::<VisibleSectors>().unwrap();
+
+                for (index, sector) in map.sectors.iter().enumerate() {
+                    if !sector_filter.contains(index) {
+                        continue;
+                    }
+
+                    render_pass.set_bind_group(1, model_uniforms.bind_group(),
+                                               &[model_uniforms.offset(index)]);
+
+                    for handle in [sector.floor_mesh, sector.ceiling_mesh, sector.wall_mesh] {
+                        let m = match meshes.get(handle) {
+                            Some(m) => m,
+                            None => continue,
+                        };
+                        m.draw(&mut render_pass);
+                    }
+                }
 
-        // TODO(patrik): Check error
-        let output = surface.get_render_target().unwrap();
-        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+                render_pass.set_pipeline(shadow_map.instanced_pipeline());
 
-        uniform_buffer.update_view(view_matrix);
-        gpu_device.queue.write_buffer(&uniform_buffer_handle,
-                                      0,
-                                      bytemuck::cast_slice(&[uniform_buffer]));
+                for (index, ((mesh, _), instances)) in batches.iter().enumerate() {
+                    let offset = model_uniforms.offset(renderable_offset + index);
+                    render_pass.set_bind_group(1, model_uniforms.bind_group(),
+                                               &[offset]);
 
-        let mut encoder = gpu_device.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("Render Encoder"),
-        });
+                    let m = match meshes.get(*mesh) {
+                        Some(m) => m,
+                        None => continue,
+                    };
+                    m.draw_instanced(&mut render_pass, &instance_buffer,
+                                     instances.clone());
+                }
+            }
+        }
 
         // window.get_render_target();
 
         // renderer.begin_render(&render_target);
         // renderer.end_render();
 
-        {
+        let (scene_view, scene_depth_view) = match &post_process {
+            Some((_, scene_target, _)) => {
+                (&scene_target.color.view, &scene_target.depth.view)
+            }
+
+            None => (&view, &depth_texture.view),
+        };
+
+        let clear_color = world.get_resource::<ClearColor>().unwrap().0;
+
+        let stats = {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                     label: Some("Render Pass"),
                     color_attachments: &[
                         wgpu::RenderPassColorAttachment {
-                            view: &view,
+                            view: scene_view,
                             resolve_target: None,
                             ops: wgpu::Operations {
-                                load: wgpu::LoadOp::Clear(
-                                    wgpu::Color {
-                                        r: 0.1,
-                                        g: 0.2,
-                                        b: 0.3,
-                                        a: 1.0,
-                                    }
-                                ),
+                                load: wgpu::LoadOp::Clear(clear_color),
                                 store: true,
                             }
                         }
                     ],
                     depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                        view: &depth_texture.view,
+                        view: scene_depth_view,
                         depth_ops: Some(wgpu::Operations {
                             load: wgpu::LoadOp::Clear(1.0),
                             store: true,
                         }),
-                        stencil_ops: None,
+                        stencil_ops: Some(wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(0),
+                            store: true,
+                        }),
                     }),
                 });
 
-            render_pass.set_bind_group(0, &uniform_buffer_bind_group, &[]);
+            render_pass.set_bind_group(0, scene_uniform.bind_group(), &[]);
+            render_pass.set_bind_group(2, shadow_map.bind_group(), &[]);
+
+            let mut stats = RenderStats {
+                gpu_time_ms,
+                ..Default::default()
+            };
 
             let map = world.get_resource::<Map>().unwrap();
-            // let sector = &map.sectors[38]; {
-            for sector in &map.sectors {
 
-                render_pass.set_pipeline(&pipeline.handle());
+            let portal_culling = world.get_resource::<GameState>().unwrap()
+                .portal_culling;
+            let current_sector = world.get_resource::<CurrentSector>().unwrap()
+                .0;
+            // NOTE(patrik): Draw everything when the camera is outside of
+            // the map
+            let visible = match current_sector {
+                Some(current) if portal_culling => {
+                    Some(visible_sectors(map, current))
+                }
+
+                _ => None,
+            };
+
+            let meshes = world.get_resource::<MeshRegistry>().unwrap();
+
+            stats.depth_prepass = pipelines.prepass.is_some();
+
+            let sector_filter = world.get_resource::<VisibleSectors>().unwrap();
+
+            for (pass_index, pipeline) in pipelines.passes().into_iter().enumerate() {
+                let first_pass = pass_index == 0;
+
+                for (index, sector) in map.sectors.iter().enumerate() {
+                    let portal_visible = match &visible {
+                        Some(visible) => visible[index],
+                        None => true,
+                    };
 
-                let m = &sector.floor_mesh;
-                render_pass.set_vertex_buffer(0, m.vertex_buffer.slice(..));
-                render_pass.set_index_buffer(m.index_buffer.slice(..),
-                                             wgpu::IndexFormat::Uint32);
+                    if !portal_visible || !sector_filter.contains(index) {
+                        if first_pass {
+                            stats.sectors_culled += 1;
+                        }
+                        continue;
+                    }
+
+                    if first_pass {
+                        stats.sectors_drawn += 1;
+                    }
+
+                    render_pass.set_pipeline(pipeline.handle());
+                    render_pass.set_stencil_reference(
+                        pipeline.stencil_reference());
+
+                    render_pass.set_bind_group(1, model_uniforms.bind_group(),
+                                               &[model_uniforms.offset(index)]);
+
+                    for handle in [sector.floor_mesh, sector.ceiling_mesh, sector.wall_mesh] {
+                        let m = meshes.get(handle).unwrap();
+                        m.draw(&mut render_pass);
+
+                        stats.draw_calls += 1;
+                        stats.triangles += m.triangle_count();
+                    }
+                }
+            }
+
+            // NOTE(patrik): Left out of the depth pre-pass, the instanced
+            // pipeline does the depth test and writes by itself
+            render_pass.set_pipeline(pipelines.instanced.handle());
+            render_pass.set_stencil_reference(
+                pipelines.instanced.stencil_reference());
+
+            for (index, ((mesh, _), instances)) in batches.iter().enumerate() {
+                let offset = model_uniforms.offset(renderable_offset + index);
+                render_pass.set_bind_group(1, model_uniforms.bind_group(),
+                                           &[offset]);
+
+                let m = match meshes.get(*mesh) {
+                    Some(m) => m,
+                    None => continue,
+                };
+                m.draw_instanced(&mut render_pass, &instance_buffer,
+                                 instances.clone());
+
+                stats.draw_calls += 1;
+                stats.triangles += m.triangle_count() * instances.len() as u32;
+            }
+
+            // NOTE(patrik): Uses the same identity model uniform as the
+            // particles
+            if !debug_lines.is_empty() {
+                render_pass.set_pipeline(pipelines.lines.handle());
+                render_pass.set_bind_group(1, model_uniforms.bind_group(),
+                                           &[model_uniforms.offset(particle_offset)]);
+
+                debug_lines.mesh().draw(&mut render_pass);
+                stats.draw_calls += 1;
+            }
+
+            stats.draw_calls += sprite_renderer.draw(
+                &mut render_pass, &model_uniforms, sprite_offset,
+                sprites.iter(&world)
+                    .take(MAX_SPRITES)
+                    .map(|(_, sprite)| sprite));
+
+            // NOTE(patrik): Drawn last without depth writes so the
+            // particles blend over the scene and each other
+            let particles = world.get_resource::<Particles>().unwrap();
+            if particles.len() > 0 {
+                render_pass.set_pipeline(pipelines.particles.handle());
+                // NOTE(patrik): The sprites replaced the shadow map bind
+                // group
+                render_pass.set_bind_group(2, shadow_map.bind_group(), &[]);
+                render_pass.set_bind_group(1, model_uniforms.bind_group(),
+                                           &[model_uniforms.offset(particle_offset)]);
+
+                let m = particles.mesh();
+                m.draw(&mut render_pass);
+
+                stats.draw_calls += 1;
+                stats.triangles += m.triangle_count();
+            }
+
+            stats
+        };
+
+        *world.get_resource_mut::<RenderStats>().unwrap() = stats;
 
-                render_pass.draw_indexed(0..m.index_count, 0, 0..1);
+        frames_since_stats_log += 1;
+        if now - last_stats_log >= RENDER_STATS_INTERVAL {
+            let fps = frames_since_stats_log as f32 / (now - last_stats_log);
+            last_stats_log = now;
+            frames_since_stats_log = 0;
 
-                let m = &sector.ceiling_mesh;
-                render_pass.set_vertex_buffer(0, m.vertex_buffer.slice(..));
-                render_pass.set_index_buffer(m.index_buffer.slice(..),
-                                             wgpu::IndexFormat::Uint32);
+            log::debug!("{:?}", world.get_resource::<RenderStats>().unwrap());
 
-                render_pass.draw_indexed(0..m.index_count, 0, 0..1);
+            let title = match gpu_time_ms {
+                Some(gpu_time_ms) => {
+                    format!("{} ({:.0} FPS, GPU {:.2} ms)",
+                            window_title, fps, gpu_time_ms)
+                }
 
-                let m = &sector.wall_mesh;
-                render_pass.set_vertex_buffer(0, m.vertex_buffer.slice(..));
-                render_pass.set_index_buffer(m.index_buffer.slice(..),
-                                             wgpu::IndexFormat::Uint32);
+                None => format!("{} ({:.0} FPS)", window_title, fps),
+            };
 
-                render_pass.draw_indexed(0..m.index_count, 0, 0..1);
+            // NOTE(patrik): The console uses the title for the input line
+            if !world.get_resource::<GameState>().unwrap().console.open {
+                window.set_title(&title);
             }
         }
 
+        if let Some((post_process, _, bind_group)) = &post_process {
+            post_process.run(&mut encoder, &view, bind_group);
+        }
+
+        let show_minimap = world.get_resource::<GameState>().unwrap()
+            .show_minimap;
+        if show_minimap {
+            let map = world.get_resource::<Map>().unwrap();
+            let meshes = world.get_resource::<MeshRegistry>().unwrap();
+            minimap.render(&gpu_device, &mut encoder, &pipelines.full,
+                           &model_uniforms, shadow_map.bind_group(),
+                           map, meshes, player_pos);
+            minimap.composite(&mut encoder, &view,
+                              surface.config().width,
+                              surface.config().height);
+        }
+
+        if let Some(gpu_timer) = &mut gpu_timer {
+            gpu_timer.end(&mut encoder);
+        }
+
         gpu_device.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
 
-        if window.should_close() {
+        if let Some(gpu_timer) = &mut gpu_timer {
+            gpu_timer.submitted();
+        }
+
+        {
             let mut game_state = world.get_resource_mut::<GameState>().unwrap();
-            game_state.close = true;
+            if game_state.screenshot {
+                game_state.screenshot = false;
+                save_screenshot(&gpu_device, &surface, &output.texture);
+            }
+        }
+
+        output.present();
+
+        if let Some(debug_view) = &mut debug_view {
+            let map = world.get_resource::<Map>().unwrap();
+            let meshes = world.get_resource::<MeshRegistry>().unwrap();
+            debug_view.render(&gpu_device, &pipelines.full, &pipelines.lines,
+                              &model_uniforms, particle_offset,
+                              shadow_map.bind_group(),
+                              map, meshes, player_pos);
+        }
+    }
+
+    if let Some(recorder) = recorder {
+        let frames = recorder.frames();
+        match recorder.finish() {
+            Ok(()) => log::info!("Replay: Recorded {} frames", frames),
+            Err(e) => log::error!("Replay: Failed to write the recording: {}",
+                                  e),
+        }
+    }
+
+    log::info!("Shutting down");
+
+    Ok(())
+}
+
+/// Read and compile the scene shader from `SHADER_PATH`, falls back to
+/// the shader embedded at build time if the file can't be read
+fn load_scene_shader(gpu_device: &GpuDevice)
+    -> Result<render::ShaderModule, wgpu::Error>
+{
+    let source = match std::fs::read_to_string(SHADER_PATH) {
+        Ok(source) => Cow::Owned(source),
+        Err(e) => {
+            log::warn!("Failed to read '{}', using the embedded shader: {}",
+                       SHADER_PATH, e);
+            Cow::Borrowed(EMBEDDED_SHADER)
+        }
+    };
+
+    gpu_device.create_wgsl_shader(SHADER_PATH, &source)
+}
+
+/// Load a PNG (or any format supported by `image`) as a window icon
+fn load_window_icon(path: &Path)
+    -> Result<glfw::PixelImage, image::ImageError>
+{
+    let image = image::open(path)?.to_rgba8();
+    let (width, height) = image.dimensions();
+
+    // NOTE(patrik): GLFW reads the pixels as RGBA bytes, native endian
+    // keeps the byte order in memory
+    let pixels = image.pixels()
+        .map(|pixel| u32::from_ne_bytes(pixel.0))
+        .collect();
+
+    Ok(glfw::PixelImage { width, height, pixels })
+}
+
+/// Pipelines used for drawing the map and the entities
+struct ScenePipelines {
+    /// Depth tested and shaded in a single pass, also used by the minimap
+    full: Arc<RenderPipeline>,
+
+    /// Same as `full` with the model matrices read from an instance
+    /// buffer, used for the renderable entities
+    instanced: Arc<RenderPipeline>,
+
+    /// Depth only pipeline and the pipeline shading the pixels left after
+    /// it (depth compare `Equal`, no depth writes). Only created when the
+    /// depth pre-pass is enabled
+    prepass: Option<(Arc<RenderPipeline>, Arc<RenderPipeline>)>,
+
+    /// Alpha blended without depth writes or culling, the particles are
+    /// quads facing the camera
+    particles: Arc<RenderPipeline>,
+
+    /// Line list for the debug lines, tested against the depth but drawn
+    /// on top of the surfaces they lie on
+    lines: Arc<RenderPipeline>,
+}
+
+impl ScenePipelines {
+    fn new(cache: &mut render::PipelineCache,
+           gpu_device: &GpuDevice,
+           surface: &render::WindowSurface,
+           shader: &render::ShaderModule,
+           layout: &render::PipelineLayout,
+           cull_mode: Option<wgpu::Face>,
+           depth_prepass: bool)
+        -> Result<Self, wgpu::Error>
+    {
+        let format = surface.config().format;
+
+        let builder = || {
+            let builder = RenderPipeline::builder()
+                .fragment_shader(shader)
+                .vertex_shader(shader)
+                .depth_stencil(true);
+
+            match cull_mode {
+                Some(face) => builder.cull_mode(face),
+                None => builder,
+            }
+        };
+
+        let full = builder()
+            .build_cached(cache, gpu_device, format, layout)?;
+
+        let instanced = builder()
+            .instanced(true)
+            .build_cached(cache, gpu_device, format, layout)?;
+
+        // NOTE(patrik): Both passes use the same vertex shader so the depth
+        // values written by the pre-pass are exactly the ones tested
+        // against in the shading pass
+        let prepass = if depth_prepass {
+            let depth_only = builder()
+                .color_writes(wgpu::ColorWrites::empty())
+                .build_cached(cache, gpu_device, format, layout)?;
+
+            let shade = builder()
+                .depth_write(false)
+                .depth_compare(wgpu::CompareFunction::Equal)
+                .build_cached(cache, gpu_device, format, layout)?;
+
+            Some((depth_only, shade))
+        } else {
+            None
+        };
+
+        let particles = RenderPipeline::builder()
+            .fragment_shader(shader)
+            .vertex_shader(shader)
+            .depth_stencil(true)
+            .depth_write(false)
+            .blend(wgpu::BlendState::ALPHA_BLENDING)
+            .build_cached(cache, gpu_device, format, layout)?;
+
+        let lines = RenderPipeline::builder()
+            .fragment_shader(shader)
+            .vertex_shader(shader)
+            .depth_stencil(true)
+            .depth_write(false)
+            .depth_compare(wgpu::CompareFunction::LessEqual)
+            .topology(wgpu::PrimitiveTopology::LineList)
+            .build_cached(cache, gpu_device, format, layout)?;
+
+        Ok(Self {
+            full,
+            instanced,
+            prepass,
+            particles,
+            lines,
+        })
+    }
+
+    /// Pipelines to draw the scene with, in order
+    fn passes(&self) -> Vec<&RenderPipeline> {
+        match &self.prepass {
+            Some((depth_only, shade)) => {
+                vec![depth_only.as_ref(), shade.as_ref()]
+            }
+            None => vec![self.full.as_ref()],
+        }
+    }
+}
+
+/// Time left before the frame deadline where the limiter stops sleeping
+/// and spins instead, sleeping can overshoot by about a millisecond
+const FRAME_LIMITER_SPIN_TIME: Duration = Duration::from_millis(2);
+
+/// Caps the frame rate by waiting at the start of each frame
+struct FrameLimiter {
+    frame_time: Duration,
+    next_frame: Instant,
+}
+
+impl FrameLimiter {
+    /// None if `max_fps` is 0
+    fn new(max_fps: u32) -> Option<Self> {
+        if max_fps == 0 {
+            return None;
+        }
+
+        Some(Self {
+            frame_time: Duration::from_secs_f64(1.0 / max_fps as f64),
+            next_frame: Instant::now(),
+        })
+    }
+
+    /// Block until it's time for the next frame
+    fn wait(&mut self) {
+        let remaining = self.next_frame
+            .saturating_duration_since(Instant::now());
+        if remaining > FRAME_LIMITER_SPIN_TIME {
+            std::thread::sleep(remaining - FRAME_LIMITER_SPIN_TIME);
+        }
+
+        while Instant::now() < self.next_frame {
+            std::hint::spin_loop();
+        }
+
+        // NOTE(patrik): The deadlines are spaced from the last one so the
+        // average stays on the cap, but a slow frame doesn't make the
+        // following frames rush to catch up
+        let now = Instant::now();
+        self.next_frame += self.frame_time;
+        if self.next_frame < now {
+            self.next_frame = now + self.frame_time;
+        }
+    }
+}
+
+/// Next cull mode when cycling with the C key
+fn next_cull_mode(cull_mode: Option<wgpu::Face>) -> Option<wgpu::Face> {
+    match cull_mode {
+        Some(wgpu::Face::Back) => Some(wgpu::Face::Front),
+        Some(wgpu::Face::Front) => None,
+        None => Some(wgpu::Face::Back),
+    }
+}
+
+fn save_screenshot(gpu_device: &GpuDevice,
+                   surface: &render::WindowSurface,
+                   texture: &wgpu::Texture)
+{
+    let config = surface.config();
+    let image = render::screenshot::capture(gpu_device, texture,
+                                            config.width, config.height,
+                                            config.format);
+
+    let image = match image {
+        Some(image) => image,
+        None => {
+            log::error!("Failed to capture screenshot");
+            return;
+        }
+    };
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let filename = format!("screenshot_{}.png", timestamp);
+
+    match image.save(&filename) {
+        Ok(_) => log::info!("Saved screenshot to '{}'", filename),
+        Err(e) => log::error!("Failed to save screenshot '{}': {}", filename, e),
+    }
+}
+
+fn digit_key(key: Key) -> Option<usize> {
+    match key {
+        Key::Num0 => Some(0),
+        Key::Num1 => Some(1),
+        Key::Num2 => Some(2),
+        Key::Num3 => Some(3),
+        Key::Num4 => Some(4),
+        Key::Num5 => Some(5),
+        Key::Num6 => Some(6),
+        Key::Num7 => Some(7),
+        Key::Num8 => Some(8),
+        Key::Num9 => Some(9),
+
+        _ => None,
+    }
+}
+
+fn execute_command(world: &mut World,
+                   physics: &mut PhysicsContext,
+                   command: Command)
+{
+    log::info!("Console: {:?}", command);
+
+    match command {
+        Command::Fov(fov) => {
+            let mut field_of_view =
+                world.get_resource_mut::<FieldOfView>().unwrap();
+            field_of_view.0 = fov.clamp(1.0, 179.0);
+        }
+
+        Command::Gravity(gravity) => {
+            physics.gravity = vector![0.0, -gravity, 0.0];
+        }
+
+        Command::Teleport(sector) => {
+            let mut game_state = world.get_resource_mut::<GameState>()
+                .unwrap();
+            game_state.teleport_sector = Some(sector);
+        }
+
+        Command::Sectors(range) => {
+            world.insert_resource(VisibleSectors(range));
+        }
+
+        Command::Gamma(gamma) => {
+            let mut display = world.get_resource_mut::<DisplaySettings>()
+                .unwrap();
+            display.gamma = gamma.clamp(0.5, 5.0);
+        }
+
+        Command::Exposure(exposure) => {
+            let mut display = world.get_resource_mut::<DisplaySettings>()
+                .unwrap();
+            display.exposure = exposure.max(0.0);
+        }
+    }
+}
+
+fn toggle_console(game_state: &mut GameState) {
+    game_state.console.toggle();
+
+    if game_state.console.open {
+        game_state.text_input.begin();
+    } else {
+        game_state.text_input.end();
+    }
+}
+
+/// Handle the events while the console is open, returns true if the event
+/// was consumed
+fn handle_console_event(game_state: &mut GameState,
+                        event: &glfw::WindowEvent)
+    -> bool
+{
+    match *event {
+        glfw::WindowEvent::Key(key, _, Action::Press, _) |
+        glfw::WindowEvent::Key(key, _, Action::Repeat, _) => {
+            match key {
+                Key::GraveAccent | Key::Escape => toggle_console(game_state),
+                Key::Enter => {
+                    let line = game_state.text_input.take();
+                    game_state.console.submit(line);
+                }
+                Key::Backspace => game_state.text_input.backspace(),
+
+                _ => {}
+            }
+
+            true
         }
+
+        // NOTE(patrik): The key that toggles the console also produces a
+        // char event
+        glfw::WindowEvent::Char('`') | glfw::WindowEvent::Char('~') => true,
+
+        _ => false,
     }
 }
 
 fn handle_window_event(game_state: &mut GameState,
                        event: glfw::WindowEvent)
 {
+    if game_state.console.open && handle_console_event(game_state, &event) {
+        return;
+    }
+
     match event {
         glfw::WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
             game_state.close = true;
@@ -619,7 +3610,41 @@ fn handle_window_event(game_state: &mut GameState,
                 Key::D => game_state.right = true,
                 Key::Space => game_state.jump = true,
 
-                _ => {},
+                Key::M => game_state.show_minimap = !game_state.show_minimap,
+                Key::F12 => game_state.screenshot = true,
+                Key::F5 => game_state.reload_shader = true,
+                Key::F3 => game_state.log_position = true,
+                Key::F4 => {
+                    game_state.show_colliders = !game_state.show_colliders;
+                }
+                Key::F6 => {
+                    game_state.debug_mode = game_state.debug_mode.next();
+                    log::info!("Debug mode: {:?}", game_state.debug_mode);
+                }
+                Key::P => game_state.toggle_pause = true,
+                Key::GraveAccent => toggle_console(game_state),
+                Key::Backspace => game_state.text_input.backspace(),
+                Key::O => {
+                    game_state.portal_culling = !game_state.portal_culling;
+                }
+                Key::C => {
+                    game_state.cull_mode = next_cull_mode(game_state.cull_mode);
+                }
+                Key::N => game_state.noclip = !game_state.noclip,
+                Key::R => game_state.reset_player = true,
+                Key::B => game_state.spawn_box = true,
+
+                Key::T => {
+                    game_state.teleport_sector = game_state.sector_input.take();
+                }
+
+                _ => {
+                    if let Some(digit) = digit_key(key) {
+                        let input = game_state.sector_input.unwrap_or(0);
+                        game_state.sector_input =
+                            Some(input.saturating_mul(10).saturating_add(digit));
+                    }
+                },
             }
         }
 
@@ -635,6 +3660,10 @@ fn handle_window_event(game_state: &mut GameState,
             }
         }
 
+        glfw::WindowEvent::Char(c) => {
+            game_state.text_input.push(c);
+        }
+
         glfw::WindowEvent::CursorPos(mx, my) => {
             let mx = mx as f32;
             let my = my as f32;
@@ -645,19 +3674,200 @@ fn handle_window_event(game_state: &mut GameState,
                 game_state.first_mouse = false;
             }
 
-            let mut x_offset = mx - game_state.last_mouse_x;
-            let mut y_offset = game_state.last_mouse_y - my;
+            let x_offset = mx - game_state.last_mouse_x;
+            let y_offset = game_state.last_mouse_y - my;
             game_state.last_mouse_x = mx;
             game_state.last_mouse_y = my;
 
-            const SENSITIVITY: f32 = 0.1;
-            x_offset *= SENSITIVITY;
-            y_offset *= SENSITIVITY;
-
-            game_state.yaw   -= x_offset;
-            game_state.pitch += y_offset;
+            game_state.mouse_delta += Vec2::new(x_offset, y_offset);
         }
 
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn view_matrix_eye_height_and_forward() {
+        let pos = Vec3::new(10.0, 5.0, -3.0);
+        let direction = Vec3::new(1.0, 0.0, 0.0);
+        let view = view_matrix(pos, direction, Vec3::Y, 20.0);
+
+        // NOTE(patrik): The eye is placed above the position and ends up
+        // at the origin of the view space
+        let eye = pos + Vec3::new(0.0, 20.0, 0.0);
+        assert_close(view.transform_point3(eye), Vec3::ZERO);
+        assert_close(view.transform_point3(pos), Vec3::new(0.0, -20.0, 0.0));
+
+        // NOTE(patrik): Left handed, the camera looks down +Z
+        assert_close(view.transform_point3(eye + direction * 5.0),
+                     Vec3::new(0.0, 0.0, 5.0));
+    }
+
+    #[test]
+    fn physics_object_falls() {
+        let mut world = World::new();
+        world.insert_resource(RigidBodySet::new());
+        let mut colliders = ColliderSet::new();
+
+        let shape = ColliderShape::Cuboid { half_extents: [1.0, 1.0, 1.0] };
+        let entity = spawn_physics_object(&mut world, &mut colliders,
+                                          Vec3::new(0.0, 64.0, 0.0), shape);
+        let handle = world.entity(entity).get::<PhysicsBody>().unwrap().0;
+
+        let mut physics = PhysicsContext::new(PHYSICS_TIMESTEP);
+        let mut bodies = world.get_resource_mut::<RigidBodySet>().unwrap();
+
+        let mut last_y = bodies.get(handle).unwrap().translation().y;
+        assert_eq!(last_y, 64.0 / UNIT_TO_METERS);
+
+        for _ in 0..10 {
+            physics.step(&mut bodies, &mut colliders, &(), &());
+
+            let y = bodies.get(handle).unwrap().translation().y;
+            assert!(y < last_y, "{} didn't fall below {}", y, last_y);
+            last_y = y;
+        }
+    }
+
+    #[test]
+    fn strip_format_header_checks_the_version() {
+        let header = |version: u32| {
+            let mut data = MIME_MAGIC.to_vec();
+            data.extend_from_slice(&version.to_le_bytes());
+            data.extend_from_slice(&[1, 2, 3]);
+            data
+        };
+
+        let supported = header(MIME_FORMAT_VERSION);
+        assert_eq!(strip_format_header(&supported).unwrap(), &[1, 2, 3]);
+
+        let newer = header(MIME_FORMAT_VERSION + 1);
+        let result = strip_format_header(&newer);
+        assert!(matches!(result,
+                         Err(MapLoadError::UnsupportedVersion(v))
+                             if v == MIME_FORMAT_VERSION + 1),
+                "{:?}", result);
+
+        let result = strip_format_header(b"MIME\x02");
+        assert!(matches!(result, Err(MapLoadError::Deserialize(_))),
+                "{:?}", result);
+
+        // NOTE(patrik): Maps from before the header are passed through
+        assert_eq!(strip_format_header(&[4, 0, 0, 0]).unwrap(), &[4, 0, 0, 0]);
+    }
+
+    #[test]
+    fn batch_instances_groups_equal_keys() {
+        let matrix = |x: f32| Mat4::from_translation(Vec3::new(x, 0.0, 0.0));
+
+        let (batches, model_matrices) = batch_instances([
+            ("box", matrix(0.0)),
+            ("barrel", matrix(1.0)),
+            ("box", matrix(2.0)),
+            ("box", matrix(3.0)),
+        ]);
+
+        assert_eq!(batches, vec![("box", 0..3), ("barrel", 3..4)]);
+        assert_eq!(model_matrices, vec![matrix(0.0), matrix(2.0),
+                                        matrix(3.0), matrix(1.0)]);
+
+        let (batches, model_matrices) =
+            batch_instances(std::iter::empty::<(u32, Mat4)>());
+        assert!(batches.is_empty());
+        assert!(model_matrices.is_empty());
+    }
+
+    #[test]
+    fn validate_sectors_rejects_empty_map() {
+        let result = validate_sectors(std::iter::empty());
+        assert!(matches!(result, Err(MapLoadError::EmptyMap)), "{:?}", result);
+    }
+
+    /// Flat floor at y = 0 with the material used for the map floors
+    fn insert_floor(colliders: &mut ColliderSet) {
+        let points = vec![
+            Point3::new(-10.0, 0.0, -10.0),
+            Point3::new(-10.0, 0.0, 10.0),
+            Point3::new(10.0, 0.0, 10.0),
+            Point3::new(10.0, 0.0, -10.0),
+        ];
+        let indices = vec![[0, 1, 2], [0, 2, 3]];
+        let floor_material = MapLoadOptions::default().floor_material;
+        let floor = floor_material.apply(ColliderBuilder::trimesh(points,
+                                                                  indices));
+        colliders.insert(floor.build());
+    }
+
+    #[test]
+    fn cuboid_settles_on_floor_without_bouncing() {
+        let mut bodies = RigidBodySet::new();
+        let mut colliders = ColliderSet::new();
+        insert_floor(&mut colliders);
+
+        let body = RigidBodyBuilder::new_dynamic()
+            .translation(vector![0.0, 2.0, 0.0])
+            .build();
+        let handle = bodies.insert(body);
+        colliders.insert_with_parent(ColliderBuilder::cuboid(0.5, 0.5, 0.5)
+                                         .build(),
+                                     handle, &mut bodies);
+
+        let mut physics = PhysicsContext::new(PHYSICS_TIMESTEP);
+        for _ in 0..180 {
+            physics.step(&mut bodies, &mut colliders, &(), &());
+
+            let velocity = bodies.get(handle).unwrap().linvel().y;
+            assert!(velocity < 0.1, "Rebounded with {} m/s", velocity);
+        }
+
+        let body = bodies.get(handle).unwrap();
+        assert!(body.linvel().y.abs() < 0.01, "{}", body.linvel().y);
+        assert!((body.translation().y - 0.5).abs() < 0.05,
+                "{}", body.translation().y);
+    }
+
+    #[test]
+    fn player_grounded_only_when_standing() {
+        let mut bodies = RigidBodySet::new();
+        let mut colliders = ColliderSet::new();
+        insert_floor(&mut colliders);
+
+        // NOTE(patrik): The capsule reaches 0.8 meters below its center
+        let mut spawn_player = |x: f32, y: f32, velocity_y: f32| {
+            let body = RigidBodyBuilder::new_dynamic()
+                .translation(vector![x, y, 0.0])
+                .linvel(vector![0.0, velocity_y, 0.0])
+                .build();
+            let body_handle = bodies.insert(body);
+            let collider_handle = colliders.insert_with_parent(
+                ColliderBuilder::capsule_y(0.5, 0.3).build(),
+                body_handle, &mut bodies);
+
+            Player {
+                collider_handle,
+                body_handle,
+                speed: 100.0,
+                jump_speed: 4.0,
+            }
+        };
+
+        let standing = spawn_player(-5.0, 0.8, 0.0);
+        let apex = spawn_player(0.0, 3.0, 0.0);
+        let jumping = spawn_player(5.0, 0.85, 4.0);
+
+        let mut physics = PhysicsContext::new(PHYSICS_TIMESTEP);
+        physics.update_query_pipeline(&bodies, &colliders);
+
+        assert!(player_grounded(&physics, &bodies, &colliders, &standing));
+        assert!(!player_grounded(&physics, &bodies, &colliders, &apex));
+        assert!(!player_grounded(&physics, &bodies, &colliders, &jumping));
+    }
+}
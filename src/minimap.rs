@@ -0,0 +1,138 @@
+use glam::f32::{ Mat4, Vec3 };
+
+use crate::render::{ GpuDevice, WindowSurface, RenderPipeline };
+use crate::render::{ UniformBuffer, RenderTarget, PostProcess };
+use crate::render::{ DynamicUniformBuffer, ModelUniform, MeshRegistry };
+use crate::render::GpuUniform;
+use crate::Map;
+
+/// Size of the minimap texture in pixels
+const MINIMAP_SIZE: u32 = 256;
+/// How many world units the minimap covers from edge to edge
+const MINIMAP_EXTENT: f32 = 2000.0;
+/// Height above the player the minimap camera is placed at
+const MINIMAP_CAMERA_HEIGHT: f32 = 1000.0;
+/// Margin between the minimap and the corner of the screen in pixels
+const MINIMAP_MARGIN: f32 = 16.0;
+
+/// Top-down view of the map around the player, rendered into a texture and
+/// composited into the top right corner of the screen
+pub struct Minimap {
+    target: RenderTarget,
+
+    uniform_buffer: UniformBuffer,
+    gpu_uniform: GpuUniform<UniformBuffer>,
+
+    composite: PostProcess,
+    composite_bind_group: wgpu::BindGroup,
+}
+
+impl Minimap {
+    pub fn new(gpu_device: &GpuDevice, surface: &WindowSurface) -> Self
+    {
+        let target = RenderTarget::new(gpu_device,
+                                       MINIMAP_SIZE, MINIMAP_SIZE,
+                                       surface.config().format);
+
+        let half_extent = MINIMAP_EXTENT / 2.0;
+        let projection_matrix =
+            Mat4::orthographic_lh(-half_extent, half_extent,
+                                  -half_extent, half_extent,
+                                  0.1, MINIMAP_CAMERA_HEIGHT * 2.0);
+
+        let uniform_buffer = UniformBuffer::new(projection_matrix,
+                                                Mat4::IDENTITY,
+                                                Mat4::IDENTITY);
+
+        let gpu_uniform = GpuUniform::new(gpu_device, &uniform_buffer,
+                                          wgpu::ShaderStages::VERTEX |
+                                          wgpu::ShaderStages::FRAGMENT,
+                                          "minimap_uniform_buffer");
+
+        let composite_shader = gpu_device.device.create_shader_module(&wgpu::include_wgsl!("minimap.wgsl"));
+        let composite = PostProcess::new(gpu_device, surface, &composite_shader);
+        let composite_bind_group =
+            composite.create_bind_group(gpu_device, &target.color);
+
+        Self {
+            target,
+
+            uniform_buffer,
+            gpu_uniform,
+
+            composite,
+            composite_bind_group,
+        }
+    }
+
+    /// Render the floors of the map from above `player_pos` into the
+    /// minimap texture
+    pub fn render(&mut self,
+                  gpu_device: &GpuDevice,
+                  encoder: &mut wgpu::CommandEncoder,
+                  pipeline: &RenderPipeline,
+                  model_uniforms: &DynamicUniformBuffer<ModelUniform>,
+                  shadow_bind_group: &wgpu::BindGroup,
+                  map: &Map,
+                  meshes: &MeshRegistry,
+                  player_pos: Vec3)
+    {
+        let eye = player_pos + Vec3::new(0.0, MINIMAP_CAMERA_HEIGHT, 0.0);
+        let view_matrix = Mat4::look_at_lh(eye, player_pos, Vec3::Z);
+
+        self.uniform_buffer.update_view(view_matrix);
+        self.gpu_uniform.update(&gpu_device.queue, &self.uniform_buffer);
+
+        let clear_color = wgpu::Color {
+            r: 0.0,
+            g: 0.0,
+            b: 0.0,
+            a: 1.0,
+        };
+
+        let mut render_pass =
+            self.target.begin_render_pass(encoder, clear_color);
+
+        render_pass.set_pipeline(pipeline.handle());
+        render_pass.set_bind_group(0, self.gpu_uniform.bind_group(), &[]);
+        // NOTE(patrik): The minimap has no directional light so the shadow
+        // map is only bound to satisfy the pipeline layout
+        render_pass.set_bind_group(2, shadow_bind_group, &[]);
+
+        for (index, sector) in map.sectors.iter().enumerate() {
+            render_pass.set_bind_group(1, model_uniforms.bind_group(),
+                                       &[model_uniforms.offset(index)]);
+
+            let m = match meshes.get(sector.floor_mesh) {
+                Some(m) => m,
+                None => continue,
+            };
+            m.draw(&mut render_pass);
+        }
+    }
+
+    /// Draw the minimap texture into the top right corner of `target`,
+    /// shrunk to fit small windows
+    pub fn composite(&self,
+                     encoder: &mut wgpu::CommandEncoder,
+                     target: &wgpu::TextureView,
+                     target_width: u32,
+                     target_height: u32)
+    {
+        // NOTE(patrik): The viewport has to stay inside the target or wgpu
+        // fails the validation
+        let size = (MINIMAP_SIZE as f32)
+            .min(target_width as f32 - MINIMAP_MARGIN * 2.0)
+            .min(target_height as f32 - MINIMAP_MARGIN * 2.0);
+        if size < 1.0 {
+            return;
+        }
+
+        let x = target_width as f32 - size - MINIMAP_MARGIN;
+        let y = MINIMAP_MARGIN;
+
+        self.composite.run_in_viewport(encoder, target,
+                                       &self.composite_bind_group,
+                                       [x, y, size, size]);
+    }
+}
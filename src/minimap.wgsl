@@ -0,0 +1,21 @@
+// Minimap composite, draws the top-down render with a marker for the
+// player in the center
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] uv: vec2<f32>;
+};
+
+[[group(0), binding(0)]]
+var source_texture: texture_2d<f32>;
+[[group(0), binding(1)]]
+var source_sampler: sampler;
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    if (distance(in.uv, vec2<f32>(0.5, 0.5)) < 0.02) {
+        return vec4<f32>(1.0, 0.0, 0.0, 1.0);
+    }
+
+    return textureSample(source_texture, source_sampler, in.uv);
+}
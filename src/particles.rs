@@ -0,0 +1,206 @@
+use bevy_ecs::prelude::*;
+use glam::f32::Vec3;
+
+use crate::render::{ GpuDevice, Mesh, Vertex };
+
+/// Max number of particles alive at the same time, new particles are
+/// dropped when the limit is reached
+pub const MAX_PARTICLES: usize = 2048;
+
+/// Spawns particles at the `Position` of the entity
+#[derive(Component, Clone, Debug)]
+pub struct ParticleEmitter {
+    /// Particles spawned per second
+    pub spawn_rate: f32,
+    /// Seconds until a particle is removed, it fades out over this time
+    pub lifetime: f32,
+    /// Start velocity of the particles (world units per second)
+    pub velocity: Vec3,
+    /// Max random offset added to each axis of the start velocity
+    pub spread: f32,
+    /// Acceleration applied to the particles, like gravity
+    pub acceleration: Vec3,
+    /// Color in sRGB space, the alpha is linear
+    pub color: [f32; 4],
+    /// Width and height of a particle (world units)
+    pub size: f32,
+
+    spawn_accumulator: f32,
+}
+
+impl ParticleEmitter {
+    pub fn new(spawn_rate: f32, lifetime: f32, velocity: Vec3, color: [f32; 4])
+        -> Self
+    {
+        Self {
+            spawn_rate,
+            lifetime,
+            velocity,
+            spread: 0.0,
+            acceleration: Vec3::ZERO,
+            color,
+            size: 2.0,
+
+            spawn_accumulator: 0.0,
+        }
+    }
+
+    pub fn spread(mut self, spread: f32) -> Self {
+        self.spread = spread;
+        self
+    }
+
+    pub fn acceleration(mut self, acceleration: Vec3) -> Self {
+        self.acceleration = acceleration;
+        self
+    }
+
+    pub fn size(mut self, size: f32) -> Self {
+        self.size = size;
+        self
+    }
+}
+
+struct Particle {
+    position: Vec3,
+    velocity: Vec3,
+    acceleration: Vec3,
+
+    age: f32,
+    lifetime: f32,
+
+    color: [f32; 4],
+    size: f32,
+}
+
+/// All the live particles, simulated on the CPU and uploaded to a dynamic
+/// mesh as camera facing quads every frame
+pub struct Particles {
+    particles: Vec<Particle>,
+    mesh: Mesh,
+
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+
+    random_state: u32,
+}
+
+impl Particles {
+    pub fn new(gpu_device: &GpuDevice) -> Self {
+        let mesh = Mesh::dynamic(gpu_device,
+                                 (MAX_PARTICLES * 4) as u32,
+                                 (MAX_PARTICLES * 6) as u32);
+
+        Self {
+            particles: Vec::new(),
+            mesh,
+
+            vertices: Vec::new(),
+            indices: Vec::new(),
+
+            random_state: 0x9e3779b9,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.particles.len()
+    }
+
+    /// Spawn the particles `emitter` has accumulated since the last update
+    pub fn emit(&mut self, emitter: &mut ParticleEmitter, position: Vec3,
+                dt: f32)
+    {
+        emitter.spawn_accumulator += emitter.spawn_rate * dt;
+
+        while emitter.spawn_accumulator >= 1.0 {
+            emitter.spawn_accumulator -= 1.0;
+
+            if self.particles.len() >= MAX_PARTICLES {
+                continue;
+            }
+
+            let offset = Vec3::new(self.random_signed(),
+                                   self.random_signed(),
+                                   self.random_signed());
+
+            self.particles.push(Particle {
+                position,
+                velocity: emitter.velocity + offset * emitter.spread,
+                acceleration: emitter.acceleration,
+
+                age: 0.0,
+                lifetime: emitter.lifetime,
+
+                color: emitter.color,
+                size: emitter.size,
+            });
+        }
+    }
+
+    /// Move the particles and remove the ones that has reached the end of
+    /// their lifetime
+    pub fn simulate(&mut self, dt: f32) {
+        for particle in &mut self.particles {
+            particle.velocity += particle.acceleration * dt;
+            particle.position += particle.velocity * dt;
+            particle.age += dt;
+        }
+
+        self.particles.retain(|particle| particle.age < particle.lifetime);
+    }
+
+    /// Build the quads facing the camera and write them to the mesh,
+    /// `right` and `up` are the camera axes in world space
+    pub fn upload(&mut self, gpu_device: &GpuDevice, right: Vec3, up: Vec3) {
+        // NOTE(patrik): Nothing is drawn without particles so the old data
+        // can stay, updating with no indices would drop the index buffer
+        if self.particles.is_empty() {
+            return;
+        }
+
+        self.vertices.clear();
+        self.indices.clear();
+
+        for particle in &self.particles {
+            let half_size = particle.size / 2.0;
+            let right = right * half_size;
+            let up = up * half_size;
+
+            // NOTE(patrik): Fade out linearly over the lifetime
+            let fade = 1.0 - (particle.age / particle.lifetime).clamp(0.0, 1.0);
+            let mut color = particle.color;
+            color[3] *= fade;
+
+            let base = self.vertices.len() as u32;
+            for corner in [-right - up, -right + up, right + up, right - up] {
+                self.vertices.push(Vertex {
+                    position: (particle.position + corner).into(),
+                    color,
+                    normal: [0.0, 0.0, 0.0],
+                });
+            }
+
+            self.indices.extend_from_slice(&[base, base + 1, base + 2,
+                                             base, base + 2, base + 3]);
+        }
+
+        self.mesh.update(gpu_device, &self.vertices, &self.indices);
+    }
+
+    pub fn mesh(&self) -> &Mesh {
+        &self.mesh
+    }
+
+    /// Random value in the range -1..1, doesn't need to be good just
+    /// different for each particle
+    fn random_signed(&mut self) -> f32 {
+        // NOTE(patrik): xorshift32
+        let mut x = self.random_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random_state = x;
+
+        (x as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
@@ -0,0 +1,250 @@
+use glam::f32::Vec3;
+use rapier3d::prelude::*;
+use serde::Deserialize;
+
+use crate::render::DebugLines;
+
+/// Shape of a collider in meters, selectable from the config
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum ColliderShape {
+    Cuboid {
+        half_extents: [f32; 3],
+    },
+
+    /// Capsule along the Y axis, the total height is
+    /// `2 * (half_height + radius)`
+    Capsule {
+        radius: f32,
+        half_height: f32,
+    },
+}
+
+impl ColliderShape {
+    pub fn builder(&self) -> ColliderBuilder {
+        match *self {
+            Self::Cuboid { half_extents: [x, y, z] } => {
+                ColliderBuilder::cuboid(x, y, z)
+            }
+
+            Self::Capsule { radius, half_height } => {
+                ColliderBuilder::capsule_y(half_height, radius)
+            }
+        }
+    }
+}
+
+/// Contact properties of a collider
+#[derive(Copy, Clone, Debug)]
+pub struct SurfaceMaterial {
+    pub friction: f32,
+    /// How the friction is combined with the friction of the other
+    /// collider in a contact, when the two colliders use different rules
+    /// the one with the highest priority (`Max` > `Multiply` > `Min` >
+    /// `Average`) wins
+    pub friction_combine_rule: CoefficientCombineRule,
+    /// Bounciness, 0 doesn't bounce at all
+    pub restitution: f32,
+}
+
+impl SurfaceMaterial {
+    /// No friction or bounce, things slide along it. Uses the smallest of
+    /// the two frictions so the friction of the other collider doesn't
+    /// get averaged in
+    pub const SLIPPERY: Self = Self {
+        friction: 0.0,
+        friction_combine_rule: CoefficientCombineRule::Min,
+        restitution: 0.0,
+    };
+
+    pub fn apply(&self, builder: ColliderBuilder) -> ColliderBuilder {
+        builder
+            .friction(self.friction)
+            .friction_combine_rule(self.friction_combine_rule)
+            .restitution(self.restitution)
+    }
+}
+
+/// All the rapier state needed to step the simulation, the simulation is
+/// stepped with a fixed timestep so it behaves the same regardless of the
+/// frame rate
+pub struct PhysicsContext {
+    pub gravity: Vector<Real>,
+    pub integration_parameters: IntegrationParameters,
+    pub physics_pipeline: PhysicsPipeline,
+    pub island_manager: IslandManager,
+    pub broad_phase: BroadPhase,
+    pub narrow_phase: NarrowPhase,
+    pub joint_set: JointSet,
+    pub ccd_solver: CCDSolver,
+    /// Spatial queries against the colliders, updated after every step
+    pub query_pipeline: QueryPipeline,
+
+    /// Real time that hasn't been simulated yet
+    accumulator: f32,
+}
+
+impl PhysicsContext {
+    pub fn new(timestep: f32) -> Self {
+        let mut integration_parameters = IntegrationParameters::default();
+        integration_parameters.dt = timestep;
+
+        Self {
+            gravity: vector![0.0, -9.81, 0.0],
+            integration_parameters,
+            physics_pipeline: PhysicsPipeline::new(),
+            island_manager: IslandManager::new(),
+            broad_phase: BroadPhase::new(),
+            narrow_phase: NarrowPhase::new(),
+            joint_set: JointSet::new(),
+            ccd_solver: CCDSolver::new(),
+            query_pipeline: QueryPipeline::new(),
+
+            accumulator: 0.0,
+        }
+    }
+
+    pub fn timestep(&self) -> f32 {
+        self.integration_parameters.dt
+    }
+
+    /// How far (0.0 - 1.0) into the next step the simulation is, used for
+    /// interpolating between the previous and current step
+    pub fn alpha(&self) -> f32 {
+        self.accumulator / self.timestep()
+    }
+
+    /// Advance the simulation by `dt` seconds of real time, runs as many
+    /// fixed steps as fits and carries the remainder over to the next call.
+    /// Returns the number of steps taken
+    pub fn update(&mut self,
+                  dt: f32,
+                  bodies: &mut RigidBodySet,
+                  colliders: &mut ColliderSet,
+                  hooks: &dyn PhysicsHooks<RigidBodySet, ColliderSet>,
+                  events: &dyn EventHandler)
+        -> u32
+    {
+        self.accumulator += dt;
+
+        let mut steps = 0;
+        while self.accumulator >= self.timestep() {
+            self.step(bodies, colliders, hooks, events);
+
+            self.accumulator -= self.timestep();
+            steps += 1;
+        }
+
+        steps
+    }
+
+    pub fn step(&mut self,
+                bodies: &mut RigidBodySet,
+                colliders: &mut ColliderSet,
+                hooks: &dyn PhysicsHooks<RigidBodySet, ColliderSet>,
+                events: &dyn EventHandler)
+    {
+        let _span = tracing::debug_span!("physics_step").entered();
+
+        self.physics_pipeline.step(
+            &self.gravity,
+            &self.integration_parameters,
+            &mut self.island_manager,
+            &mut self.broad_phase,
+            &mut self.narrow_phase,
+            bodies,
+            colliders,
+            &mut self.joint_set,
+            &mut self.ccd_solver,
+            hooks,
+            events,
+        );
+
+        self.update_query_pipeline(bodies, colliders);
+    }
+
+    /// Update the query pipeline without stepping, needed before the first
+    /// step or after colliders has been added or moved by hand
+    pub fn update_query_pipeline(&mut self,
+                                 bodies: &RigidBodySet,
+                                 colliders: &ColliderSet)
+    {
+        self.query_pipeline.update(&self.island_manager, bodies, colliders);
+    }
+
+    /// Cast a ray against all the colliders except `exclude`, returns the
+    /// collider hit and the distance along `direction` to the hit
+    pub fn cast_ray(&self,
+                    colliders: &ColliderSet,
+                    origin: Point<Real>,
+                    direction: Vector<Real>,
+                    max_toi: Real,
+                    exclude: Option<ColliderHandle>)
+        -> Option<(ColliderHandle, Real)>
+    {
+        let ray = Ray::new(origin, direction);
+        let filter = |handle: ColliderHandle| Some(handle) != exclude;
+
+        self.query_pipeline.cast_ray(colliders, &ray, max_toi, true,
+                                     InteractionGroups::all(),
+                                     Some(&filter))
+    }
+}
+
+/// Colors of the collider visualization, sensors are drawn differently
+/// since nothing collides with them
+const COLLIDER_COLOR: [f32; 4] = [0.0, 1.0, 0.0, 1.0];
+const SENSOR_COLOR: [f32; 4] = [1.0, 1.0, 0.0, 1.0];
+
+/// Add the outlines of all the colliders to `lines`, `scale` converts from
+/// meters to world units. Trimeshes and cuboids are drawn as they are,
+/// other shapes as their bounding box
+pub fn collider_lines(colliders: &ColliderSet, scale: f32,
+                      lines: &mut DebugLines)
+{
+    let to_world = |p: Point<Real>| Vec3::new(p.x, p.y, p.z) * scale;
+
+    for (_, collider) in colliders.iter() {
+        let color = if collider.is_sensor() {
+            SENSOR_COLOR
+        } else {
+            COLLIDER_COLOR
+        };
+
+        let position = collider.position();
+        let shape = collider.shape();
+
+        if let Some(trimesh) = shape.as_trimesh() {
+            let vertices = trimesh.vertices();
+
+            for triangle in trimesh.indices() {
+                let p = |i: u32| to_world(position * vertices[i as usize]);
+                let (a, b, c) = (p(triangle[0]), p(triangle[1]), p(triangle[2]));
+
+                // NOTE(patrik): The edges shared between triangles are
+                // drawn twice, good enough for debugging
+                lines.line(a, b, color);
+                lines.line(b, c, color);
+                lines.line(c, a, color);
+            }
+        } else if let Some(cuboid) = shape.as_cuboid() {
+            let h = cuboid.half_extents;
+            let corner = |x: f32, y: f32, z: f32| {
+                to_world(position * point![h.x * x, h.y * y, h.z * z])
+            };
+
+            // NOTE(patrik): The cuboid can be rotated so the edges are
+            // transformed one by one instead of drawing an aabb
+            for a in [-1.0, 1.0] {
+                for b in [-1.0, 1.0] {
+                    lines.line(corner(-1.0, a, b), corner(1.0, a, b), color);
+                    lines.line(corner(a, -1.0, b), corner(a, 1.0, b), color);
+                    lines.line(corner(a, b, -1.0), corner(a, b, 1.0), color);
+                }
+            }
+        } else {
+            let aabb = collider.compute_aabb();
+            lines.aabb(to_world(aabb.mins), to_world(aabb.maxs), color);
+        }
+    }
+}
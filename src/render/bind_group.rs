@@ -0,0 +1,146 @@
+use super::GpuDevice;
+
+/// Builds a bind group together with its layout, the layout entry for each
+/// resource is derived from how it's added
+#[derive(Default)]
+pub struct BindGroupBuilder<'a> {
+    label: Option<&'a str>,
+
+    layout_entries: Vec<wgpu::BindGroupLayoutEntry>,
+    entries: Vec<wgpu::BindGroupEntry<'a>>,
+}
+
+impl<'a> BindGroupBuilder<'a> {
+    pub fn new() -> Self {
+        Self {
+            label: None,
+
+            layout_entries: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn label(mut self, label: &'a str) -> Self {
+        self.label = Some(label);
+        self
+    }
+
+    /// Bind the whole of `buffer` as a uniform buffer
+    pub fn buffer(mut self,
+                  binding: u32,
+                  buffer: &'a wgpu::Buffer,
+                  visibility: wgpu::ShaderStages)
+        -> Self
+    {
+        self.layout_entries.push(wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+
+        self.entries.push(wgpu::BindGroupEntry {
+            binding,
+            resource: buffer.as_entire_binding(),
+        });
+
+        self
+    }
+
+    /// Bind a filterable 2D color texture, visible to the fragment stage
+    pub fn texture(mut self, binding: u32, view: &'a wgpu::TextureView)
+        -> Self
+    {
+        self.layout_entries.push(wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float {
+                    filterable: true,
+                },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        });
+
+        self.entries.push(wgpu::BindGroupEntry {
+            binding,
+            resource: wgpu::BindingResource::TextureView(view),
+        });
+
+        self
+    }
+
+    /// Bind a filtering sampler, visible to the fragment stage
+    pub fn sampler(mut self, binding: u32, sampler: &'a wgpu::Sampler)
+        -> Self
+    {
+        self.layout_entries.push(wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
+        });
+
+        self.entries.push(wgpu::BindGroupEntry {
+            binding,
+            resource: wgpu::BindingResource::Sampler(sampler),
+        });
+
+        self
+    }
+
+    pub fn build(&self, gpu_device: &GpuDevice)
+        -> (wgpu::BindGroup, wgpu::BindGroupLayout)
+    {
+        let layout = gpu_device.device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                label: self.label,
+                entries: &self.layout_entries,
+            }
+        );
+
+        let bind_group = gpu_device.device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                label: self.label,
+                layout: &layout,
+                entries: &self.entries,
+            }
+        );
+
+        (bind_group, layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::test_device;
+
+    #[test]
+    #[ignore = "needs a GPU adapter, run with --ignored"]
+    fn uniform_only_bind_group() {
+        let gpu_device = test_device();
+
+        let buffer = gpu_device.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("test_uniform_buffer"),
+            size: 64,
+            usage: wgpu::BufferUsages::UNIFORM,
+            mapped_at_creation: false,
+        });
+
+        let builder = BindGroupBuilder::new()
+            .label("test_bind_group")
+            .buffer(0, &buffer, wgpu::ShaderStages::VERTEX);
+        assert_eq!(builder.layout_entries.len(), 1);
+        assert_eq!(builder.entries.len(), 1);
+
+        let result = gpu_device.catch_errors(|| builder.build(&gpu_device));
+        assert!(result.is_ok(), "{:?}", result.err());
+    }
+}
@@ -0,0 +1,68 @@
+use glam::f32::Vec3;
+
+use super::{ GpuDevice, Mesh, Vertex };
+
+/// Lines collected on the CPU during the frame and drawn in one go with a
+/// `LineList` pipeline
+pub struct DebugLines {
+    vertices: Vec<Vertex>,
+    mesh: Mesh,
+}
+
+impl DebugLines {
+    pub fn new(gpu_device: &GpuDevice) -> Self {
+        Self {
+            vertices: Vec::new(),
+            mesh: Mesh::dynamic(gpu_device, 4096, 0),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+    }
+
+    /// Line from `a` to `b` in world space, `color` is in sRGB space
+    pub fn line(&mut self, a: Vec3, b: Vec3, color: [f32; 4]) {
+        for position in [a, b] {
+            self.vertices.push(Vertex {
+                position: position.to_array(),
+                color,
+                normal: [0.0, 0.0, 0.0],
+            });
+        }
+    }
+
+    /// The edges of the box with the corners `min` and `max`
+    pub fn aabb(&mut self, min: Vec3, max: Vec3, color: [f32; 4]) {
+        let corner = |x: bool, y: bool, z: bool| {
+            Vec3::new(if x { max.x } else { min.x },
+                      if y { max.y } else { min.y },
+                      if z { max.z } else { min.z })
+        };
+
+        for a in [false, true] {
+            for b in [false, true] {
+                self.line(corner(false, a, b), corner(true, a, b), color);
+                self.line(corner(a, false, b), corner(a, true, b), color);
+                self.line(corner(a, b, false), corner(a, b, true), color);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vertices.is_empty()
+    }
+
+    /// Write the lines to the GPU, needs to be called before drawing
+    pub fn upload(&mut self, gpu_device: &GpuDevice) {
+        if self.vertices.is_empty() {
+            return;
+        }
+
+        self.mesh.update(gpu_device, &self.vertices, &[]);
+    }
+
+    pub fn mesh(&self) -> &Mesh {
+        &self.mesh
+    }
+}
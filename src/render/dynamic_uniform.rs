@@ -0,0 +1,116 @@
+use std::marker::PhantomData;
+use std::num::NonZeroU64;
+
+use super::GpuDevice;
+
+/// Uniform buffer holding `capacity` values of `T`, each aligned to the
+/// device `min_uniform_buffer_offset_alignment`. The values are selected
+/// per draw with a dynamic offset from `offset`
+pub struct DynamicUniformBuffer<T: bytemuck::Pod> {
+    buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+
+    stride: usize,
+    capacity: usize,
+    data: Vec<u8>,
+
+    _marker: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> DynamicUniformBuffer<T> {
+    pub fn new(gpu_device: &GpuDevice,
+               capacity: usize,
+               visibility: wgpu::ShaderStages)
+        -> Self
+    {
+        let capacity = capacity.max(1);
+
+        let size = std::mem::size_of::<T>();
+        let alignment = gpu_device.device.limits()
+            .min_uniform_buffer_offset_alignment as usize;
+        let stride = size.div_ceil(alignment) * alignment;
+
+        let buffer = gpu_device.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Dynamic Uniform Buffer"),
+            size: (stride * capacity) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group_layout = gpu_device.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: NonZeroU64::new(size as u64),
+                    },
+                    count: None,
+                }
+            ],
+            label: Some("dynamic_uniform_buffer_bind_group_layout"),
+        });
+
+        let bind_group = gpu_device.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &buffer,
+                        offset: 0,
+                        size: NonZeroU64::new(size as u64),
+                    }),
+                }
+            ],
+            label: Some("dynamic_uniform_buffer_bind_group"),
+        });
+
+        Self {
+            buffer,
+            bind_group_layout,
+            bind_group,
+
+            stride,
+            capacity,
+            data: vec![0; stride * capacity],
+
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.bind_group_layout
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Dynamic offset to pass to `set_bind_group` for the value at `index`
+    pub fn offset(&self, index: usize) -> wgpu::DynamicOffset {
+        assert!(index < self.capacity, "Dynamic uniform index out of range");
+        (index * self.stride) as wgpu::DynamicOffset
+    }
+
+    /// Set the CPU side value at `index`, call `upload` to send the changes
+    /// to the GPU
+    pub fn set(&mut self, index: usize, value: &T) {
+        assert!(index < self.capacity, "Dynamic uniform index out of range");
+
+        let start = index * self.stride;
+        let bytes = bytemuck::bytes_of(value);
+        self.data[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    pub fn upload(&self, gpu_device: &GpuDevice) {
+        gpu_device.queue.write_buffer(&self.buffer, 0, &self.data);
+    }
+}
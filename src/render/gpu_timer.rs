@@ -0,0 +1,140 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ Context, Poll, RawWaker, RawWakerVTable, Waker };
+
+use super::GpuDevice;
+
+type MapFuture =
+    Pin<Box<dyn Future<Output = Result<(), wgpu::BufferAsyncError>> + Send>>;
+
+enum Readback {
+    /// The buffer is free to resolve the queries into
+    Idle,
+    /// The resolve is recorded but not submitted yet
+    Resolved,
+    /// Waiting for the GPU to finish the frame and the buffer to map
+    Mapping(MapFuture),
+}
+
+/// Measures the GPU time of a frame with timestamp queries, needs the
+/// `TIMESTAMP_QUERY` feature. The result is read back a few frames later
+/// without stalling, frames are skipped while a read back is in flight
+pub struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    readback_buffer: wgpu::Buffer,
+    readback: Readback,
+
+    /// Nanoseconds per timestamp tick
+    period: f32,
+    last_time_ms: Option<f32>,
+}
+
+impl GpuTimer {
+    /// None if the device was created without `TIMESTAMP_QUERY`
+    pub fn new(gpu_device: &GpuDevice) -> Option<Self> {
+        if !gpu_device.device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            return None;
+        }
+
+        let query_set = gpu_device.device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("gpu_timer_queries"),
+            ty: wgpu::QueryType::Timestamp,
+            count: 2,
+        });
+
+        let readback_buffer = gpu_device.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("gpu_timer_readback"),
+            size: 2 * wgpu::QUERY_SIZE as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST |
+                   wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        Some(Self {
+            query_set,
+            readback_buffer,
+            readback: Readback::Idle,
+
+            period: gpu_device.queue.get_timestamp_period(),
+            last_time_ms: None,
+        })
+    }
+
+    /// Record the start timestamp, should be the first command of the
+    /// frame
+    pub fn begin(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.write_timestamp(&self.query_set, 0);
+    }
+
+    /// Record the end timestamp, should be the last command of the frame
+    pub fn end(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.write_timestamp(&self.query_set, 1);
+
+        if let Readback::Idle = self.readback {
+            encoder.resolve_query_set(&self.query_set, 0..2,
+                                      &self.readback_buffer, 0);
+            self.readback = Readback::Resolved;
+        }
+    }
+
+    /// Start mapping the results, needs to be called after the encoder
+    /// passed to `end` is submitted
+    pub fn submitted(&mut self) {
+        if let Readback::Resolved = self.readback {
+            let future = self.readback_buffer.slice(..)
+                .map_async(wgpu::MapMode::Read);
+            self.readback = Readback::Mapping(Box::pin(future));
+        }
+    }
+
+    /// Read back the results if the GPU is done with them. Returns the
+    /// latest GPU frame time in milliseconds
+    pub fn poll(&mut self, gpu_device: &GpuDevice) -> Option<f32> {
+        let future = match &mut self.readback {
+            Readback::Mapping(future) => future,
+            _ => return self.last_time_ms,
+        };
+
+        // NOTE(patrik): The map callbacks only run when the device is
+        // polled
+        gpu_device.device.poll(wgpu::Maintain::Poll);
+
+        let waker = noop_waker();
+        let mut context = Context::from_waker(&waker);
+        let result = match future.as_mut().poll(&mut context) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return self.last_time_ms,
+        };
+
+        if result.is_ok() {
+            let timestamps = {
+                let data = self.readback_buffer.slice(..).get_mapped_range();
+                data.chunks_exact(wgpu::QUERY_SIZE as usize)
+                    .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+                    .collect::<Vec<_>>()
+            };
+            self.readback_buffer.unmap();
+
+            let ticks = timestamps[1].wrapping_sub(timestamps[0]);
+            self.last_time_ms = Some(ticks as f32 * self.period / 1_000_000.0);
+        }
+
+        self.readback = Readback::Idle;
+
+        self.last_time_ms
+    }
+}
+
+/// Waker that does nothing, the map future is polled once per frame
+/// instead of waiting to be woken up
+fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+
+    fn noop(_: *const ()) {}
+
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+    unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+}
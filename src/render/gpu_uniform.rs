@@ -0,0 +1,59 @@
+use std::marker::PhantomData;
+
+use wgpu::util::DeviceExt;
+
+use super::{ GpuDevice, BindGroupBuilder };
+
+/// Uniform buffer on the GPU holding a single `T`, bound at `binding(0)`
+/// of its own bind group
+pub struct GpuUniform<T: bytemuck::Pod> {
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    bind_group_layout: wgpu::BindGroupLayout,
+
+    _marker: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> GpuUniform<T> {
+    pub fn new(gpu_device: &GpuDevice,
+               value: &T,
+               visibility: wgpu::ShaderStages,
+               label: &str)
+        -> Self
+    {
+        let buffer = gpu_device.device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents: bytemuck::bytes_of(value),
+                usage: wgpu::BufferUsages::UNIFORM |
+                       wgpu::BufferUsages::COPY_DST,
+            }
+        );
+
+        let (bind_group, bind_group_layout) = BindGroupBuilder::new()
+            .label(label)
+            .buffer(0, &buffer, visibility)
+            .build(gpu_device);
+
+        Self {
+            buffer,
+            bind_group,
+            bind_group_layout,
+
+            _marker: PhantomData,
+        }
+    }
+
+    /// Write `value` to the buffer, takes effect for the next submit
+    pub fn update(&self, queue: &wgpu::Queue, value: &T) {
+        queue.write_buffer(&self.buffer, 0, bytemuck::bytes_of(value));
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.bind_group_layout
+    }
+}
@@ -0,0 +1,87 @@
+use glam::f32::Mat4;
+
+use super::GpuDevice;
+
+/// Per instance data, the model matrix is passed as four vec4 attributes
+/// since vertex attributes can't be matrices
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct InstanceRaw {
+    model_matrix: [[f32; 4]; 4],
+}
+
+impl InstanceRaw {
+    pub fn new(model_matrix: Mat4) -> Self {
+        Self {
+            model_matrix: model_matrix.to_cols_array_2d(),
+        }
+    }
+
+    /// Uses the shader locations 5 to 8, the locations below are left
+    /// for `Vertex`
+    pub(crate) fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        const ATTRIBUTES: [wgpu::VertexAttribute; 4] = wgpu::vertex_attr_array![
+            5 => Float32x4,
+            6 => Float32x4,
+            7 => Float32x4,
+            8 => Float32x4,
+        ];
+
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &ATTRIBUTES,
+        }
+    }
+}
+
+/// Vertex buffer holding the model matrices for instanced draws
+pub struct InstanceBuffer {
+    buffer: wgpu::Buffer,
+    count: u32,
+    capacity: u32,
+}
+
+impl InstanceBuffer {
+    /// Empty buffer with room for `capacity` instances
+    pub fn new(gpu_device: &GpuDevice, capacity: u32) -> Self {
+        let capacity = capacity.max(1);
+
+        let buffer = gpu_device.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Instance Buffer"),
+            size: (std::mem::size_of::<InstanceRaw>() * capacity as usize)
+                as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            buffer,
+            count: 0,
+            capacity,
+        }
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Replace the instances, the number of instances can't grow past the
+    /// capacity the buffer was created with
+    pub fn update(&mut self, gpu_device: &GpuDevice, model_matrices: &[Mat4]) {
+        let count: u32 = model_matrices.len().try_into().unwrap();
+        assert!(count <= self.capacity, "Instance buffer capacity exceeded");
+
+        let instances = model_matrices.iter()
+            .map(|m| InstanceRaw::new(*m))
+            .collect::<Vec<_>>();
+
+        gpu_device.queue.write_buffer(&self.buffer, 0,
+                                      bytemuck::cast_slice(&instances));
+        self.count = count;
+    }
+}
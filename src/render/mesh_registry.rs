@@ -0,0 +1,39 @@
+use super::Mesh;
+
+/// Reference to a `Mesh` stored in a `MeshRegistry`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MeshHandle(u32);
+
+/// Owns all the meshes, everything else refers to them with a `MeshHandle`
+/// so the same mesh can be shared
+#[derive(Default)]
+pub struct MeshRegistry {
+    meshes: Vec<Mesh>,
+}
+
+impl MeshRegistry {
+    pub fn new() -> Self {
+        Self {
+            meshes: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, mesh: Mesh) -> MeshHandle {
+        let handle = MeshHandle(self.meshes.len().try_into().unwrap());
+        self.meshes.push(mesh);
+
+        handle
+    }
+
+    pub fn get(&self, handle: MeshHandle) -> Option<&Mesh> {
+        self.meshes.get(handle.0 as usize)
+    }
+
+    pub fn len(&self) -> usize {
+        self.meshes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.meshes.is_empty()
+    }
+}
@@ -1,10 +1,60 @@
+use std::ops::Range;
+
 use wgpu::util::DeviceExt;
 
-use glam::f32::Mat4;
+use glam::f32::{ Mat3, Mat4, Vec3 };
 
 pub use pipeline::{ PipelineLayout, RenderPipeline, RenderPipelineBuilder };
+pub use pipeline::{ PipelineCache, ShaderModule };
+pub use pipeline::{ stencil_write_reference, stencil_equal_reference };
+pub use texture::{ Texture, TextureOptions, RenderTarget };
+pub use post_process::{ PostProcess, fullscreen_pipeline };
+pub use dynamic_uniform::DynamicUniformBuffer;
+pub use instance::{ InstanceRaw, InstanceBuffer };
+pub use mesh_registry::{ MeshHandle, MeshRegistry };
+pub use shadow::ShadowMap;
+pub use bind_group::BindGroupBuilder;
+pub use gpu_uniform::GpuUniform;
+pub use texture_atlas::{ TextureAtlas, TextureAtlasBuilder, UvRect };
+pub use debug_lines::DebugLines;
+pub use gpu_timer::GpuTimer;
 
 pub mod pipeline;
+pub mod texture;
+pub mod post_process;
+pub mod screenshot;
+pub mod dynamic_uniform;
+pub mod instance;
+pub mod mesh_registry;
+pub mod shadow;
+pub mod bind_group;
+pub mod gpu_uniform;
+pub mod texture_atlas;
+pub mod debug_lines;
+pub mod gpu_timer;
+
+#[derive(Copy, Clone, Debug)]
+pub enum SurfaceConfigureError {
+    /// The adapter can't present to the surface
+    UnsupportedAdapter,
+    /// The surface and adapter has no format in common
+    NoCompatibleFormat,
+    /// The window framebuffer has a zero or negative size
+    InvalidSize,
+}
+
+impl std::fmt::Display for SurfaceConfigureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedAdapter =>
+                write!(f, "adapter can't present to the surface"),
+            Self::NoCompatibleFormat =>
+                write!(f, "no format supported by both the surface and the adapter"),
+            Self::InvalidSize =>
+                write!(f, "window has no size"),
+        }
+    }
+}
 
 pub struct WindowSurface {
     surface: wgpu::Surface,
@@ -22,21 +72,54 @@ impl WindowSurface {
     fn configure(&mut self,
                  device: &wgpu::Device,
                  adapter: &wgpu::Adapter,
-                 width: u32, height: u32)
+                 width: u32, height: u32,
+                 vsync: bool)
+        -> Result<(), SurfaceConfigureError>
     {
-        let surface_format =
-            self.surface.get_preferred_format(&adapter).unwrap();
+        if !adapter.is_surface_supported(&self.surface) {
+            return Err(SurfaceConfigureError::UnsupportedAdapter);
+        }
+
+        // NOTE(patrik): wgpu 0.12 doesn't expose the list of supported
+        // formats, the preferred format is the only one we know works
+        let surface_format = self.surface.get_preferred_format(&adapter)
+            .ok_or(SurfaceConfigureError::NoCompatibleFormat)?;
+
+        // NOTE(patrik): The shaders output linear colors so we want the
+        // surface to do the sRGB encoding for us
+        let surface_format = match surface_format {
+            wgpu::TextureFormat::Bgra8Unorm =>
+                wgpu::TextureFormat::Bgra8UnormSrgb,
+            wgpu::TextureFormat::Rgba8Unorm =>
+                wgpu::TextureFormat::Rgba8UnormSrgb,
+            format => format,
+        };
+
+        if !surface_format.describe().srgb {
+            log::warn!("Surface format {:?} is not sRGB, colors will look too dark",
+                       surface_format);
+        }
+
+        log::info!("Surface: Using format {:?}", surface_format);
 
         let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            // NOTE(patrik): COPY_SRC is needed for taking screenshots
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT |
+                   wgpu::TextureUsages::COPY_SRC,
             format: surface_format,
             width,
             height,
-            present_mode: wgpu::PresentMode::Fifo,
+            present_mode: if vsync {
+                wgpu::PresentMode::Fifo
+            } else {
+                wgpu::PresentMode::Immediate
+            },
         };
 
         self.surface.configure(&device, &config);
         self.config = Some(config);
+
+        Ok(())
     }
 
     pub fn get_render_target(&self)
@@ -48,6 +131,85 @@ impl WindowSurface {
     pub fn config(&self) -> &wgpu::SurfaceConfiguration {
         self.config.as_ref().expect("Surface not configured")
     }
+
+    /// Configure the surface with a new size, `width` and `height` needs
+    /// to be non-zero
+    pub fn resize(&mut self, gpu_device: &GpuDevice, width: u32, height: u32) {
+        let mut config = self.config().clone();
+        config.width = width;
+        config.height = height;
+
+        self.surface.configure(&gpu_device.device, &config);
+        self.config = Some(config);
+    }
+
+    /// Configure the surface again with the current config, used to
+    /// recover when the surface is lost or outdated
+    pub fn reconfigure(&self, gpu_device: &GpuDevice) {
+        self.surface.configure(&gpu_device.device, self.config());
+    }
+
+    /// Configure the surface with `present_mode`. wgpu can't list the
+    /// supported modes up front, a mode the surface doesn't support falls
+    /// back to `Fifo` (with a warning from wgpu)
+    pub fn set_present_mode(&mut self,
+                            gpu_device: &GpuDevice,
+                            present_mode: wgpu::PresentMode)
+    {
+        let mut config = self.config().clone();
+        config.present_mode = present_mode;
+
+        self.surface.configure(&gpu_device.device, &config);
+        self.config = Some(config);
+    }
+}
+
+/// Convert a color authored in sRGB space to a linear `wgpu::Color`, clear
+/// colors are written to the surface as linear values
+pub fn srgb_color(r: f64, g: f64, b: f64, a: f64) -> wgpu::Color {
+    fn to_linear(c: f64) -> f64 {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    wgpu::Color {
+        r: to_linear(r),
+        g: to_linear(g),
+        b: to_linear(b),
+        a,
+    }
+}
+
+/// Options used when creating the `GpuDevice`
+#[derive(Copy, Clone, Debug)]
+pub struct GpuOptions {
+    pub backends: wgpu::Backends,
+    pub power_preference: wgpu::PowerPreference,
+    pub vsync: bool,
+    /// Request `TIMESTAMP_QUERY` if the adapter supports it, used by
+    /// `GpuTimer`
+    pub timestamp_queries: bool,
+}
+
+impl Default for GpuOptions {
+    /// Uses all backends and prefers the high performance GPU unless
+    /// overridden with the `WGPU_BACKEND` and `WGPU_POWER_PREF`
+    /// environment variables
+    fn default() -> Self {
+        // NOTE(patrik): The default power preference picks the integrated
+        // GPU on a lot of dual GPU laptops
+        Self {
+            backends: wgpu::util::backend_bits_from_env()
+                .unwrap_or(wgpu::Backends::all()),
+            power_preference: wgpu::util::power_preference_from_env()
+                .unwrap_or(wgpu::PowerPreference::HighPerformance),
+            vsync: true,
+            timestamp_queries: false,
+        }
+    }
 }
 
 pub struct GpuDevice {
@@ -56,6 +218,9 @@ pub struct GpuDevice {
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
 
+    /// Used for the surfaces created after the device
+    vsync: bool,
+
     /*
     pub depth_texture: Texture,
     pub render_pipeline: wgpu::RenderPipeline,
@@ -65,101 +230,212 @@ pub struct GpuDevice {
     */
 }
 
+impl Drop for GpuDevice {
+    fn drop(&mut self) {
+        // NOTE(patrik): Let the GPU finish all the submitted work before
+        // the device gets released
+        self.wait_idle();
+    }
+}
+
 impl GpuDevice {
-    pub async fn new_for_window(window: &glfw::Window)
+    /// Name, backend and type of the selected adapter
+    pub fn adapter_info(&self) -> wgpu::AdapterInfo {
+        self.adapter.get_info()
+    }
+
+    /// Compile a WGSL shader, the compile errors are returned instead of
+    /// going to the uncaptured error handler (which panics)
+    pub fn create_wgsl_shader(&self, label: &str, source: &str)
+        -> Result<ShaderModule, wgpu::Error>
+    {
+        let handle = self.catch_errors(|| {
+            self.device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                label: Some(label),
+                source: wgpu::ShaderSource::Wgsl(source.into()),
+            })
+        })?;
+
+        Ok(ShaderModule::new(handle))
+    }
+
+    /// Run `f` inside a validation error scope, any validation error from
+    /// the resources created in `f` is returned instead of going to the
+    /// uncaptured error handler (which panics). The resources created are
+    /// invalid when an error is returned
+    pub fn catch_errors<F, T>(&self, f: F) -> Result<T, wgpu::Error>
+        where F: FnOnce() -> T
+    {
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+
+        let result = f();
+
+        match pollster::block_on(self.device.pop_error_scope()) {
+            Some(error) => Err(error),
+            None => Ok(result),
+        }
+    }
+
+    /// Block until all the submitted work on the device is done
+    pub fn wait_idle(&self) {
+        self.device.poll(wgpu::Maintain::Wait);
+    }
+
+    /// Create and configure a surface for `window`. The adapter might not
+    /// have been picked with this window in mind, so it can fail with
+    /// `UnsupportedAdapter`
+    pub fn create_surface(&self, window: &glfw::Window)
+        -> Result<WindowSurface, SurfaceConfigureError>
+    {
+        let surface = unsafe { self.instance.create_surface(window) };
+        let mut surface = WindowSurface::new(surface);
+
+        configure_window_surface(&mut surface, &self.device, &self.adapter,
+                                 window, self.vsync)?;
+
+        Ok(surface)
+    }
+
+    /// Create a device without a window, for rendering offscreen. Use
+    /// `create_surface` to present to a window later on
+    pub async fn new(options: GpuOptions) -> Option<Self> {
+        let _span = tracing::info_span!("gpu_init").entered();
+
+        let instance = wgpu::Instance::new(options.backends);
+        Self::from_instance(instance, None, options).await
+    }
+
+    /// Create a device and a surface for `window`, the adapter is picked
+    /// so it can present to the window
+    pub async fn new_for_window(window: &glfw::Window, options: GpuOptions)
         -> Option<(Self, WindowSurface)>
     {
-        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let _span = tracing::info_span!("gpu_init").entered();
+
+        let instance = wgpu::Instance::new(options.backends);
 
+        // NOTE(patrik): The surface is created before the device so only
+        // adapters that can present to it are considered, otherwise
+        // configuring the surface can fail later on
         let surface = unsafe { instance.create_surface(window) };
+        let gpu_device = Self::from_instance(instance, Some(&surface),
+                                             options).await?;
+
         let mut surface = WindowSurface::new(surface);
+        let result = configure_window_surface(&mut surface,
+                                              &gpu_device.device,
+                                              &gpu_device.adapter,
+                                              window, options.vsync);
+        if let Err(e) = result {
+            log::error!("Surface: Failed to configure: {}", e);
+            return None;
+        }
 
+        Some((gpu_device, surface))
+    }
+
+    async fn from_instance(instance: wgpu::Instance,
+                           compatible_surface: Option<&wgpu::Surface>,
+                           options: GpuOptions)
+        -> Option<Self>
+    {
         let adapter = instance.request_adapter(
             &wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
-                compatible_surface: None,
+                power_preference: options.power_preference,
+                compatible_surface,
                 force_fallback_adapter: false,
             },
-        ).await.unwrap();
+        ).await;
 
-        let desc = wgpu::DeviceDescriptor {
-            features: wgpu::Features::default(),
-            limits: wgpu::Limits::default(),
-            label: None,
+        let adapter = match adapter {
+            Some(adapter) => adapter,
+            None if compatible_surface.is_some() => {
+                log::error!("GPU: No adapter can present to the window \
+                             surface (backends: {:?})", options.backends);
+                return None;
+            }
+            None => {
+                log::error!("GPU: No adapter found (backends: {:?})",
+                            options.backends);
+                return None;
+            }
         };
 
-        let (device, queue) = adapter.request_device(&desc, None,)
-            .await
-            .expect("Failed to request device");
+        let info = adapter.get_info();
+        log::info!("GPU: Using adapter '{}' ({:?}, {:?})",
+                   info.name, info.backend, info.device_type);
 
+        let mut features = wgpu::Features::default();
+        if options.timestamp_queries {
+            if adapter.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+                features |= wgpu::Features::TIMESTAMP_QUERY;
+            } else {
+                log::warn!("GPU: Timestamp queries are not supported by the \
+                            adapter");
+            }
+        }
 
-        let (width, height) = window.get_framebuffer_size();
-        surface.configure(&device, &adapter,
-                          width.try_into().ok()?,
-                          height.try_into().ok()?);
-
-        /*
-        let shader = device.create_shader_module(&wgpu::include_wgsl!("../shader.wgsl"));
-
-
-        let width = surface.config().width;
-        let height = surface.config().height;
-        let depth_texture = Texture::create_depth_texture(&device, width, height, "Depth Texture");
-
+        let desc = wgpu::DeviceDescriptor {
+            features,
+            limits: wgpu::Limits::default(),
+            label: None,
+        };
 
-    */
+        let (device, queue) = match adapter.request_device(&desc, None).await {
+            Ok(result) => result,
+            Err(e) => {
+                log::error!("GPU: Failed to request a device from '{}': {}",
+                            info.name, e);
+                return None;
+            }
+        };
 
-        let gpu_device = Self {
+        Some(Self {
             instance,
             adapter,
             device,
             queue,
-        };
 
-        Some((gpu_device, surface))
+            vsync: options.vsync,
+        })
     }
 }
 
-pub struct Texture {
-    pub texture: wgpu::Texture,
-    pub view: wgpu::TextureView,
-}
-
-impl Texture {
-    const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
-
-    pub fn create_depth_texture(gpu_device: &GpuDevice, width: u32, height: u32)
-        -> Self
-    {
-        let size = wgpu::Extent3d {
-            width,
-            height,
-            depth_or_array_layers: 1,
-        };
-
-        let desc = wgpu::TextureDescriptor {
-            label: None,
-            size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: Self::DEPTH_FORMAT,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT |
-                   wgpu::TextureUsages::TEXTURE_BINDING,
-        };
+/// Configure `surface` with the framebuffer size of `window`
+fn configure_window_surface(surface: &mut WindowSurface,
+                            device: &wgpu::Device,
+                            adapter: &wgpu::Adapter,
+                            window: &glfw::Window,
+                            vsync: bool)
+    -> Result<(), SurfaceConfigureError>
+{
+    let (width, height) = window.get_framebuffer_size();
+    let width = u32::try_from(width).ok().filter(|&width| width > 0);
+    let height = u32::try_from(height).ok().filter(|&height| height > 0);
 
-        let texture = gpu_device.device.create_texture(&desc);
-
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    match (width, height) {
+        (Some(width), Some(height)) => {
+            surface.configure(device, adapter, width, height, vsync)
+        }
 
-        Self { texture, view }
+        _ => Err(SurfaceConfigureError::InvalidSize),
     }
 }
 
 pub struct Mesh {
     pub vertex_buffer: wgpu::Buffer,
-    pub index_buffer: wgpu::Buffer,
+    pub vertex_count: u32,
+
+    /// None for meshes drawn straight from the vertices
+    pub index_buffer: Option<wgpu::Buffer>,
     pub index_count: u32,
+
+    /// Number of vertices and indices the buffers have room for, only
+    /// used by dynamic meshes
+    vertex_capacity: u32,
+    index_capacity: u32,
+    /// The buffers can be written to with `update`
+    dynamic: bool,
 }
 
 impl Mesh {
@@ -168,6 +444,7 @@ impl Mesh {
                      index_buffer: &Vec<u32>)
         -> Self
     {
+        let vertex_count = vertex_buffer.len();
         let index_count = index_buffer.len();
 
         let vertex_buffer = gpu_device.device.create_buffer_init(
@@ -186,20 +463,252 @@ impl Mesh {
             }
         );
 
+        Self {
+            vertex_buffer,
+            vertex_count: vertex_count.try_into().unwrap(),
+
+            index_buffer: Some(index_buffer),
+            index_count: index_count.try_into().unwrap(),
+
+            vertex_capacity: vertex_count.try_into().unwrap(),
+            index_capacity: index_count.try_into().unwrap(),
+            dynamic: false,
+        }
+    }
+
+    /// Box centered on the origin with flat shaded faces, wound like the
+    /// map geometry (clockwise seen from the outside)
+    pub fn cuboid(gpu_device: &GpuDevice, half_extents: Vec3, color: [f32; 4])
+        -> Self
+    {
+        // NOTE(patrik): Each face is spanned by `u` and `v` with
+        // `u x v` pointing out of the box
+        let faces = [
+            (Vec3::X, Vec3::Y, Vec3::Z),
+            (-Vec3::X, Vec3::Z, Vec3::Y),
+            (Vec3::Y, Vec3::Z, Vec3::X),
+            (-Vec3::Y, Vec3::X, Vec3::Z),
+            (Vec3::Z, Vec3::X, Vec3::Y),
+            (-Vec3::Z, Vec3::Y, Vec3::X),
+        ];
+
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for (normal, u, v) in faces {
+            let center = normal * half_extents;
+            let u = u * half_extents;
+            let v = v * half_extents;
+
+            let base = vertices.len() as u32;
+            for corner in [center - u - v, center + u - v,
+                           center + u + v, center - u + v]
+            {
+                vertices.push(Vertex {
+                    position: corner.to_array(),
+                    color,
+                    normal: normal.to_array(),
+                });
+            }
+
+            indices.extend([base, base + 1, base + 2, base, base + 2, base + 3]);
+        }
+
+        Self::from_data(gpu_device, &vertices, &indices)
+    }
+
+    /// Empty mesh with room for `vertex_capacity` vertices and
+    /// `index_capacity` indices, filled in with `update`. An index capacity
+    /// of 0 starts out as a non-indexed mesh
+    pub fn dynamic(gpu_device: &GpuDevice,
+                   vertex_capacity: u32,
+                   index_capacity: u32)
+        -> Self
+    {
+        let vertex_buffer = create_dynamic_buffer(
+            gpu_device, "Dynamic Vertex Buffer", wgpu::BufferUsages::VERTEX,
+            vertex_buffer_size(vertex_capacity));
+
+        let index_buffer = if index_capacity > 0 {
+            Some(create_dynamic_buffer(
+                gpu_device, "Dynamic Index Buffer", wgpu::BufferUsages::INDEX,
+                index_buffer_size(index_capacity)))
+        } else {
+            None
+        };
 
         Self {
             vertex_buffer,
+            vertex_count: 0,
+
             index_buffer,
-            index_count: index_count.try_into().unwrap(),
+            index_count: 0,
+
+            vertex_capacity,
+            index_capacity,
+            dynamic: true,
+        }
+    }
+
+    /// Replace the content of the mesh, the buffers are reallocated if the
+    /// data doesn't fit (or the mesh wasn't created with `dynamic`). Empty
+    /// `indices` makes the mesh non-indexed
+    pub fn update(&mut self,
+                  gpu_device: &GpuDevice,
+                  vertices: &[Vertex],
+                  indices: &[u32])
+    {
+        let vertex_count: u32 = vertices.len().try_into().unwrap();
+        let index_count: u32 = indices.len().try_into().unwrap();
+
+        if !self.dynamic || vertex_count > self.vertex_capacity {
+            self.vertex_capacity = vertex_count.next_power_of_two();
+            self.vertex_buffer = create_dynamic_buffer(
+                gpu_device, "Dynamic Vertex Buffer",
+                wgpu::BufferUsages::VERTEX,
+                vertex_buffer_size(self.vertex_capacity));
+        }
+
+        if index_count == 0 {
+            self.index_buffer = None;
+            self.index_capacity = 0;
+        } else if !self.dynamic || self.index_buffer.is_none() ||
+                  index_count > self.index_capacity
+        {
+            self.index_capacity = index_count.next_power_of_two();
+            self.index_buffer = Some(create_dynamic_buffer(
+                gpu_device, "Dynamic Index Buffer",
+                wgpu::BufferUsages::INDEX,
+                index_buffer_size(self.index_capacity)));
+        }
+
+        self.dynamic = true;
+
+        if !vertices.is_empty() {
+            gpu_device.queue.write_buffer(&self.vertex_buffer, 0,
+                                          bytemuck::cast_slice(vertices));
+        }
+
+        if let Some(index_buffer) = &self.index_buffer {
+            gpu_device.queue.write_buffer(index_buffer, 0,
+                                          bytemuck::cast_slice(indices));
+        }
+
+        self.vertex_count = vertex_count;
+        self.index_count = index_count;
+    }
+
+    /// Mesh without an index buffer, every three vertices is a triangle.
+    /// Used for generated geometry like debug lines
+    pub fn from_vertices(gpu_device: &GpuDevice, vertices: &[Vertex]) -> Self {
+        let vertex_buffer = gpu_device.device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Vertex Buffer"),
+                contents: bytemuck::cast_slice(vertices),
+                usage: wgpu::BufferUsages::VERTEX,
+            }
+        );
+
+        Self {
+            vertex_buffer,
+            vertex_count: vertices.len().try_into().unwrap(),
+
+            index_buffer: None,
+            index_count: 0,
+
+            vertex_capacity: vertices.len().try_into().unwrap(),
+            index_capacity: 0,
+            dynamic: false,
+        }
+    }
+
+    pub fn is_indexed(&self) -> bool {
+        self.index_buffer.is_some()
+    }
+
+    /// Number of triangles drawn by `draw`, assuming a triangle list
+    pub fn triangle_count(&self) -> u32 {
+        if self.is_indexed() {
+            self.index_count / 3
+        } else {
+            self.vertex_count / 3
+        }
+    }
+
+    /// Bind the buffers and draw the mesh once, with or without the index
+    /// buffer
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+
+        match &self.index_buffer {
+            Some(index_buffer) => {
+                render_pass.set_index_buffer(index_buffer.slice(..),
+                                             wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..self.index_count, 0, 0..1);
+            }
+
+            None => render_pass.draw(0..self.vertex_count, 0..1),
         }
     }
+
+    /// Draw the mesh once for every instance of `instances` in `range`,
+    /// needs a pipeline built with `instanced(true)`
+    pub fn draw_instanced<'a>(&'a self,
+                              render_pass: &mut wgpu::RenderPass<'a>,
+                              instances: &'a InstanceBuffer,
+                              range: Range<u32>)
+    {
+        debug_assert!(range.end <= instances.count());
+
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_vertex_buffer(1, instances.buffer().slice(..));
+
+        match &self.index_buffer {
+            Some(index_buffer) => {
+                render_pass.set_index_buffer(index_buffer.slice(..),
+                                             wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..self.index_count, 0, range);
+            }
+
+            None => render_pass.draw(0..self.vertex_count, range),
+        }
+    }
+}
+
+fn vertex_buffer_size(capacity: u32) -> wgpu::BufferAddress {
+    // NOTE(patrik): Zero sized buffers can't be bound
+    (capacity.max(1) as usize * std::mem::size_of::<Vertex>())
+        as wgpu::BufferAddress
+}
+
+fn index_buffer_size(capacity: u32) -> wgpu::BufferAddress {
+    (capacity.max(1) as usize * std::mem::size_of::<u32>())
+        as wgpu::BufferAddress
+}
+
+fn create_dynamic_buffer(gpu_device: &GpuDevice,
+                         label: &str,
+                         usage: wgpu::BufferUsages,
+                         size: wgpu::BufferAddress)
+    -> wgpu::Buffer
+{
+    gpu_device.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size,
+        usage: usage | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
     pub position: [f32; 3],
-    pub color: [f32; 3],
+
+    /// Vertex color in sRGB space, converted to linear in the shader. The
+    /// alpha is linear
+    pub color: [f32; 4],
+
+    pub normal: [f32; 3],
 }
 
 impl Vertex {
@@ -217,6 +726,12 @@ impl Vertex {
                 wgpu::VertexAttribute {
                     offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
                     shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 7]>() as wgpu::BufferAddress,
+                    shader_location: 2,
                     format: wgpu::VertexFormat::Float32x3,
                 }
             ]
@@ -224,12 +739,57 @@ impl Vertex {
     }
 }
 
+/// Max number of point lights affecting the scene at once, needs to match
+/// `MAX_POINT_LIGHTS` in shader.wgsl and sprite.wgsl
+pub const MAX_POINT_LIGHTS: usize = 4;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PointLight {
+    /// World position in xyz and the radius in w
+    position: [f32; 4],
+    /// Linear color in rgb, w is padding
+    color: [f32; 4],
+}
+
+impl PointLight {
+    /// Light at `position` (world units) that fades out to nothing at
+    /// `radius` world units away
+    pub fn new(position: Vec3, color: Vec3, radius: f32) -> Self {
+        Self {
+            position: position.extend(radius).to_array(),
+            color: color.extend(0.0).to_array(),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct UniformBuffer {
     projection_matrix: [f32; 4 * 4],
     view_matrix: [f32; 4 * 4],
     model_matrix: [f32; 4 * 4],
+
+    /// Linear fog color in xyz and the fog density in w
+    fog: [f32; 4],
+    /// Light level in x, 1 in y if the sun casts shadows, the rest is
+    /// padding
+    light: [f32; 4],
+
+    point_lights: [PointLight; MAX_POINT_LIGHTS],
+    /// Number of used point lights in x, the rest is padding
+    point_light_count: [u32; 4],
+
+    /// View projection of the directional light, used for the shadow map
+    light_space_matrix: [f32; 4 * 4],
+    /// Direction the directional light shines in xyz and the intensity in
+    /// w, an intensity of 0 turns the light off
+    sun: [f32; 4],
+
+    /// Gamma in x and exposure in y, the rest is padding
+    display: [f32; 4],
+    /// `DebugMode` in x, the rest is padding
+    debug_mode: [u32; 4],
 }
 
 impl UniformBuffer {
@@ -238,6 +798,18 @@ impl UniformBuffer {
             projection_matrix: [0.0; 4 * 4],
             view_matrix: [0.0; 4 * 4],
             model_matrix: [0.0; 4 * 4],
+
+            fog: [0.0; 4],
+            light: [1.0, 1.0, 0.0, 0.0],
+
+            point_lights: [PointLight::default(); MAX_POINT_LIGHTS],
+            point_light_count: [0; 4],
+
+            light_space_matrix: Mat4::IDENTITY.to_cols_array(),
+            sun: [0.0, -1.0, 0.0, 0.0],
+
+            display: [DEFAULT_GAMMA, DEFAULT_EXPOSURE, 0.0, 0.0],
+            debug_mode: [DebugMode::Lit as u32, 0, 0, 0],
         };
 
         result.update(projection_matrix, view_matrix, model_matrix);
@@ -251,6 +823,10 @@ impl UniformBuffer {
         model_matrix.write_cols_to_slice(&mut self.model_matrix);
     }
 
+    pub fn update_projection(&mut self, projection: Mat4) {
+        projection.write_cols_to_slice(&mut self.projection_matrix);
+    }
+
     pub fn update_view(&mut self, view: Mat4) {
         view.write_cols_to_slice(&mut self.view_matrix);
     }
@@ -258,4 +834,231 @@ impl UniformBuffer {
     pub fn update_model(&mut self, model: Mat4) {
         model.write_cols_to_slice(&mut self.model_matrix);
     }
+
+    pub fn update_environment(&mut self,
+                              fog_color: Vec3,
+                              fog_density: f32,
+                              light_level: f32)
+    {
+        self.fog = fog_color.extend(fog_density).to_array();
+        self.light[0] = light_level;
+    }
+
+    /// With shadows disabled the sun lights everything it faces without
+    /// reading the shadow map
+    pub fn update_shadows(&mut self, enabled: bool) {
+        self.light[1] = if enabled { 1.0 } else { 0.0 };
+    }
+
+    pub fn update_sun(&mut self,
+                      direction: Vec3,
+                      intensity: f32,
+                      light_space_matrix: Mat4)
+    {
+        self.sun = direction.normalize_or_zero().extend(intensity).to_array();
+        light_space_matrix.write_cols_to_slice(&mut self.light_space_matrix);
+    }
+
+    /// Brightness adjustment applied as the last step of the scene shader,
+    /// a gamma of `DEFAULT_GAMMA` leaves the colors as they are
+    pub fn update_display(&mut self, gamma: f32, exposure: f32) {
+        self.display[0] = gamma;
+        self.display[1] = exposure;
+    }
+
+    pub fn update_debug_mode(&mut self, mode: DebugMode) {
+        self.debug_mode[0] = mode as u32;
+    }
+
+    /// Set the point lights for the frame, only the first
+    /// `MAX_POINT_LIGHTS` are used
+    pub fn update_point_lights(&mut self, lights: &[PointLight]) {
+        let count = lights.len().min(MAX_POINT_LIGHTS);
+        self.point_lights[..count].copy_from_slice(&lights[..count]);
+        self.point_light_count[0] = count as u32;
+    }
+}
+
+/// Gamma the display is assumed to have, the sRGB surface already encodes
+/// for it
+pub const DEFAULT_GAMMA: f32 = 2.2;
+pub const DEFAULT_EXPOSURE: f32 = 1.0;
+
+/// What the scene shader outputs instead of the lit color, the values
+/// needs to match the ones checked in `shader.wgsl`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum DebugMode {
+    Lit = 0,
+    /// World space normals as RGB
+    Normals = 1,
+    /// Distance from the camera in grayscale, white is far away
+    Depth = 2,
+    /// Vertex color times the material color, without lighting or fog
+    Albedo = 3,
+}
+
+impl DebugMode {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Lit => Self::Normals,
+            Self::Normals => Self::Depth,
+            Self::Depth => Self::Albedo,
+            Self::Albedo => Self::Lit,
+        }
+    }
+}
+
+/// How an object is shaded
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Material {
+    /// Unlit objects ignore the sector light level and fog, used for
+    /// things like debug geometry and emissive surfaces
+    pub lit: bool,
+    /// Linear color multiplied with the vertex colors
+    pub base_color: [f32; 4],
+    /// Fragments with an alpha below this are discarded, 0 turns the alpha
+    /// test off. Cutouts keep the depth writes so they don't need sorting
+    pub alpha_cutoff: f32,
+}
+
+impl Material {
+    pub const UNLIT: Self = Self {
+        lit: false,
+        base_color: [1.0; 4],
+        alpha_cutoff: 0.0,
+    };
+
+    /// Hard edged transparency for sprites and decals
+    pub const CUTOUT: Self = Self {
+        lit: true,
+        base_color: [1.0; 4],
+        alpha_cutoff: 0.5,
+    };
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            lit: true,
+            base_color: [1.0; 4],
+            alpha_cutoff: 0.0,
+        }
+    }
+}
+
+/// Per object data, stored in a `DynamicUniformBuffer` and selected with a
+/// dynamic offset for each draw
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ModelUniform {
+    model_matrix: [f32; 4 * 4],
+
+    /// Inverse transpose of the upper 3x3 of the model matrix, the columns
+    /// are padded to vec4 to match the WGSL `mat3x3` uniform layout
+    normal_matrix: [[f32; 4]; 3],
+
+    /// Linear color multiplied with the vertex colors
+    base_color: [f32; 4],
+    /// 1 if the object is affected by the lighting and fog
+    lit: u32,
+    /// Fragments with an alpha below this are discarded
+    alpha_cutoff: f32,
+    _padding: [u32; 2],
+}
+
+impl ModelUniform {
+    pub fn new(model_matrix: Mat4) -> Self {
+        Self::with_material(model_matrix, &Material::default())
+    }
+
+    pub fn with_material(model_matrix: Mat4, material: &Material) -> Self {
+        let mut result = Self {
+            model_matrix: [0.0; 4 * 4],
+            normal_matrix: [[0.0; 4]; 3],
+
+            base_color: material.base_color,
+            lit: material.lit as u32,
+            alpha_cutoff: material.alpha_cutoff,
+            _padding: [0; 2],
+        };
+
+        result.update_model(model_matrix);
+
+        result
+    }
+
+    /// Update the model matrix and recompute the normal matrix from it
+    pub fn update_model(&mut self, model_matrix: Mat4) {
+        model_matrix.write_cols_to_slice(&mut self.model_matrix);
+
+        let normal_matrix =
+            Mat3::from_mat4(model_matrix).inverse().transpose();
+        self.normal_matrix = [
+            normal_matrix.x_axis.extend(0.0).to_array(),
+            normal_matrix.y_axis.extend(0.0).to_array(),
+            normal_matrix.z_axis.extend(0.0).to_array(),
+        ];
+    }
+}
+
+/// Headless device for the tests, the tests using it are ignored by
+/// default since they need a GPU adapter to run on
+#[cfg(test)]
+pub(crate) fn test_device() -> GpuDevice {
+    pollster::block_on(GpuDevice::new(GpuOptions::default()))
+        .expect("Failed to create a GPU device for the test")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a uniform buffer at `group(0)`
+    const UNIFORM_SHADER: &str = "
+struct Color {
+    color: vec4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> color: Color;
+
+[[stage(vertex)]]
+fn vs_main([[location(0)]] position: vec3<f32>) -> [[builtin(position)]] vec4<f32> {
+    return vec4<f32>(position, 1.0);
+}
+
+[[stage(fragment)]]
+fn fs_main() -> [[location(0)]] vec4<f32> {
+    return color.color;
+}
+";
+
+    #[test]
+    #[ignore = "needs a GPU adapter, run with --ignored"]
+    fn catch_errors_returns_layout_mismatch() {
+        let gpu_device = test_device();
+
+        let shader = gpu_device.create_wgsl_shader("uniform", UNIFORM_SHADER)
+            .unwrap();
+
+        // NOTE(patrik): The layout is missing the bind group the shader
+        // reads from
+        let layout = PipelineLayout::builder().build(&gpu_device);
+        let result = RenderPipeline::builder()
+            .vertex_shader(&shader)
+            .fragment_shader(&shader)
+            .build(&gpu_device, wgpu::TextureFormat::Rgba8Unorm, &layout);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter, run with --ignored"]
+    fn catch_errors_returns_shader_errors() {
+        let gpu_device = test_device();
+
+        let result = gpu_device.create_wgsl_shader("invalid", "fn main( {");
+        assert!(result.is_err());
+    }
 }
@@ -1,13 +1,47 @@
-use super::{ Texture, Vertex, WindowSurface, GpuDevice };
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use super::{ Texture, Vertex, InstanceRaw, GpuDevice };
+
+static NEXT_RESOURCE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Id identifying a shader module or a pipeline layout in the pipeline
+/// cache key. Unlike the address it is never reused after the resource
+/// gets dropped
+fn next_resource_id() -> u64 {
+    NEXT_RESOURCE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub struct ShaderModule {
+    id: u64,
+    handle: wgpu::ShaderModule,
+}
+
+impl ShaderModule {
+    pub fn new(handle: wgpu::ShaderModule) -> Self {
+        Self {
+            id: next_resource_id(),
+            handle,
+        }
+    }
+
+    pub fn handle(&self) -> &wgpu::ShaderModule {
+        &self.handle
+    }
+}
 
 pub struct PipelineLayoutBuilder<'a> {
     bind_group_layouts: Vec<&'a wgpu::BindGroupLayout>,
+    push_constant_ranges: Vec<wgpu::PushConstantRange>,
 }
 
 impl<'a> PipelineLayoutBuilder<'a> {
     fn new() -> Self {
         Self {
             bind_group_layouts: Vec::new(),
+            push_constant_ranges: Vec::new(),
         }
     }
 
@@ -19,12 +53,24 @@ impl<'a> PipelineLayoutBuilder<'a> {
         self
     }
 
+    pub fn push_constant_range(mut self,
+                               stages: wgpu::ShaderStages,
+                               range: Range<u32>)
+        -> Self
+    {
+        self.push_constant_ranges.push(wgpu::PushConstantRange {
+            stages,
+            range,
+        });
+        self
+    }
+
     pub fn build(&self, gpu_device: &GpuDevice) -> PipelineLayout {
         let handle = gpu_device.device.create_pipeline_layout(
             &wgpu::PipelineLayoutDescriptor {
                 label: None,
                 bind_group_layouts: &self.bind_group_layouts,
-                push_constant_ranges: &[],
+                push_constant_ranges: &self.push_constant_ranges,
             }
         );
 
@@ -33,12 +79,16 @@ impl<'a> PipelineLayoutBuilder<'a> {
 }
 
 pub struct PipelineLayout {
+    id: u64,
     handle: wgpu::PipelineLayout,
 }
 
 impl PipelineLayout {
     fn new(handle: wgpu::PipelineLayout) -> Self {
-        Self { handle }
+        Self {
+            id: next_resource_id(),
+            handle,
+        }
     }
 
     pub fn handle(&self) -> &wgpu::PipelineLayout {
@@ -51,9 +101,16 @@ impl PipelineLayout {
 }
 
 pub struct RenderPipelineBuilder<'a> {
-    vertex_shader: Option<&'a wgpu::ShaderModule>,
-    fragment_shader: Option<&'a wgpu::ShaderModule>,
+    vertex_shader: Option<&'a ShaderModule>,
+    fragment_shader: Option<&'a ShaderModule>,
     use_depth_stencil: bool,
+    depth_write: bool,
+    depth_compare: wgpu::CompareFunction,
+    stencil: wgpu::StencilState,
+    stencil_reference: u32,
+    color_writes: wgpu::ColorWrites,
+    blend: wgpu::BlendState,
+    instanced: bool,
 
     topology: wgpu::PrimitiveTopology,
     front_face: wgpu::FrontFace,
@@ -67,20 +124,30 @@ impl<'a> RenderPipelineBuilder<'a> {
             vertex_shader: None,
             fragment_shader: None,
             use_depth_stencil: false,
+            depth_write: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            stencil_reference: 0,
+            color_writes: wgpu::ColorWrites::ALL,
+            blend: wgpu::BlendState::REPLACE,
+            instanced: false,
 
             topology: wgpu::PrimitiveTopology::TriangleList,
+            // NOTE(patrik): The maps from the mime exporter are wound
+            // clockwise when seen from the visible side, cycle the cull
+            // mode in game (C) to check for inverted geometry
             front_face: wgpu::FrontFace::Cw,
             cull_mode: None,
             polygon_mode: wgpu::PolygonMode::Fill,
         }
     }
 
-    pub fn vertex_shader(mut self, shader: &'a wgpu::ShaderModule) -> Self {
+    pub fn vertex_shader(mut self, shader: &'a ShaderModule) -> Self {
         self.vertex_shader = Some(shader);
         self
     }
 
-    pub fn fragment_shader(mut self, shader: &'a wgpu::ShaderModule) -> Self {
+    pub fn fragment_shader(mut self, shader: &'a ShaderModule) -> Self {
         self.fragment_shader = Some(shader);
         self
     }
@@ -90,6 +157,58 @@ impl<'a> RenderPipelineBuilder<'a> {
         self
     }
 
+    /// Write the depth of the fragments to the depth buffer, defaults to
+    /// true. Only used with `depth_stencil` enabled
+    pub fn depth_write(mut self, depth_write: bool) -> Self {
+        self.depth_write = depth_write;
+        self
+    }
+
+    /// Depth test against the depth buffer, defaults to `Less`. A skybox
+    /// drawn at the far plane wants `LessEqual`. Only used with
+    /// `depth_stencil` enabled
+    pub fn depth_compare(mut self, compare: wgpu::CompareFunction) -> Self {
+        self.depth_compare = compare;
+        self
+    }
+
+    /// Stencil test and operations for the front and back faces, defaults
+    /// to no stencil test. Only used with `depth_stencil` enabled
+    pub fn stencil(mut self, stencil: wgpu::StencilState) -> Self {
+        self.stencil = stencil;
+        self
+    }
+
+    /// Value the stencil buffer is compared against and written with,
+    /// defaults to 0. wgpu sets it on the render pass so it's stored in
+    /// the pipeline, see `RenderPipeline::stencil_reference`
+    pub fn stencil_reference(mut self, reference: u32) -> Self {
+        self.stencil_reference = reference;
+        self
+    }
+
+    /// Channels written to the color target, `ColorWrites::empty()` gives
+    /// a depth only pipeline
+    pub fn color_writes(mut self, color_writes: wgpu::ColorWrites) -> Self {
+        self.color_writes = color_writes;
+        self
+    }
+
+    /// How the fragments are blended with the color target, defaults to
+    /// `REPLACE`. Transparent geometry wants `ALPHA_BLENDING` and usually
+    /// no depth writes
+    pub fn blend(mut self, blend: wgpu::BlendState) -> Self {
+        self.blend = blend;
+        self
+    }
+
+    /// Read the model matrix from a per instance vertex buffer in slot 1,
+    /// uses the `vs_instanced` entry point instead of `vs_main`
+    pub fn instanced(mut self, instanced: bool) -> Self {
+        self.instanced = instanced;
+        self
+    }
+
     pub fn topology(mut self, topology: wgpu::PrimitiveTopology) -> Self {
         self.topology = topology;
         self
@@ -110,42 +229,58 @@ impl<'a> RenderPipelineBuilder<'a> {
         self
     }
 
+    /// Build the pipeline, validation errors (like the shader not matching
+    /// the layout) are returned instead of aborting
     pub fn build(&self,
                  gpu_device: &GpuDevice,
-                 surface: &WindowSurface,
+                 format: wgpu::TextureFormat,
                  pipeline_layout: &PipelineLayout)
-        -> RenderPipeline
+        -> Result<RenderPipeline, wgpu::Error>
     {
         let depth_stencil = if self.use_depth_stencil {
             Some(wgpu::DepthStencilState {
                 format: Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
+                depth_write_enabled: self.depth_write,
+                depth_compare: self.depth_compare,
+                stencil: self.stencil.clone(),
                 bias: wgpu::DepthBiasState::default(),
             })
         } else {
+            if !self.depth_write ||
+               self.depth_compare != wgpu::CompareFunction::Less
+            {
+                log::warn!("Pipeline: Depth options set without a depth \
+                            stencil, they are ignored");
+            }
+
             None
         };
 
-        let handle = gpu_device.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        let (entry_point, buffers) = if self.instanced {
+            ("vs_instanced", vec![Vertex::desc(), InstanceRaw::desc()])
+        } else {
+            ("vs_main", vec![Vertex::desc()])
+        };
+
+        let handle = gpu_device.catch_errors(|| gpu_device.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: None,
             layout: Some(pipeline_layout.handle()),
 
             vertex: wgpu::VertexState {
-                module: self.vertex_shader.expect("No vertex shader selected"),
-                entry_point: "vs_main",
-                buffers: &[Vertex::desc()],
+                module: self.vertex_shader
+                    .expect("No vertex shader selected").handle(),
+                entry_point,
+                buffers: &buffers,
             },
 
             fragment: Some(wgpu::FragmentState {
                 module: self.fragment_shader
-                    .expect("No fragment shader selected"),
+                    .expect("No fragment shader selected").handle(),
                 entry_point: "fs_main",
                 targets: &[wgpu::ColorTargetState {
-                    format: surface.config().format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
+                    format,
+                    blend: Some(self.blend),
+                    write_mask: self.color_writes,
                 }],
             }),
 
@@ -168,19 +303,163 @@ impl<'a> RenderPipelineBuilder<'a> {
             },
 
             multiview: None,
+        }))?;
+
+        Ok(RenderPipeline::new(handle, self.stencil_reference))
+    }
+
+    /// Same as `build` but returns the pipeline from `cache` if an
+    /// identical one has already been built
+    pub fn build_cached(&self,
+                        cache: &mut PipelineCache,
+                        gpu_device: &GpuDevice,
+                        format: wgpu::TextureFormat,
+                        pipeline_layout: &PipelineLayout)
+        -> Result<Arc<RenderPipeline>, wgpu::Error>
+    {
+        let key = self.key(format, pipeline_layout);
+
+        if let Some(pipeline) = cache.pipelines.get(&key) {
+            return Ok(pipeline.clone());
+        }
+
+        let pipeline = Arc::new(self.build(gpu_device, format,
+                                           pipeline_layout)?);
+        cache.pipelines.insert(key, pipeline.clone());
+
+        Ok(pipeline)
+    }
+
+    fn key(&self,
+           format: wgpu::TextureFormat,
+           pipeline_layout: &PipelineLayout)
+        -> PipelineKey
+    {
+        PipelineKey {
+            vertex_shader: self.vertex_shader.map(|s| s.id),
+            fragment_shader: self.fragment_shader.map(|s| s.id),
+            pipeline_layout: pipeline_layout.id,
+            color_format: format,
+            use_depth_stencil: self.use_depth_stencil,
+            depth_write: self.depth_write,
+            depth_compare: self.depth_compare,
+            stencil: self.stencil.clone(),
+            stencil_reference: self.stencil_reference,
+            color_writes: self.color_writes,
+            blend: self.blend,
+            instanced: self.instanced,
+
+            topology: self.topology,
+            front_face: self.front_face,
+            cull_mode: self.cull_mode,
+            polygon_mode: self.polygon_mode,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct PipelineKey {
+    vertex_shader: Option<u64>,
+    fragment_shader: Option<u64>,
+    pipeline_layout: u64,
+    color_format: wgpu::TextureFormat,
+    use_depth_stencil: bool,
+    depth_write: bool,
+    depth_compare: wgpu::CompareFunction,
+    stencil: wgpu::StencilState,
+    stencil_reference: u32,
+    color_writes: wgpu::ColorWrites,
+    blend: wgpu::BlendState,
+    instanced: bool,
+
+    topology: wgpu::PrimitiveTopology,
+    front_face: wgpu::FrontFace,
+    cull_mode: Option<wgpu::Face>,
+    polygon_mode: wgpu::PolygonMode,
+}
+
+/// Stencil state that writes the reference value wherever a fragment
+/// passes the depth test, used for marking the pixels covered by a portal
+pub fn stencil_write_reference() -> wgpu::StencilState {
+    let face = wgpu::StencilFaceState {
+        compare: wgpu::CompareFunction::Always,
+        fail_op: wgpu::StencilOperation::Keep,
+        depth_fail_op: wgpu::StencilOperation::Keep,
+        pass_op: wgpu::StencilOperation::Replace,
+    };
+
+    wgpu::StencilState {
+        front: face,
+        back: face,
+        read_mask: 0xff,
+        write_mask: 0xff,
+    }
+}
+
+/// Stencil state that only draws where the stencil buffer is equal to the
+/// reference value, used for drawing the scene behind a portal
+pub fn stencil_equal_reference() -> wgpu::StencilState {
+    let face = wgpu::StencilFaceState {
+        compare: wgpu::CompareFunction::Equal,
+        fail_op: wgpu::StencilOperation::Keep,
+        depth_fail_op: wgpu::StencilOperation::Keep,
+        pass_op: wgpu::StencilOperation::Keep,
+    };
+
+    wgpu::StencilState {
+        front: face,
+        back: face,
+        read_mask: 0xff,
+        write_mask: 0,
+    }
+}
+
+/// Cache of render pipelines keyed on the builder configuration, so
+/// identical pipelines only get created once
+#[derive(Default)]
+pub struct PipelineCache {
+    pipelines: HashMap<PipelineKey, Arc<RenderPipeline>>,
+}
+
+impl PipelineCache {
+    pub fn new() -> Self {
+        Self {
+            pipelines: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pipelines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pipelines.is_empty()
+    }
+
+    /// Drop every pipeline using `shader`, the cached pipelines would
+    /// otherwise keep the old shader alive after it has been replaced
+    pub fn evict_shader(&mut self, shader: &ShaderModule) {
+        let id = Some(shader.id);
+        self.pipelines.retain(|key, _| {
+            key.vertex_shader != id && key.fragment_shader != id
         });
-        RenderPipeline::new(handle)
+    }
+
+    pub fn clear(&mut self) {
+        self.pipelines.clear();
     }
 }
 
 pub struct RenderPipeline {
     handle: wgpu::RenderPipeline,
+    stencil_reference: u32,
 }
 
 impl RenderPipeline {
-    fn new(handle: wgpu::RenderPipeline) -> Self {
+    fn new(handle: wgpu::RenderPipeline, stencil_reference: u32) -> Self {
         Self {
-            handle
+            handle,
+            stencil_reference,
         }
     }
 
@@ -188,6 +467,12 @@ impl RenderPipeline {
         &self.handle
     }
 
+    /// Pass to `wgpu::RenderPass::set_stencil_reference` after setting the
+    /// pipeline
+    pub fn stencil_reference(&self) -> u32 {
+        self.stencil_reference
+    }
+
     pub fn builder<'a>() -> RenderPipelineBuilder<'a> {
         RenderPipelineBuilder::new()
     }
@@ -195,3 +480,72 @@ impl RenderPipeline {
 
 // TODO(patrik): Add compute
 pub struct ComputePipeline {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::test_device;
+
+    const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+    const SHADER: &str = "
+[[stage(vertex)]]
+fn vs_main([[location(0)]] position: vec3<f32>) -> [[builtin(position)]] vec4<f32> {
+    return vec4<f32>(position, 1.0);
+}
+
+[[stage(fragment)]]
+fn fs_main() -> [[location(0)]] vec4<f32> {
+    return vec4<f32>(1.0, 1.0, 1.0, 1.0);
+}
+";
+
+    #[test]
+    #[ignore = "needs a GPU adapter, run with --ignored"]
+    fn build_cached_hits_and_misses() {
+        let gpu_device = test_device();
+
+        let shader = gpu_device.create_wgsl_shader("test", SHADER).unwrap();
+        let layout = PipelineLayout::builder().build(&gpu_device);
+        let mut cache = PipelineCache::new();
+
+        fn builder(shader: &ShaderModule) -> RenderPipelineBuilder<'_> {
+            RenderPipeline::builder()
+                .vertex_shader(shader)
+                .fragment_shader(shader)
+        }
+
+        let first = builder(&shader)
+            .build_cached(&mut cache, &gpu_device, FORMAT, &layout).unwrap();
+        let second = builder(&shader)
+            .build_cached(&mut cache, &gpu_device, FORMAT, &layout).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(cache.len(), 1);
+
+        let culled = builder(&shader)
+            .cull_mode(wgpu::Face::Back)
+            .build_cached(&mut cache, &gpu_device, FORMAT, &layout).unwrap();
+        assert!(!Arc::ptr_eq(&first, &culled));
+        assert_eq!(cache.len(), 2);
+
+        let masked = builder(&shader)
+            .depth_stencil(true)
+            .stencil(stencil_equal_reference())
+            .stencil_reference(1)
+            .build_cached(&mut cache, &gpu_device, FORMAT, &layout).unwrap();
+        assert_eq!(masked.stencil_reference(), 1);
+        assert_eq!(cache.len(), 3);
+
+        cache.evict_shader(&shader);
+        assert!(cache.is_empty());
+
+        // NOTE(patrik): Same source but a different module, the new module
+        // can end up at the address of the dropped one
+        drop(shader);
+        let reloaded = gpu_device.create_wgsl_shader("test", SHADER).unwrap();
+        let after_reload = builder(&reloaded)
+            .build_cached(&mut cache, &gpu_device, FORMAT, &layout).unwrap();
+        assert!(!Arc::ptr_eq(&first, &after_reload));
+        assert_eq!(cache.len(), 1);
+    }
+}
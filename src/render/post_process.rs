@@ -0,0 +1,233 @@
+use super::{ Texture, WindowSurface, GpuDevice };
+
+/// Build a pipeline that runs `fragment_shader` (`fs_main`) once per pixel
+/// of the target, the vertex stage generates a single triangle covering
+/// the screen so no vertex buffer is needed. Draw it with `draw(0..3, 0..1)`
+///
+/// The fragment shader gets the `uv` of the pixel at `location(0)`, the
+/// layout is derived from the shader. `format` is the format of the target
+pub fn fullscreen_pipeline(gpu_device: &GpuDevice,
+                           format: wgpu::TextureFormat,
+                           fragment_shader: &wgpu::ShaderModule)
+    -> wgpu::RenderPipeline
+{
+    create_fullscreen_pipeline(gpu_device, format, fragment_shader, None)
+}
+
+fn create_fullscreen_pipeline(gpu_device: &GpuDevice,
+                              format: wgpu::TextureFormat,
+                              fragment_shader: &wgpu::ShaderModule,
+                              layout: Option<&wgpu::PipelineLayout>)
+    -> wgpu::RenderPipeline
+{
+    let vertex_shader = gpu_device.device.create_shader_module(
+        &wgpu::include_wgsl!("../fullscreen.wgsl"));
+
+    gpu_device.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Fullscreen Pipeline"),
+        layout,
+
+        vertex: wgpu::VertexState {
+            module: &vertex_shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+
+        fragment: Some(wgpu::FragmentState {
+            module: fragment_shader,
+            entry_point: "fs_main",
+            targets: &[wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            }],
+        }),
+
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: None,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+
+        depth_stencil: None,
+
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+
+        multiview: None,
+    })
+}
+
+/// Runs a fullscreen pass that reads a color texture and writes the result
+/// of a user supplied fragment shader (`fs_main`) to a target, used for
+/// effects like gamma correction or tonemapping
+///
+/// The fragment shader gets the source texture at `group(0) binding(0)` and
+/// the sampler at `group(0) binding(1)`
+pub struct PostProcess {
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+}
+
+impl PostProcess {
+    pub fn new(gpu_device: &GpuDevice,
+               surface: &WindowSurface,
+               fragment_shader: &wgpu::ShaderModule)
+        -> Self
+    {
+        let bind_group_layout = gpu_device.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float {
+                            filterable: true,
+                        },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(
+                        wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                }
+            ],
+            label: Some("post_process_bind_group_layout"),
+        });
+
+        let pipeline_layout = gpu_device.device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[],
+            }
+        );
+
+        let pipeline = create_fullscreen_pipeline(gpu_device,
+                                                  surface.config().format,
+                                                  fragment_shader,
+                                                  Some(&pipeline_layout));
+
+        Self {
+            pipeline,
+            bind_group_layout,
+        }
+    }
+
+    /// Create the bind group for reading from `source`, the texture needs
+    /// to have a sampler (see `Texture::create_render_target`)
+    pub fn create_bind_group(&self, gpu_device: &GpuDevice, source: &Texture)
+        -> wgpu::BindGroup
+    {
+        let sampler = source.sampler.as_ref()
+            .expect("Post process source texture has no sampler");
+
+        gpu_device.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                },
+
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                }
+            ],
+            label: Some("post_process_bind_group"),
+        })
+    }
+
+    pub fn run(&self,
+               encoder: &mut wgpu::CommandEncoder,
+               target: &wgpu::TextureView,
+               bind_group: &wgpu::BindGroup)
+    {
+        self.pass(encoder, target, bind_group,
+                  wgpu::LoadOp::Clear(wgpu::Color::BLACK), None);
+    }
+
+    /// Run the pass on top of the existing content of `target`, only
+    /// covering the viewport `[x, y, width, height]` (in pixels)
+    pub fn run_in_viewport(&self,
+                           encoder: &mut wgpu::CommandEncoder,
+                           target: &wgpu::TextureView,
+                           bind_group: &wgpu::BindGroup,
+                           viewport: [f32; 4])
+    {
+        self.pass(encoder, target, bind_group,
+                  wgpu::LoadOp::Load, Some(viewport));
+    }
+
+    fn pass(&self,
+            encoder: &mut wgpu::CommandEncoder,
+            target: &wgpu::TextureView,
+            bind_group: &wgpu::BindGroup,
+            load: wgpu::LoadOp<wgpu::Color>,
+            viewport: Option<[f32; 4]>)
+    {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Post Process Pass"),
+            color_attachments: &[
+                wgpu::RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load,
+                        store: true,
+                    }
+                }
+            ],
+            depth_stencil_attachment: None,
+        });
+
+        if let Some([x, y, width, height]) = viewport {
+            render_pass.set_viewport(x, y, width, height, 0.0, 1.0);
+        }
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::test_device;
+
+    const UV_SHADER: &str = "
+[[stage(fragment)]]
+fn fs_main([[location(0)]] uv: vec2<f32>) -> [[location(0)]] vec4<f32> {
+    return vec4<f32>(uv, 0.0, 1.0);
+}
+";
+
+    #[test]
+    #[ignore = "needs a GPU adapter, run with --ignored"]
+    fn fullscreen_pipeline_builds_headless() {
+        let gpu_device = test_device();
+
+        let shader = gpu_device.create_wgsl_shader("uv", UV_SHADER).unwrap();
+        let pipeline = gpu_device.catch_errors(|| {
+            fullscreen_pipeline(&gpu_device, wgpu::TextureFormat::Rgba8Unorm,
+                                shader.handle())
+        });
+
+        assert!(pipeline.is_ok(), "{:?}", pipeline.err());
+    }
+}
@@ -0,0 +1,91 @@
+use std::num::NonZeroU32;
+
+use super::GpuDevice;
+
+/// Copy `texture` back to the CPU as an RGBA image, the texture needs to
+/// be created with `COPY_SRC` usage
+pub fn capture(gpu_device: &GpuDevice,
+               texture: &wgpu::Texture,
+               width: u32, height: u32,
+               format: wgpu::TextureFormat)
+    -> Option<image::RgbaImage>
+{
+    let is_bgra = match format {
+        wgpu::TextureFormat::Bgra8Unorm |
+        wgpu::TextureFormat::Bgra8UnormSrgb => true,
+
+        wgpu::TextureFormat::Rgba8Unorm |
+        wgpu::TextureFormat::Rgba8UnormSrgb => false,
+
+        _ => {
+            log::error!("Screenshot: Unsupported texture format {:?}", format);
+            return None;
+        }
+    };
+
+    // NOTE(patrik): wgpu requires the rows in the buffer to be aligned to
+    // 256 bytes, so the rows are padded and the padding is stripped when
+    // reading the data back
+    let unpadded_bytes_per_row = width * 4;
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let padding = (align - unpadded_bytes_per_row % align) % align;
+    let padded_bytes_per_row = unpadded_bytes_per_row + padding;
+
+    let buffer_size = (padded_bytes_per_row * height) as wgpu::BufferAddress;
+    let buffer = gpu_device.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Screenshot Buffer"),
+        size: buffer_size,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = gpu_device.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Screenshot Encoder"),
+    });
+
+    encoder.copy_texture_to_buffer(
+        wgpu::ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+            aspect: wgpu::TextureAspect::All,
+        },
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(padded_bytes_per_row),
+                rows_per_image: NonZeroU32::new(height),
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+
+    gpu_device.queue.submit(std::iter::once(encoder.finish()));
+
+    let buffer_slice = buffer.slice(..);
+    let map_future = buffer_slice.map_async(wgpu::MapMode::Read);
+    gpu_device.device.poll(wgpu::Maintain::Wait);
+    pollster::block_on(map_future).ok()?;
+
+    let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+    {
+        let data = buffer_slice.get_mapped_range();
+        for row in data.chunks(padded_bytes_per_row as usize) {
+            pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+        }
+    }
+    buffer.unmap();
+
+    if is_bgra {
+        for pixel in pixels.chunks_mut(4) {
+            pixel.swap(0, 2);
+        }
+    }
+
+    image::RgbaImage::from_raw(width, height, pixels)
+}
@@ -0,0 +1,216 @@
+use glam::f32::{ Mat4, Vec3 };
+
+use super::{ GpuDevice, InstanceRaw, Texture, Vertex };
+
+/// Size of the shadow map in pixels
+const SHADOW_MAP_SIZE: u32 = 2048;
+
+/// Depth of the scene seen from the directional light, sampled in the
+/// scene pass at `group(2)` with a comparison sampler
+pub struct ShadowMap {
+    texture: Texture,
+
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+
+    pipeline: wgpu::RenderPipeline,
+    instanced_pipeline: wgpu::RenderPipeline,
+}
+
+impl ShadowMap {
+    /// `shader` needs the `vs_shadow` and `vs_shadow_instanced` entry
+    /// points using the same uniform buffer and model uniform bind groups
+    /// as the scene
+    pub fn new(gpu_device: &GpuDevice,
+               shader: &wgpu::ShaderModule,
+               uniform_bind_group_layout: &wgpu::BindGroupLayout,
+               model_bind_group_layout: &wgpu::BindGroupLayout)
+        -> Self
+    {
+        let texture = Texture::create_shadow_map(gpu_device, SHADOW_MAP_SIZE);
+        let bind_group_layout =
+            Texture::shadow_map_bind_group_layout(gpu_device);
+
+        let bind_group = gpu_device.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(
+                        texture.sampler.as_ref().unwrap()),
+                }
+            ],
+            label: Some("shadow_map_bind_group"),
+        });
+
+        let pipeline = create_pipeline(gpu_device, shader,
+                                       uniform_bind_group_layout,
+                                       model_bind_group_layout, false);
+        let instanced_pipeline = create_pipeline(gpu_device, shader,
+                                                 uniform_bind_group_layout,
+                                                 model_bind_group_layout,
+                                                 true);
+
+        Self {
+            texture,
+
+            bind_group_layout,
+            bind_group,
+
+            pipeline,
+            instanced_pipeline,
+        }
+    }
+
+    /// Recreate the pipeline with a new shader module, used when the
+    /// shader is reloaded
+    pub fn reload_shader(&mut self,
+                         gpu_device: &GpuDevice,
+                         shader: &wgpu::ShaderModule,
+                         uniform_bind_group_layout: &wgpu::BindGroupLayout,
+                         model_bind_group_layout: &wgpu::BindGroupLayout)
+    {
+        self.pipeline = create_pipeline(gpu_device, shader,
+                                        uniform_bind_group_layout,
+                                        model_bind_group_layout, false);
+        self.instanced_pipeline = create_pipeline(gpu_device, shader,
+                                                  uniform_bind_group_layout,
+                                                  model_bind_group_layout,
+                                                  true);
+    }
+
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.bind_group_layout
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    /// Pipeline for drawing with `Mesh::draw_instanced` in the pass started
+    /// by `begin_pass`
+    pub fn instanced_pipeline(&self) -> &wgpu::RenderPipeline {
+        &self.instanced_pipeline
+    }
+
+    /// Begin the depth only pass into the shadow map with the shadow
+    /// pipeline set, the caller binds the uniforms and draws the scene
+    pub fn begin_pass<'a>(&'a self, encoder: &'a mut wgpu::CommandEncoder)
+        -> wgpu::RenderPass<'a>
+    {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Shadow Pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        render_pass.set_pipeline(&self.pipeline);
+
+        render_pass
+    }
+}
+
+fn create_pipeline(gpu_device: &GpuDevice,
+                   shader: &wgpu::ShaderModule,
+                   uniform_bind_group_layout: &wgpu::BindGroupLayout,
+                   model_bind_group_layout: &wgpu::BindGroupLayout,
+                   instanced: bool)
+    -> wgpu::RenderPipeline
+{
+    let pipeline_layout = gpu_device.device.create_pipeline_layout(
+        &wgpu::PipelineLayoutDescriptor {
+            label: Some("Shadow Pipeline Layout"),
+            bind_group_layouts: &[uniform_bind_group_layout,
+                                  model_bind_group_layout],
+            push_constant_ranges: &[],
+        }
+    );
+
+    let (entry_point, buffers) = if instanced {
+        ("vs_shadow_instanced", vec![Vertex::desc(), InstanceRaw::desc()])
+    } else {
+        ("vs_shadow", vec![Vertex::desc()])
+    };
+
+    gpu_device.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Shadow Pipeline"),
+        layout: Some(&pipeline_layout),
+
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point,
+            buffers: &buffers,
+        },
+
+        // NOTE(patrik): Depth only, no fragment stage needed
+        fragment: None,
+
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Cw,
+            // NOTE(patrik): The sectors are open from some directions
+            // so both sides need to cast shadows
+            cull_mode: None,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: Texture::SHADOW_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            // NOTE(patrik): Pushes the depth away from the light to
+            // get rid of shadow acne
+            bias: wgpu::DepthBiasState {
+                constant: 2,
+                slope_scale: 2.0,
+                clamp: 0.0,
+            },
+        }),
+
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+
+        multiview: None,
+    })
+}
+
+/// View projection of a directional light shining along `direction`,
+/// covers a box of `extent` world units centered on `center`
+pub fn light_space_matrix(direction: Vec3, center: Vec3, extent: f32) -> Mat4 {
+    let direction = direction.normalize();
+    let half_extent = extent / 2.0;
+
+    // NOTE(patrik): look_at fails when looking straight along the up vector
+    let up = if direction.abs().abs_diff_eq(Vec3::Y, 0.001) {
+        Vec3::Z
+    } else {
+        Vec3::Y
+    };
+
+    let eye = center - direction * extent;
+    let view = Mat4::look_at_lh(eye, center, up);
+    let projection = Mat4::orthographic_lh(-half_extent, half_extent,
+                                           -half_extent, half_extent,
+                                           0.0, extent * 2.0);
+
+    projection * view
+}
@@ -0,0 +1,400 @@
+use std::num::{ NonZeroU8, NonZeroU32 };
+
+use super::GpuDevice;
+
+#[derive(Copy, Clone, Debug)]
+pub struct TextureOptions {
+    /// Generate a full mip chain for the texture
+    pub mipmaps: bool,
+
+    /// Anisotropic filtering level, 1 means off and 16 is the highest
+    /// quality. Only used when `mipmaps` is enabled
+    pub anisotropy: u8,
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        Self {
+            mipmaps: true,
+            anisotropy: 1,
+        }
+    }
+}
+
+pub struct Texture {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub sampler: Option<wgpu::Sampler>,
+}
+
+impl Texture {
+    /// Depth with a stencil component for masking out portals
+    pub const DEPTH_FORMAT: wgpu::TextureFormat =
+        wgpu::TextureFormat::Depth24PlusStencil8;
+
+    /// Shadow maps only need depth, without stencil it can be sampled
+    /// directly
+    pub const SHADOW_FORMAT: wgpu::TextureFormat =
+        wgpu::TextureFormat::Depth32Float;
+
+    pub fn create_depth_texture(gpu_device: &GpuDevice, width: u32, height: u32)
+        -> Self
+    {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+
+        let desc = wgpu::TextureDescriptor {
+            label: None,
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT |
+                   wgpu::TextureUsages::TEXTURE_BINDING,
+        };
+
+        let texture = gpu_device.device.create_texture(&desc);
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        Self { texture, view, sampler: None }
+    }
+
+    /// Replace the depth texture with one of `width`x`height`, needs to be
+    /// called when the surface is resized so the depth attachment matches
+    /// the color attachment. Zero sized textures are rejected and leaves
+    /// the current texture untouched
+    pub fn recreate_depth(&mut self,
+                          gpu_device: &GpuDevice,
+                          width: u32, height: u32)
+    {
+        if width == 0 || height == 0 {
+            log::warn!("Ignoring depth texture resize to {}x{}",
+                       width, height);
+            return;
+        }
+
+        *self = Self::create_depth_texture(gpu_device, width, height);
+    }
+
+    /// Depth texture of `size`x`size` with a comparison sampler
+    /// (`LessEqual`), for sampling with `textureSampleCompare` in shadow
+    /// mapping. Bind it with `shadow_map_bind_group_layout`
+    pub fn create_shadow_map(gpu_device: &GpuDevice, size: u32) -> Self {
+        let size = wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        };
+
+        let desc = wgpu::TextureDescriptor {
+            label: Some("Shadow Map"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::SHADOW_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT |
+                   wgpu::TextureUsages::TEXTURE_BINDING,
+        };
+
+        let texture = gpu_device.device.create_texture(&desc);
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let sampler = gpu_device.device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                label: Some("Shadow Sampler"),
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                compare: Some(wgpu::CompareFunction::LessEqual),
+                ..Default::default()
+            }
+        );
+
+        Self { texture, view, sampler: Some(sampler) }
+    }
+
+    /// Layout for a shadow map, the depth texture at `binding(0)` and the
+    /// comparison sampler at `binding(1)`, visible to the fragment stage
+    pub fn shadow_map_bind_group_layout(gpu_device: &GpuDevice)
+        -> wgpu::BindGroupLayout
+    {
+        gpu_device.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(
+                        wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                }
+            ],
+            label: Some("shadow_map_bind_group_layout"),
+        })
+    }
+
+    /// Create a color texture that can be rendered to and then sampled
+    /// from in another pass
+    pub fn create_render_target(gpu_device: &GpuDevice,
+                                width: u32, height: u32,
+                                format: wgpu::TextureFormat)
+        -> Self
+    {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+
+        let desc = wgpu::TextureDescriptor {
+            label: Some("Render Target"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT |
+                   wgpu::TextureUsages::TEXTURE_BINDING,
+        };
+
+        let texture = gpu_device.device.create_texture(&desc);
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let sampler = gpu_device.device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            }
+        );
+
+        Self { texture, view, sampler: Some(sampler) }
+    }
+
+    pub fn from_image_bytes(gpu_device: &GpuDevice,
+                            bytes: &[u8],
+                            options: TextureOptions)
+        -> Option<Self>
+    {
+        let image = image::load_from_memory(bytes).ok()?.to_rgba8();
+        Some(Self::from_rgba_image(gpu_device, image, options))
+    }
+
+    /// Upload an sRGB image, used for textures generated at runtime
+    pub fn from_rgba_image(gpu_device: &GpuDevice,
+                           image: image::RgbaImage,
+                           options: TextureOptions)
+        -> Self
+    {
+        let (width, height) = image.dimensions();
+
+        let mip_level_count = if options.mipmaps {
+            mip_level_count(width, height)
+        } else {
+            1
+        };
+
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+
+        let desc = wgpu::TextureDescriptor {
+            label: None,
+            size,
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING |
+                   wgpu::TextureUsages::COPY_DST,
+        };
+
+        let texture = gpu_device.device.create_texture(&desc);
+
+        // NOTE(patrik): The mip chain is generated on the CPU, every level
+        // is downsampled from the previous one
+        let mut level_image = image;
+        for mip_level in 0..mip_level_count {
+            if mip_level > 0 {
+                let level_width = (width >> mip_level).max(1);
+                let level_height = (height >> mip_level).max(1);
+
+                level_image = image::imageops::resize(
+                    &level_image,
+                    level_width, level_height,
+                    image::imageops::FilterType::Triangle);
+            }
+
+            let (level_width, level_height) = level_image.dimensions();
+
+            gpu_device.queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture,
+                    mip_level,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                &level_image,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(4 * level_width),
+                    rows_per_image: NonZeroU32::new(level_height),
+                },
+                wgpu::Extent3d {
+                    width: level_width,
+                    height: level_height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let sampler = gpu_device.device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::Repeat,
+                address_mode_v: wgpu::AddressMode::Repeat,
+                address_mode_w: wgpu::AddressMode::Repeat,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Linear,
+                anisotropy_clamp: anisotropy_clamp(gpu_device, &options),
+                ..Default::default()
+            }
+        );
+
+        Self { texture, view, sampler: Some(sampler) }
+    }
+}
+
+/// Offscreen color + depth target, used for rendering the scene into a
+/// texture instead of the swapchain
+pub struct RenderTarget {
+    pub color: Texture,
+    pub depth: Texture,
+
+    width: u32,
+    height: u32,
+}
+
+impl RenderTarget {
+    pub fn new(gpu_device: &GpuDevice,
+               width: u32, height: u32,
+               format: wgpu::TextureFormat)
+        -> Self
+    {
+        let color = Texture::create_render_target(gpu_device,
+                                                  width, height,
+                                                  format);
+        let depth = Texture::create_depth_texture(gpu_device, width, height);
+
+        Self {
+            color,
+            depth,
+
+            width,
+            height,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn begin_render_pass<'a>(&'a self,
+                                 encoder: &'a mut wgpu::CommandEncoder,
+                                 clear_color: wgpu::Color)
+        -> wgpu::RenderPass<'a>
+    {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render Target Pass"),
+            color_attachments: &[
+                wgpu::RenderPassColorAttachment {
+                    view: &self.color.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(clear_color),
+                        store: true,
+                    }
+                }
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(0),
+                    store: true,
+                }),
+            }),
+        })
+    }
+}
+
+/// Number of mip levels needed to go from `width`x`height` down to 1x1
+fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+/// Validate the requested anisotropy level against the options and the
+/// device, falls back to no anisotropic filtering if it can't be used
+fn anisotropy_clamp(gpu_device: &GpuDevice, options: &TextureOptions)
+    -> Option<NonZeroU8>
+{
+    let anisotropy = options.anisotropy;
+    if anisotropy <= 1 {
+        return None;
+    }
+
+    if !matches!(anisotropy, 2 | 4 | 8 | 16) {
+        log::warn!("Invalid anisotropy level {} (valid: 1, 2, 4, 8, 16)",
+                   anisotropy);
+        return None;
+    }
+
+    if !options.mipmaps {
+        log::warn!("Anisotropic filtering requires mipmaps");
+        return None;
+    }
+
+    let flags = gpu_device.adapter.get_downlevel_properties().flags;
+    if !flags.contains(wgpu::DownlevelFlags::ANISOTROPIC_FILTERING) {
+        log::warn!("Device doesn't support anisotropic filtering");
+        return None;
+    }
+
+    NonZeroU8::new(anisotropy)
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use glam::f32::Vec2;
+
+use super::{ GpuDevice, Texture, TextureOptions };
+
+/// Where a sub-texture is placed in the atlas, maps the 0..1 UVs of the
+/// original texture into the atlas
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UvRect {
+    pub offset: Vec2,
+    pub scale: Vec2,
+}
+
+impl UvRect {
+    pub fn transform(&self, uv: Vec2) -> Vec2 {
+        self.offset + uv * self.scale
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum TextureAtlasError {
+    /// The named texture doesn't fit in what is left of the atlas
+    OutOfSpace(String),
+    /// The named texture is wider or taller than the atlas itself
+    TooLarge(String),
+}
+
+impl std::fmt::Display for TextureAtlasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::OutOfSpace(name) =>
+                write!(f, "texture '{}' doesn't fit in the atlas", name),
+            Self::TooLarge(name) =>
+                write!(f, "texture '{}' is larger than the atlas", name),
+        }
+    }
+}
+
+impl std::error::Error for TextureAtlasError {}
+
+/// Collects the images to pack into a `TextureAtlas`
+pub struct TextureAtlasBuilder {
+    size: u32,
+    padding: u32,
+
+    images: Vec<(String, image::RgbaImage)>,
+}
+
+impl TextureAtlasBuilder {
+    /// `size` is the width and height of the atlas texture in pixels
+    pub fn new(size: u32) -> Self {
+        Self {
+            size,
+            padding: 1,
+
+            images: Vec::new(),
+        }
+    }
+
+    /// Empty pixels between the sub-textures so the filtering doesn't pick
+    /// up the neighbors, defaults to 1
+    pub fn padding(mut self, padding: u32) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn add(mut self, name: &str, image: image::RgbaImage) -> Self {
+        self.images.push((name.to_string(), image));
+        self
+    }
+
+    /// Pack the images with shelf packing and upload the atlas
+    pub fn build(self, gpu_device: &GpuDevice, options: TextureOptions)
+        -> Result<TextureAtlas, TextureAtlasError>
+    {
+        let sizes = self.images.iter()
+            .map(|(_, image)| image.dimensions())
+            .collect::<Vec<_>>();
+
+        let placements = pack_shelves(&sizes, self.size, self.padding)
+            .map_err(|error| {
+                let name = |index: usize| self.images[index].0.clone();
+
+                match error {
+                    PackError::OutOfSpace(index) => {
+                        TextureAtlasError::OutOfSpace(name(index))
+                    }
+
+                    PackError::TooLarge(index) => {
+                        TextureAtlasError::TooLarge(name(index))
+                    }
+                }
+            })?;
+
+        let mut atlas = image::RgbaImage::new(self.size, self.size);
+        let mut regions = HashMap::new();
+
+        let atlas_size = self.size as f32;
+        for ((name, image), (x, y)) in self.images.iter().zip(placements) {
+            image::imageops::replace(&mut atlas, image, x as i64, y as i64);
+
+            let (width, height) = image.dimensions();
+            regions.insert(name.clone(), UvRect {
+                offset: Vec2::new(x as f32, y as f32) / atlas_size,
+                scale: Vec2::new(width as f32, height as f32) / atlas_size,
+            });
+        }
+
+        // NOTE(patrik): The smaller mip levels blend the sub-textures
+        // together, more padding hides it for a few levels
+        let texture = Texture::from_rgba_image(gpu_device, atlas, options);
+
+        Ok(TextureAtlas {
+            texture,
+            regions,
+        })
+    }
+}
+
+/// Many small textures packed into one, so meshes using different
+/// textures can still be drawn together
+pub struct TextureAtlas {
+    texture: Texture,
+    regions: HashMap<String, UvRect>,
+}
+
+impl TextureAtlas {
+    pub fn builder(size: u32) -> TextureAtlasBuilder {
+        TextureAtlasBuilder::new(size)
+    }
+
+    pub fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    /// UV transform of the texture added as `name`
+    pub fn region(&self, name: &str) -> Option<UvRect> {
+        self.regions.get(name).copied()
+    }
+}
+
+/// Why `pack_shelves` failed, with the index of the rectangle
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum PackError {
+    /// Doesn't fit in the space left after the rectangles placed before it
+    OutOfSpace(usize),
+    /// Wider or taller than the atlas, doesn't fit even on its own
+    TooLarge(usize),
+}
+
+/// Place the rectangles on shelves, tallest first. Returns the top left
+/// corner of each rectangle in the order they were given
+fn pack_shelves(sizes: &[(u32, u32)], atlas_size: u32, padding: u32)
+    -> Result<Vec<(u32, u32)>, PackError>
+{
+    if let Some(index) = sizes.iter()
+        .position(|&(width, height)| width > atlas_size || height > atlas_size)
+    {
+        return Err(PackError::TooLarge(index));
+    }
+
+    let mut order = (0..sizes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&index| std::cmp::Reverse(sizes[index].1));
+
+    let mut placements = vec![(0, 0); sizes.len()];
+
+    let mut shelf_y = 0;
+    let mut shelf_height = 0;
+    let mut x = 0;
+
+    for index in order {
+        let (width, height) = sizes[index];
+
+        if x + width > atlas_size {
+            // NOTE(patrik): Start a new shelf below the current one
+            shelf_y += shelf_height + padding;
+            shelf_height = 0;
+            x = 0;
+        }
+
+        if shelf_y + height > atlas_size {
+            return Err(PackError::OutOfSpace(index));
+        }
+
+        placements[index] = (x, shelf_y);
+
+        x += width + padding;
+        shelf_height = shelf_height.max(height);
+    }
+
+    Ok(placements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_shelves_fills_a_shelf() {
+        let sizes = [(4, 4), (4, 2)];
+        assert_eq!(pack_shelves(&sizes, 16, 1), Ok(vec![(0, 0), (5, 0)]));
+    }
+
+    #[test]
+    fn pack_shelves_places_tallest_first() {
+        let sizes = [(2, 2), (2, 6)];
+        assert_eq!(pack_shelves(&sizes, 16, 1), Ok(vec![(3, 0), (0, 0)]));
+    }
+
+    #[test]
+    fn pack_shelves_opens_a_new_shelf() {
+        let sizes = [(8, 8), (8, 4)];
+        assert_eq!(pack_shelves(&sizes, 16, 1), Ok(vec![(0, 0), (0, 9)]));
+    }
+
+    #[test]
+    fn pack_shelves_fits_exactly() {
+        let sizes = [(16, 16)];
+        assert_eq!(pack_shelves(&sizes, 16, 1), Ok(vec![(0, 0)]));
+    }
+
+    #[test]
+    fn pack_shelves_out_of_space() {
+        let sizes = [(16, 10), (16, 10)];
+        assert_eq!(pack_shelves(&sizes, 16, 1), Err(PackError::OutOfSpace(1)));
+    }
+
+    #[test]
+    fn pack_shelves_too_wide() {
+        let sizes = [(4, 4), (17, 1)];
+        assert_eq!(pack_shelves(&sizes, 16, 1), Err(PackError::TooLarge(1)));
+    }
+
+    #[test]
+    fn pack_shelves_too_tall() {
+        let sizes = [(1, 17)];
+        assert_eq!(pack_shelves(&sizes, 16, 1), Err(PackError::TooLarge(0)));
+    }
+
+    #[test]
+    fn pack_shelves_nothing_to_pack() {
+        assert_eq!(pack_shelves(&[], 16, 1), Ok(Vec::new()));
+    }
+}
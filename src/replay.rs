@@ -0,0 +1,226 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{ BufRead, BufReader, BufWriter, Write };
+use std::path::Path;
+
+use glam::f32::Vec2;
+
+/// First line of a replay file, bumped when the format changes
+const REPLAY_HEADER: &str = "nocks-replay 1";
+
+/// Input of a single frame, enough to drive the player movement and the
+/// camera the same way again
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FrameInput {
+    /// Delta time of the frame, after clamping
+    pub dt: f32,
+
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+    pub jump: bool,
+
+    pub mouse_delta: Vec2,
+}
+
+impl FrameInput {
+    fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.split_whitespace();
+
+        let mut number = || parts.next()?.parse::<f32>().ok();
+        let dt = number()?;
+        let up = number()? != 0.0;
+        let down = number()? != 0.0;
+        let left = number()? != 0.0;
+        let right = number()? != 0.0;
+        let jump = number()? != 0.0;
+        let mouse_delta = Vec2::new(number()?, number()?);
+
+        Some(Self { dt, up, down, left, right, jump, mouse_delta })
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(std::io::Error),
+    /// The file doesn't start with `REPLAY_HEADER`
+    InvalidHeader,
+    /// The line (1 based) couldn't be parsed
+    InvalidFrame(usize),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::InvalidHeader => write!(f, "not a replay file"),
+            Self::InvalidFrame(line) => write!(f, "invalid frame on line {}", line),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<std::io::Error> for ReplayError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Writes the input of every frame to a file, one frame per line
+pub struct ReplayRecorder {
+    writer: BufWriter<File>,
+    frames: usize,
+}
+
+impl ReplayRecorder {
+    pub fn create<P>(path: P) -> Result<Self, ReplayError>
+        where P: AsRef<Path>
+    {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", REPLAY_HEADER)?;
+
+        Ok(Self {
+            writer,
+            frames: 0,
+        })
+    }
+
+    pub fn record(&mut self, input: &FrameInput) -> Result<(), ReplayError> {
+        // NOTE(patrik): Rust prints floats with enough digits to parse
+        // back to the exact same value
+        writeln!(self.writer, "{} {} {} {} {} {} {} {}",
+                 input.dt,
+                 input.up as u8, input.down as u8,
+                 input.left as u8, input.right as u8,
+                 input.jump as u8,
+                 input.mouse_delta.x, input.mouse_delta.y)?;
+
+        self.frames += 1;
+
+        Ok(())
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn finish(mut self) -> Result<(), ReplayError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Recorded input played back one frame at a time
+pub struct Replay {
+    frames: Vec<FrameInput>,
+    next: usize,
+}
+
+impl Replay {
+    pub fn load<P>(path: P) -> Result<Self, ReplayError>
+        where P: AsRef<Path>
+    {
+        let reader = BufReader::new(File::open(path)?);
+        let mut lines = reader.lines();
+
+        match lines.next() {
+            Some(Ok(header)) if header.trim() == REPLAY_HEADER => {}
+            Some(Err(e)) => return Err(ReplayError::Io(e)),
+            _ => return Err(ReplayError::InvalidHeader),
+        }
+
+        let mut frames = Vec::new();
+        for (index, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            // NOTE(patrik): + 2 for the header and the line numbers
+            // starting at 1
+            let frame = FrameInput::parse(&line)
+                .ok_or(ReplayError::InvalidFrame(index + 2))?;
+            frames.push(frame);
+        }
+
+        Ok(Self {
+            frames,
+            next: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Input for the next frame, None when the replay has ended
+    pub fn next_frame(&mut self) -> Option<FrameInput> {
+        let frame = self.frames.get(self.next).copied()?;
+        self.next += 1;
+
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_frames_load_back() {
+        let path = std::env::temp_dir()
+            .join(format!("nocks-replay-{}.txt", std::process::id()));
+
+        let frames = [
+            FrameInput {
+                dt: 1.0 / 60.0,
+                up: true,
+                jump: true,
+                mouse_delta: Vec2::new(0.1, -2.5),
+                ..Default::default()
+            },
+            FrameInput {
+                dt: 0.0173,
+                left: true,
+                right: true,
+                down: true,
+                mouse_delta: Vec2::new(-13.0, 7.25),
+                ..Default::default()
+            },
+        ];
+
+        let mut recorder = ReplayRecorder::create(&path).unwrap();
+        for frame in &frames {
+            recorder.record(frame).unwrap();
+        }
+        assert_eq!(recorder.frames(), frames.len());
+        recorder.finish().unwrap();
+
+        let mut replay = Replay::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(replay.len(), frames.len());
+        for frame in &frames {
+            assert_eq!(replay.next_frame(), Some(*frame));
+        }
+        assert_eq!(replay.next_frame(), None);
+    }
+
+    #[test]
+    fn load_rejects_missing_header() {
+        let path = std::env::temp_dir()
+            .join(format!("nocks-replay-header-{}.txt", std::process::id()));
+        std::fs::write(&path, "0.016 0 0 0 0 0 0 0\n").unwrap();
+
+        let result = Replay::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(ReplayError::InvalidHeader)));
+    }
+}
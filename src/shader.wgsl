@@ -1,37 +1,257 @@
 // Vertex shader
 
+let MAX_POINT_LIGHTS: u32 = 4u;
+
+struct PointLight {
+    // xyz is the world position and w the radius
+    position: vec4<f32>;
+    color: vec4<f32>;
+};
+
 struct UniformBuffer {
     projection_matrix: mat4x4<f32>;
     view_matrix: mat4x4<f32>;
     model_matrix: mat4x4<f32>;
+
+    // xyz is the linear fog color and w the fog density
+    fog: vec4<f32>;
+    // x is the light level, y is 1 if the sun casts shadows
+    light: vec4<f32>;
+
+    point_lights: array<PointLight, MAX_POINT_LIGHTS>;
+    // x is the number of used point lights
+    point_light_count: vec4<u32>;
+
+    light_space_matrix: mat4x4<f32>;
+    // xyz is the direction of the directional light and w the intensity
+    sun: vec4<f32>;
+
+    // x is the gamma and y the exposure
+    display: vec4<f32>;
+    // x is the debug mode, 0 is the normal lit output
+    debug_mode: vec4<u32>;
 };
 
 [[group(0), binding(0)]]
 var<uniform> uniform_buffer: UniformBuffer;
 
+struct ModelUniform {
+    model_matrix: mat4x4<f32>;
+    normal_matrix: mat3x3<f32>;
+    base_color: vec4<f32>;
+    // 1 if affected by the lighting and fog
+    lit: u32;
+    // Fragments with an alpha below this are discarded
+    alpha_cutoff: f32;
+};
+
+[[group(1), binding(0)]]
+var<uniform> model_uniform: ModelUniform;
+
+// Transform a normal from model space to world space, uses the normal
+// matrix so non-uniform scaling doesn't skew the normal
+fn transform_normal(normal: vec3<f32>) -> vec3<f32> {
+    return normalize(model_uniform.normal_matrix * normal);
+}
+
 struct VertexInput {
     [[location(0)]] position: vec3<f32>;
-    [[location(1)]] color: vec3<f32>;
+    [[location(1)]] color: vec4<f32>;
+    [[location(2)]] normal: vec3<f32>;
 };
 
 struct VertexOutput {
     [[builtin(position)]] clip_position: vec4<f32>;
-    [[location(0)]] color: vec3<f32>;
+    [[location(0)]] color: vec4<f32>;
+    [[location(1)]] view_depth: f32;
+    [[location(2)]] world_position: vec3<f32>;
+    [[location(3)]] world_normal: vec3<f32>;
 };
 
+// Vertex colors are authored in sRGB, the surface expects linear colors
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let lower = color / 12.92;
+    let higher = pow((color + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(higher, lower, color <= vec3<f32>(0.04045));
+}
+
 [[stage(vertex)]]
 fn vs_main(
     model: VertexInput,
 ) -> VertexOutput {
+    let world_position = model_uniform.model_matrix * vec4<f32>(model.position, 1.0);
+    let view_position = uniform_buffer.view_matrix * world_position;
+
     var out: VertexOutput;
-    out.color = model.color;
-    out.clip_position = uniform_buffer.projection_matrix * uniform_buffer.view_matrix * uniform_buffer.model_matrix * vec4<f32>(model.position, 1.0);
+    out.color = vec4<f32>(srgb_to_linear(model.color.rgb), model.color.a);
+    out.clip_position = uniform_buffer.projection_matrix * view_position;
+    out.view_depth = view_position.z;
+    out.world_position = world_position.xyz;
+    out.world_normal = transform_normal(model.normal);
     return out;
 }
 
+struct InstanceInput {
+    [[location(5)]] model_matrix_0: vec4<f32>;
+    [[location(6)]] model_matrix_1: vec4<f32>;
+    [[location(7)]] model_matrix_2: vec4<f32>;
+    [[location(8)]] model_matrix_3: vec4<f32>;
+};
+
+// Same as vs_main but the model matrix comes from the instance buffer
+[[stage(vertex)]]
+fn vs_instanced(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    let view_position = uniform_buffer.view_matrix * world_position;
+
+    var out: VertexOutput;
+    out.color = vec4<f32>(srgb_to_linear(model.color.rgb), model.color.a);
+    out.clip_position = uniform_buffer.projection_matrix * view_position;
+    out.view_depth = view_position.z;
+    out.world_position = world_position.xyz;
+    out.world_normal = normalize((model_matrix * vec4<f32>(model.normal, 0.0)).xyz);
+    return out;
+}
+
+// Depth only, renders the scene from the directional light into the
+// shadow map
+[[stage(vertex)]]
+fn vs_shadow(
+    model: VertexInput,
+) -> [[builtin(position)]] vec4<f32> {
+    return uniform_buffer.light_space_matrix * model_uniform.model_matrix * vec4<f32>(model.position, 1.0);
+}
+
+// Same as vs_shadow but the model matrix comes from the instance buffer
+[[stage(vertex)]]
+fn vs_shadow_instanced(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> [[builtin(position)]] vec4<f32> {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    return uniform_buffer.light_space_matrix * model_matrix * vec4<f32>(model.position, 1.0);
+}
+
 // Fragment shader
 
+[[group(2), binding(0)]]
+var shadow_map: texture_depth_2d;
+[[group(2), binding(1)]]
+var shadow_sampler: sampler_comparison;
+
+// How much of the directional light reaches the position, 0 is fully in
+// shadow. Filtered over 3x3 texels to soften the edges
+fn shadow_factor(position: vec3<f32>) -> f32 {
+    if (uniform_buffer.light.y == 0.0) {
+        return 1.0;
+    }
+
+    let light_position = uniform_buffer.light_space_matrix * vec4<f32>(position, 1.0);
+    let ndc = light_position.xyz / light_position.w;
+    let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+
+    // Everything outside of the shadow map is lit
+    if (ndc.z > 1.0 || any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0))) {
+        return 1.0;
+    }
+
+    let texel_size = 1.0 / f32(textureDimensions(shadow_map).x);
+
+    var lit = 0.0;
+    for (var y = -1; y <= 1; y = y + 1) {
+        for (var x = -1; x <= 1; x = x + 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * texel_size;
+            lit = lit + textureSampleCompareLevel(shadow_map, shadow_sampler, uv + offset, ndc.z);
+        }
+    }
+
+    return lit / 9.0;
+}
+
+// Sum of the point lights reaching the surface, the lights fade out
+// quadratically and are gone at their radius
+fn point_lighting(position: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    var total = vec3<f32>(0.0);
+
+    let count = min(uniform_buffer.point_light_count.x, MAX_POINT_LIGHTS);
+    for (var i = 0u; i < count; i = i + 1u) {
+        let light = uniform_buffer.point_lights[i];
+
+        let to_light = light.position.xyz - position;
+        let dist = length(to_light);
+        let falloff = max(1.0 - dist / light.position.w, 0.0);
+
+        let diffuse = max(dot(normal, to_light / max(dist, 0.0001)), 0.0);
+        total = total + light.color.rgb * diffuse * falloff * falloff;
+    }
+
+    return total;
+}
+
+// Exposure and gamma adjustment, the surface does the sRGB encoding so a
+// gamma of 2.2 leaves the color unchanged
+fn adjust_display(color: vec3<f32>) -> vec3<f32> {
+    let exposed = color * uniform_buffer.display.y;
+    return pow(max(exposed, vec3<f32>(0.0)), vec3<f32>(2.2 / uniform_buffer.display.x));
+}
+
+let DEBUG_MODE_NORMALS: u32 = 1u;
+let DEBUG_MODE_DEPTH: u32 = 2u;
+let DEBUG_MODE_ALBEDO: u32 = 3u;
+
+// View depth that shows up as white in the depth debug mode
+let DEBUG_DEPTH_RANGE: f32 = 2048.0;
+
 [[stage(fragment)]]
 fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-    return vec4<f32>(in.color, 1.0);
+    let base_color = in.color * model_uniform.base_color;
+    if (base_color.a < model_uniform.alpha_cutoff) {
+        discard;
+    }
+
+    let debug_mode = uniform_buffer.debug_mode.x;
+    if (debug_mode == DEBUG_MODE_NORMALS) {
+        return vec4<f32>(normalize(in.world_normal) * 0.5 + 0.5, base_color.a);
+    }
+
+    if (debug_mode == DEBUG_MODE_DEPTH) {
+        let depth = clamp(in.view_depth / DEBUG_DEPTH_RANGE, 0.0, 1.0);
+        return vec4<f32>(vec3<f32>(depth), base_color.a);
+    }
+
+    if (debug_mode == DEBUG_MODE_ALBEDO) {
+        return base_color;
+    }
+
+    if (model_uniform.lit == 0u) {
+        return vec4<f32>(adjust_display(base_color.rgb), base_color.a);
+    }
+
+    let normal = normalize(in.world_normal);
+    let sun_diffuse = max(dot(normal, -normalize(uniform_buffer.sun.xyz)), 0.0);
+    let sun = uniform_buffer.sun.w * sun_diffuse * shadow_factor(in.world_position);
+
+    let lighting = uniform_buffer.light.x + sun + point_lighting(in.world_position, normal);
+    let color = base_color.rgb * lighting;
+
+    // Exponential fog, a density of 0 leaves the color untouched
+    let fog_amount = 1.0 - exp(-uniform_buffer.fog.w * max(in.view_depth, 0.0));
+    let fogged = mix(color, uniform_buffer.fog.xyz, fog_amount);
+    return vec4<f32>(adjust_display(fogged), base_color.a);
 }
@@ -0,0 +1,207 @@
+use std::path::Path;
+
+use bevy_ecs::prelude::*;
+use glam::f32::{ Mat4, Vec2, Vec3 };
+
+use crate::render::{ GpuDevice, WindowSurface, RenderPipeline, PipelineLayout };
+use crate::render::{ Mesh, Vertex, Texture, TextureOptions, BindGroupBuilder };
+use crate::render::{ DynamicUniformBuffer, ModelUniform, Material };
+
+/// Handle to a texture registered with `SpriteRenderer::add_texture`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SpriteTexture(u32);
+
+/// Textured quad drawn at the `Position` of the entity, always turned to
+/// face the camera
+#[derive(Component, Copy, Clone, Debug)]
+pub struct Sprite {
+    pub texture: SpriteTexture,
+    /// Width and height in world units, the bottom edge is placed at the
+    /// position
+    pub size: Vec2,
+    /// Usually `Material::CUTOUT`, the alpha cutoff decides which texels
+    /// are cut out
+    pub material: Material,
+}
+
+/// Model matrix that turns the unit sprite quad to face the camera,
+/// `right` and `up` are the camera axes in world space
+pub fn billboard_matrix(position: Vec3,
+                        size: Vec2,
+                        right: Vec3, up: Vec3, forward: Vec3)
+    -> Mat4
+{
+    Mat4::from_cols((right * size.x).extend(0.0),
+                    (up * size.y).extend(0.0),
+                    forward.extend(0.0),
+                    position.extend(1.0))
+}
+
+pub struct SpriteRenderer {
+    quad: Mesh,
+
+    textures: Vec<(Texture, wgpu::BindGroup)>,
+
+    pipeline: RenderPipeline,
+}
+
+impl SpriteRenderer {
+    /// The sprites share the scene uniform buffer and model uniforms, the
+    /// texture is bound at `group(2)`
+    pub fn new(gpu_device: &GpuDevice,
+               surface: &WindowSurface,
+               uniform_bind_group_layout: &wgpu::BindGroupLayout,
+               model_bind_group_layout: &wgpu::BindGroupLayout)
+        -> Result<Self, wgpu::Error>
+    {
+        let vertices = vec![
+            quad_vertex(-0.5, 0.0),
+            quad_vertex(-0.5, 1.0),
+            quad_vertex(0.5, 1.0),
+            quad_vertex(0.5, 0.0),
+        ];
+        let indices = vec![0, 1, 2, 0, 2, 3];
+        let quad = Mesh::from_data(gpu_device, &vertices, &indices);
+
+        // NOTE(patrik): Only used for the layout, the bind groups are
+        // created for each texture in add_texture. Identical layouts are
+        // compatible so the pipeline accepts those bind groups
+        let placeholder = placeholder_image();
+        let placeholder = Texture::from_rgba_image(gpu_device, placeholder,
+                                                   sprite_texture_options());
+        let (_, texture_bind_group_layout) = texture_bind_group(gpu_device,
+                                                                &placeholder);
+
+        let shader = gpu_device.create_wgsl_shader("sprite.wgsl",
+                                                   include_str!("sprite.wgsl"))?;
+
+        let pipeline_layout = PipelineLayout::builder()
+            .bind_group_layout(uniform_bind_group_layout)
+            .bind_group_layout(model_bind_group_layout)
+            .bind_group_layout(&texture_bind_group_layout)
+            .build(gpu_device);
+
+        // NOTE(patrik): The quad is seen from both sides depending on how
+        // the camera is turned
+        let pipeline = RenderPipeline::builder()
+            .vertex_shader(&shader)
+            .fragment_shader(&shader)
+            .depth_stencil(true)
+            .build(gpu_device, surface.config().format, &pipeline_layout)?;
+
+        Ok(Self {
+            quad,
+
+            textures: Vec::new(),
+
+            pipeline,
+        })
+    }
+
+    pub fn add_texture(&mut self, gpu_device: &GpuDevice, texture: Texture)
+        -> SpriteTexture
+    {
+        let (bind_group, _) = texture_bind_group(gpu_device, &texture);
+
+        let handle = SpriteTexture(self.textures.len() as u32);
+        self.textures.push((texture, bind_group));
+
+        handle
+    }
+
+    /// Load the texture at `path`, a placeholder is used if the image
+    /// can't be read so the sprite is still visible
+    pub fn load_texture(&mut self, gpu_device: &GpuDevice, path: &Path)
+        -> SpriteTexture
+    {
+        let image = match image::open(path) {
+            Ok(image) => image.to_rgba8(),
+            Err(e) => {
+                log::warn!("Failed to load sprite '{}', using a placeholder: {}",
+                           path.display(), e);
+                placeholder_image()
+            }
+        };
+
+        let texture = Texture::from_rgba_image(gpu_device, image,
+                                               sprite_texture_options());
+        self.add_texture(gpu_device, texture)
+    }
+
+    /// Draw `sprites` with the model uniforms starting at `first_offset`,
+    /// the caller has already bound the scene uniform buffer at `group(0)`
+    /// and set the model uniforms for the sprites in the same order.
+    /// Returns the number of draw calls
+    pub fn draw<'a, I>(&'a self,
+                       render_pass: &mut wgpu::RenderPass<'a>,
+                       model_uniforms: &'a DynamicUniformBuffer<ModelUniform>,
+                       first_offset: usize,
+                       sprites: I)
+        -> u32
+    where
+        I: Iterator<Item = &'a Sprite>,
+    {
+        render_pass.set_pipeline(self.pipeline.handle());
+
+        let mut draw_calls = 0;
+        for (index, sprite) in sprites.enumerate() {
+            let bind_group = match self.textures.get(sprite.texture.0 as usize) {
+                Some((_, bind_group)) => bind_group,
+                None => continue,
+            };
+
+            render_pass.set_bind_group(1, model_uniforms.bind_group(),
+                                       &[model_uniforms.offset(first_offset + index)]);
+            render_pass.set_bind_group(2, bind_group, &[]);
+
+            self.quad.draw(render_pass);
+            draw_calls += 1;
+        }
+
+        draw_calls
+    }
+}
+
+fn quad_vertex(x: f32, y: f32) -> Vertex {
+    Vertex {
+        position: [x, y, 0.0],
+        color: [1.0, 1.0, 1.0, 1.0],
+        normal: [0.0, 0.0, -1.0],
+    }
+}
+
+fn sprite_texture_options() -> TextureOptions {
+    // NOTE(patrik): Mipmaps blur out the alpha so the cutouts shrink with
+    // the distance
+    TextureOptions {
+        mipmaps: false,
+        ..Default::default()
+    }
+}
+
+fn texture_bind_group(gpu_device: &GpuDevice, texture: &Texture)
+    -> (wgpu::BindGroup, wgpu::BindGroupLayout)
+{
+    BindGroupBuilder::new()
+        .label("sprite_texture_bind_group")
+        .texture(0, &texture.view)
+        .sampler(1, texture.sampler.as_ref().unwrap())
+        .build(gpu_device)
+}
+
+/// Magenta disc on a transparent background
+fn placeholder_image() -> image::RgbaImage {
+    const SIZE: u32 = 32;
+
+    image::RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+        let center = (SIZE as f32 - 1.0) / 2.0;
+        let dx = x as f32 - center;
+        let dy = y as f32 - center;
+
+        if dx * dx + dy * dy <= center * center {
+            image::Rgba([255, 0, 255, 255])
+        } else {
+            image::Rgba([0, 0, 0, 0])
+        }
+    })
+}
@@ -0,0 +1,108 @@
+// Billboard sprites, the model matrix from the CPU rotates a unit quad to
+// face the camera
+
+let MAX_POINT_LIGHTS: u32 = 4u;
+
+struct PointLight {
+    // xyz is the world position and w the radius
+    position: vec4<f32>;
+    color: vec4<f32>;
+};
+
+// Same layout as the scene shader up to the display settings, the rest of
+// the scene uniform buffer isn't needed
+struct UniformBuffer {
+    projection_matrix: mat4x4<f32>;
+    view_matrix: mat4x4<f32>;
+    model_matrix: mat4x4<f32>;
+
+    // xyz is the linear fog color and w the fog density
+    fog: vec4<f32>;
+    // x is the light level, y is 1 if the sun casts shadows
+    light: vec4<f32>;
+
+    point_lights: array<PointLight, MAX_POINT_LIGHTS>;
+    // x is the number of used point lights
+    point_light_count: vec4<u32>;
+
+    light_space_matrix: mat4x4<f32>;
+    // xyz is the direction of the directional light and w the intensity
+    sun: vec4<f32>;
+
+    // x is the gamma and y the exposure
+    display: vec4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> uniform_buffer: UniformBuffer;
+
+struct ModelUniform {
+    model_matrix: mat4x4<f32>;
+    normal_matrix: mat3x3<f32>;
+    base_color: vec4<f32>;
+    // 1 if affected by the lighting and fog
+    lit: u32;
+    // Fragments with an alpha below this are discarded
+    alpha_cutoff: f32;
+};
+
+[[group(1), binding(0)]]
+var<uniform> model_uniform: ModelUniform;
+
+[[group(2), binding(0)]]
+var sprite_texture: texture_2d<f32>;
+[[group(2), binding(1)]]
+var sprite_sampler: sampler;
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] color: vec4<f32>;
+    [[location(2)]] normal: vec3<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] uv: vec2<f32>;
+    [[location(1)]] view_depth: f32;
+};
+
+[[stage(vertex)]]
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    let world_position = model_uniform.model_matrix * vec4<f32>(model.position, 1.0);
+    let view_position = uniform_buffer.view_matrix * world_position;
+
+    var out: VertexOutput;
+    out.clip_position = uniform_buffer.projection_matrix * view_position;
+    // The quad goes from -0.5 to 0.5 on x and 0 to 1 on y
+    out.uv = vec2<f32>(model.position.x + 0.5, 1.0 - model.position.y);
+    out.view_depth = view_position.z;
+    return out;
+}
+
+// Exposure and gamma, same as the scene shader so the sprites match the
+// rest of the scene
+fn adjust_display(color: vec3<f32>) -> vec3<f32> {
+    let exposed = color * uniform_buffer.display.y;
+    return pow(max(exposed, vec3<f32>(0.0)), vec3<f32>(2.2 / uniform_buffer.display.x));
+}
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let base_color = textureSample(sprite_texture, sprite_sampler, in.uv) * model_uniform.base_color;
+
+    // Alpha test instead of blending so the sprites don't need sorting
+    if (base_color.a < model_uniform.alpha_cutoff) {
+        discard;
+    }
+
+    if (model_uniform.lit == 0u) {
+        return vec4<f32>(adjust_display(base_color.rgb), 1.0);
+    }
+
+    let color = base_color.rgb * uniform_buffer.light.x;
+    let fog_amount = 1.0 - exp(-uniform_buffer.fog.w * max(in.view_depth, 0.0));
+    let fogged = mix(color, uniform_buffer.fog.xyz, fog_amount);
+    return vec4<f32>(adjust_display(fogged), 1.0);
+}